@@ -0,0 +1,160 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs::{create_reader, create_writer, log_file_path, sorted_log_generations, Command, KvStore, TrackingBufReader};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Commands written and read back by every codec
+const COMMANDS: usize = 100_000;
+
+/// Bytes of magic and format version every log starts with
+const HEADER_LEN: u64 = 8;
+
+/// Ways of laying commands out in a log
+///
+/// `Bincode` is the store's own format and goes through `KvStore::set` and `KvStore::open`, so
+/// its numbers also cover the index, the checksums and the record separators. The other two have
+/// no store path, so they are written and read back through the same buffered log files instead.
+#[derive(Clone, Copy)]
+enum Codec {
+    /// A `Command` per line, as serde_json writes it.
+    Json,
+    /// Framed bincode records, as `KvStore` writes them.
+    Bincode,
+    /// The key and value, each after its 4-byte big-endian length.
+    Raw,
+}
+
+const CODECS: [(&str, Codec); 3] = [("json", Codec::Json), ("bincode", Codec::Bincode), ("raw", Codec::Raw)];
+
+fn key(i: usize) -> String {
+    format!("key{:0>13}", i)
+}
+
+fn value(i: usize) -> String {
+    format!("{:v>100}", i)
+}
+
+/// Writes the workload into a fresh directory, flushing after every command like `KvStore::set` does
+fn encode(codec: Codec, dir: &Path) {
+    if let Codec::Bincode = codec {
+        let store = KvStore::open(dir).unwrap();
+        for i in 0..COMMANDS {
+            store.set(key(i), value(i)).unwrap();
+        }
+        store.close().unwrap();
+        return;
+    }
+    let mut writer = create_writer(&log_file_path(dir, 1)).unwrap();
+    for i in 0..COMMANDS {
+        match codec {
+            Codec::Json => {
+                let command = Command::Set { key: key(i), value: value(i), expires_at: None, seq: i as u64 + 1 };
+                serde_json::to_writer(&mut writer, &command).unwrap();
+                writer.write_all(b"\n").unwrap();
+            }
+            _ => {
+                for field in [key(i), value(i)] {
+                    writer.write_all(&(field.len() as u32).to_be_bytes()).unwrap();
+                    writer.write_all(field.as_bytes()).unwrap();
+                }
+            }
+        }
+        writer.flush().unwrap();
+    }
+}
+
+/// Reads the workload back into an index of each key's latest record, returning the keys found
+fn decode(codec: Codec, dir: &Path) -> usize {
+    if let Codec::Bincode = codec {
+        return KvStore::open(dir).unwrap().len();
+    }
+    let mut reader = create_reader(&log_file_path(dir, 1)).unwrap();
+    reader.seek(SeekFrom::Start(HEADER_LEN)).unwrap();
+    let mut index = BTreeMap::new();
+    match codec {
+        Codec::Json => {
+            let mut stream = serde_json::Deserializer::from_reader(&mut reader).into_iter::<Command>();
+            let mut start = HEADER_LEN;
+            while let Some(command) = stream.next() {
+                let end = HEADER_LEN + stream.byte_offset() as u64;
+                if let Command::Set { key, .. } = command.unwrap() {
+                    index.insert(key, (start, end - start));
+                }
+                start = end;
+            }
+        }
+        _ => {
+            let mut start = HEADER_LEN;
+            while let Some(key) = read_field(&mut reader) {
+                let value = read_field(&mut reader).unwrap();
+                let end = start + 8 + key.len() as u64 + value.len() as u64;
+                index.insert(String::from_utf8(key).unwrap(), (start, end - start));
+                start = end;
+            }
+        }
+    }
+    index.len()
+}
+
+/// Reads a length-prefixed field of a raw log, or `None` at the end of it
+fn read_field(reader: &mut TrackingBufReader<File>) -> Option<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len).ok()?;
+    let mut field = vec![0; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut field).unwrap();
+    Some(field)
+}
+
+fn disk_size(dir: &Path) -> u64 {
+    sorted_log_generations(dir)
+        .unwrap()
+        .into_iter()
+        .map(|gen| std::fs::metadata(log_file_path(dir, gen)).unwrap().len())
+        .sum()
+}
+
+/// Prints the size each codec's log ends up on disk, ahead of the timings
+fn sizes(_: &mut Criterion) {
+    for (name, codec) in CODECS {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        encode(codec, temp_dir.path());
+        let size = disk_size(temp_dir.path());
+        println!("{:<8} {:>10} bytes on disk, {:.1} per command", name, size, size as f64 / COMMANDS as f64);
+    }
+}
+
+fn encode_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec_encode");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(COMMANDS as u64));
+    for (name, codec) in CODECS {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_batched(
+                || TempDir::new().expect("unable to create temporary working directory"),
+                |temp_dir| encode(codec, temp_dir.path()),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn decode_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec_decode");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(COMMANDS as u64));
+    for (name, codec) in CODECS {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        encode(codec, temp_dir.path());
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| assert_eq!(decode(codec, temp_dir.path()), COMMANDS))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sizes, encode_throughput, decode_throughput);
+criterion_main!(benches);
@@ -0,0 +1,205 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs::{CompactionStrategy, KvStore};
+use std::time::Instant;
+use tempfile::TempDir;
+
+/// Key and value lengths in bytes that every benchmark runs over
+const SIZES: [(usize, usize); 3] = [(8, 16), (16, 256), (32, 4096)];
+
+/// Keys written into the store before the read benchmarks start
+const POPULATION: usize = 10_000;
+
+/// A fixed xorshift sequence, so every run reads the same keys in the same order
+struct Keys(u64);
+
+impl Keys {
+    fn next(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % bound as u64) as usize
+    }
+}
+
+fn key(i: usize, len: usize) -> String {
+    format!("{:0>width$}", i, width = len)
+}
+
+fn value(len: usize) -> String {
+    "v".repeat(len)
+}
+
+/// Opens a store in a fresh directory holding `POPULATION` keys
+fn populated(key_len: usize, value_len: usize) -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let entries = (0..POPULATION).map(|i| (key(i, key_len), value(value_len))).collect();
+    store.set_batch(entries).unwrap();
+    (temp_dir, store)
+}
+
+fn sequential_writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sequential_writes");
+    for (key_len, value_len) in SIZES {
+        group.throughput(Throughput::Elements(1000));
+        group.bench_function(BenchmarkId::from_parameter(format!("{}/{}", key_len, value_len)), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+                    let store = KvStore::open(temp_dir.path()).unwrap();
+                    (temp_dir, store)
+                },
+                |(_temp_dir, store)| {
+                    for i in 0..1000 {
+                        store.set(key(i, key_len), value(value_len)).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn random_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("random_reads");
+    for (key_len, value_len) in SIZES {
+        let (_temp_dir, store) = populated(key_len, value_len);
+        let mut keys = Keys(0x2545_f491_4f6c_dd1d);
+        group.bench_function(BenchmarkId::from_parameter(format!("{}/{}", key_len, value_len)), |b| {
+            b.iter(|| store.get(key(keys.next(POPULATION), key_len)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Runs of a hundred reads of keys written one after another, so each record follows the last in the log
+fn clustered_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("clustered_reads");
+    for (key_len, value_len) in SIZES {
+        let (_temp_dir, store) = populated(key_len, value_len);
+        let mut keys = Keys(0xbb67_ae85_84ca_a73b);
+        group.throughput(Throughput::Elements(100));
+        group.bench_function(BenchmarkId::from_parameter(format!("{}/{}", key_len, value_len)), |b| {
+            b.iter(|| {
+                let first = keys.next(POPULATION - 100);
+                for i in first..first + 100 {
+                    store.get(key(i, key_len)).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Nine reads of existing keys for every overwrite of one
+fn read_heavy_mixed(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_heavy_mixed");
+    for (key_len, value_len) in SIZES {
+        let (_temp_dir, store) = populated(key_len, value_len);
+        let mut keys = Keys(0x9e37_79b9_7f4a_7c15);
+        group.throughput(Throughput::Elements(10));
+        group.bench_function(BenchmarkId::from_parameter(format!("{}/{}", key_len, value_len)), |b| {
+            b.iter(|| {
+                for _ in 0..9 {
+                    store.get(key(keys.next(POPULATION), key_len)).unwrap();
+                }
+                store.set(key(keys.next(POPULATION), key_len), value(value_len)).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Drops every log of the store from the OS page cache, as if memory pressure had evicted it
+#[cfg(target_os = "linux")]
+fn evict_logs(dir: &std::path::Path) {
+    use std::os::unix::io::AsRawFd;
+    for gen in kvs::sorted_log_generations(dir).unwrap() {
+        let log = std::fs::File::open(kvs::log_file_path(dir, gen)).unwrap();
+        // Only clean pages can be dropped
+        log.sync_all().unwrap();
+        unsafe { libc::posix_fadvise(log.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    }
+}
+
+/// A hundred reads of random keys once the logs have left the page cache, with and without a warmup first
+#[cfg(target_os = "linux")]
+fn cold_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cold_reads");
+    let (key_len, value_len) = SIZES[2];
+    let (temp_dir, store) = populated(key_len, value_len);
+    let mut keys = Keys(0x6a09_e667_f3bc_c908);
+    group.throughput(Throughput::Elements(100));
+    for warmup in [false, true] {
+        let name = if warmup { "warmed" } else { "cold" };
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_batched(
+                || {
+                    evict_logs(temp_dir.path());
+                    if warmup {
+                        store.warmup().unwrap();
+                    }
+                },
+                |()| {
+                    for _ in 0..100 {
+                        store.get(key(keys.next(POPULATION), key_len)).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cold_reads(_: &mut Criterion) {}
+
+/// The longest a single overwrite of a large store stalls behind compaction, under each strategy
+///
+/// Every iteration times two thousand random overwrites of 4 KiB values and counts only the
+/// slowest, which under `Full` is the one that rewrites the whole store.
+fn compaction_pause(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compaction_pause");
+    group.sample_size(10);
+    let (key_len, value_len) = SIZES[2];
+    for (name, strategy) in [("full", CompactionStrategy::Full), ("size_tiered", CompactionStrategy::SizeTiered)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder()
+            .compaction_strategy(strategy)
+            .max_log_size(1024 * 1024)
+            .open(temp_dir.path())
+            .unwrap();
+        store.set_batch((0..POPULATION).map(|i| (key(i, key_len), value(value_len))).collect()).unwrap();
+        let mut keys = Keys(0x3c6e_f372_fe94_f82b);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| {
+                        (0..2000)
+                            .map(|_| {
+                                let start = Instant::now();
+                                store.set(key(keys.next(POPULATION), key_len), value(value_len)).unwrap();
+                                start.elapsed()
+                            })
+                            .max()
+                            .unwrap()
+                    })
+                    .sum()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    sequential_writes,
+    random_reads,
+    clustered_reads,
+    read_heavy_mixed,
+    cold_reads,
+    compaction_pause
+);
+criterion_main!(benches);
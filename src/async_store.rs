@@ -0,0 +1,75 @@
+use std::io;
+use std::panic;
+use std::sync::Mutex;
+use tokio::task;
+use crate::{KvStore, Result};
+
+/// An async handle onto a `KvStore` for use inside a tokio runtime.
+///
+/// Every call runs the blocking store operation on tokio's blocking thread
+/// pool, so log reads, writes and compactions never stall the async workers.
+/// Clones share the same store, and a panic inside the store is resumed in
+/// the awaiting task. The handle keeps its store behind a mutex only so that
+/// it can be shared between tasks; each call works on its own clone.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{AsyncKvStore, KvStore, Result};
+/// # async fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = AsyncKvStore::new(KvStore::open(temp_dir.path())?);
+/// store.set("key".to_owned(), "value".to_owned()).await?;
+/// assert_eq!(store.get("key".to_owned()).await?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncKvStore {
+    store: Mutex<KvStore>,
+}
+
+impl Clone for AsyncKvStore {
+    fn clone(&self) -> Self {
+        AsyncKvStore::new(self.store())
+    }
+}
+
+impl AsyncKvStore {
+    pub fn new(store: KvStore) -> AsyncKvStore {
+        AsyncKvStore { store: Mutex::new(store) }
+    }
+
+    /// Returns a blocking handle onto the same store
+    pub fn store(&self) -> KvStore {
+        self.store.lock().unwrap().clone()
+    }
+
+    /// Sets the value of a key, replacing any previous value
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |store| store.set(key, value)).await
+    }
+
+    /// Gets the value of a key, or `None` if it does not exist
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |store| store.get(key)).await
+    }
+
+    /// Removes a key, returning `KvsError::KeyNotFound` if it does not exist
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.run(move |store| store.remove(key)).await
+    }
+
+    async fn run<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(KvStore) -> Result<T> + Send + 'static,
+    {
+        let store = self.store();
+        match task::spawn_blocking(move || op(store)).await {
+            Ok(result) => result,
+            Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+            // Only happens when the runtime shuts down before the task starts
+            Err(err) => Err(io::Error::new(io::ErrorKind::Interrupted, err).into()),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+extern crate exitcode;
+
+use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use clap::{Args, Parser, Subcommand};
+use kvs::{KvsClient, KvsError, Result};
+
+fn main() -> Result<()> {
+    let args: KvClientArgs = KvClientArgs::parse();
+    let client = KvsClient::new(args.addr);
+    run(&client, args.operation)
+}
+
+/// Sends a single CLI operation to the server and exits
+fn run(client: &KvsClient, operation: Operation) -> Result<()> {
+    match operation {
+        Operation::Get(cmd) => {
+            if let Some(value) = client.get(cmd.key)? {
+                println!("{}", value);
+            } else {
+                println!("Key not found");
+            }
+            std::process::exit(exitcode::OK);
+        }
+        Operation::Set(cmd) => {
+            client.set(cmd.key, cmd.value)?;
+            std::process::exit(exitcode::OK);
+        }
+        Operation::Remove(cmd) => match client.remove(cmd.key) {
+            Ok(()) => std::process::exit(exitcode::OK),
+            Err(KvsError::KeyNotFound { .. }) => {
+                println!("Key not found");
+                std::process::exit(exitcode::CONFIG);
+            }
+            Err(err) => Err(err),
+        },
+    }
+}
+
+/// Talks to a kvs-server over the network
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct KvClientArgs {
+    /// Operation to perform on KV
+    #[clap(subcommand)]
+    pub operation: Operation,
+
+    /// Address of the server
+    #[clap(long, global = true, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Operation {
+    /// Get a value by key
+    Get(GetCliCommand),
+
+    /// Set a value by key
+    Set(SetCliCommand),
+
+    /// Remove a value by key
+    #[clap(name = "rm")]
+    Remove(RemoveCliCommand),
+}
+
+#[derive(Args, Debug, Deserialize, Serialize)]
+pub struct GetCliCommand {
+    /// Name of key to get value for
+    key: String,
+}
+
+#[derive(Args, Debug, Deserialize, Serialize)]
+pub struct SetCliCommand {
+    /// Name of key to get value for
+    key: String,
+    /// Value to set for key
+    value: String,
+}
+
+#[derive(Args, Debug, Deserialize, Serialize)]
+pub struct RemoveCliCommand {
+    /// Name of key to remove value for
+    key: String,
+}
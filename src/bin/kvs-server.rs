@@ -0,0 +1,100 @@
+use std::env::current_dir;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::{Parser, ValueEnum};
+use std::thread;
+use kvs::{AnyEngine, EngineKind, KvStore, KvsServer, RequestLog, Result, SharedQueueThreadPool};
+
+fn main() {
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+    let args: ServerArgs = ServerArgs::parse();
+    if let Err(err) = run(args) {
+        eprintln!("{}", err);
+        std::process::exit(exitcode::SOFTWARE);
+    }
+}
+
+fn run(args: ServerArgs) -> Result<()> {
+    let mut builder = KvStore::builder();
+    if let Some(engine) = args.engine {
+        builder = builder.engine(engine.into());
+    }
+    let engine = builder.open_engine(current_dir()?)?;
+    eprintln!("kvs-server {} using the {} engine on {}", env!("CARGO_PKG_VERSION"), engine.kind(), args.addr);
+
+    let request_log = args.request_log.map(RequestLog::open).transpose()?;
+    let drain_timeout = Duration::from_secs(args.drain_timeout);
+    let threads = thread::available_parallelism().map_or(4, |threads| threads.get() as u32);
+    let pool = SharedQueueThreadPool::with_queue_len(threads, args.queue_len.unwrap_or(threads as usize))?;
+    serve(engine, pool, args.addr, request_log, drain_timeout)
+}
+
+fn serve(
+    engine: AnyEngine,
+    pool: SharedQueueThreadPool,
+    addr: SocketAddr,
+    request_log: Option<RequestLog>,
+    drain_timeout: Duration,
+) -> Result<()> {
+    let server = KvsServer::new(engine, pool).drain_timeout(drain_timeout);
+
+    // The first SIGINT or SIGTERM drains the server, a second one exits straight away
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || {
+        if shutdown.is_requested() {
+            std::process::exit(exitcode::SOFTWARE);
+        }
+        eprintln!("kvs-server shutting down");
+        shutdown.shutdown();
+    })
+    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+
+    match request_log {
+        Some(request_log) => server.request_log(request_log).run(addr),
+        None => server.run(addr),
+    }
+}
+
+/// Serves a key-value store over the network
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct ServerArgs {
+    /// Address to listen on
+    #[clap(long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+
+    /// Storage engine, defaults to the one the directory was created with or kvs
+    #[clap(long, value_enum)]
+    engine: Option<Engine>,
+
+    /// File to append every received request to, for replaying with `kvs replay`
+    #[clap(long)]
+    request_log: Option<PathBuf>,
+
+    /// Seconds to let requests in flight finish after SIGINT or SIGTERM before exiting anyway
+    #[clap(long, default_value_t = 30)]
+    drain_timeout: u64,
+
+    /// Connections that may wait for a free thread before more are turned away as busy,
+    /// defaults to the number of threads
+    #[clap(long)]
+    queue_len: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled,
+}
+
+impl From<Engine> for EngineKind {
+    fn from(engine: Engine) -> EngineKind {
+        match engine {
+            Engine::Kvs => EngineKind::Kvs,
+            Engine::Sled => EngineKind::Sled,
+        }
+    }
+}
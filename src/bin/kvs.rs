@@ -1,48 +1,301 @@
 extern crate exitcode;
 
 use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
-use clap::{Args, Parser, Subcommand};
-use kvs::{KvStore, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use kvs::{KvStore, KvsEngine, KvsError, Result};
 use env::current_dir;
 
-fn main() -> Result<()> {
+/// A key given to `rm` or `exists` is not in the store
+const KEY_NOT_FOUND: i32 = 1;
+
+const EXIT_STATUS: &str = "Exit status:
+  0   success, including `get` of a missing key
+  1   the key given to `rm` or `exists` was not found
+  2   the command line could not be parsed
+  74  a log could not be read or written, or is corrupt
+  70  any other error";
+
+fn main() {
     let args: KvArgs = KvArgs::parse();
-    let mut store = KvStore::open(current_dir()?)?;
+    let code = cli(args).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        exit_code(&err)
+    });
+    std::process::exit(code);
+}
+
+/// Chooses the exit status for an error, following the scheme in `--help`
+///
+/// Usage errors never get here, clap exits with 2 for them.
+fn exit_code(err: &KvsError) -> i32 {
+    match err {
+        KvsError::KeyNotFound { .. } => KEY_NOT_FOUND,
+        KvsError::Io(_)
+        | KvsError::CreateDir { .. }
+        | KvsError::Corrupt { .. }
+        | KvsError::CorruptRecord { .. }
+        | KvsError::UnsupportedFormat { .. }
+        | KvsError::UnrecognizedLog { .. }
+        | KvsError::Bincode(_)
+        | KvsError::Utf8(_)
+        | KvsError::DecryptionFailed => exitcode::IOERR,
+        _ => exitcode::SOFTWARE,
+    }
+}
 
+/// Runs the requested operation, returning the exit status for it
+fn cli(args: KvArgs) -> Result<i32> {
+    let path = match args.path {
+        Some(path) => path,
+        None => current_dir()?,
+    };
+    if let Operation::Repair = args.operation {
+        // Repair works on logs that no longer open, so it never opens the store
+        let report = KvStore::repair(path)?;
+        match args.format {
+            Format::Plain => report.generations.iter().for_each(|gen| {
+                println!(
+                    "generation {}: kept {}, dropped {}, trimmed {} bytes",
+                    gen.gen, gen.kept, gen.dropped, gen.trimmed_bytes
+                )
+            }),
+            Format::Json => {
+                let generations: Vec<_> = report
+                    .generations
+                    .iter()
+                    .map(|gen| RepairOutput {
+                        gen: gen.gen,
+                        kept: gen.kept,
+                        dropped: gen.dropped,
+                        trimmed_bytes: gen.trimmed_bytes,
+                    })
+                    .collect();
+                print_json(&generations)?;
+            }
+        }
+        return Ok(exitcode::OK);
+    }
+    if let Operation::Validate = args.operation {
+        // Opening would tidy the directory up, so it is checked without opening
+        let report = KvStore::validate(path)?;
+        match args.format {
+            Format::Plain => {
+                println!("generations: {}", report.generations);
+                println!("records: {}", report.records);
+                for corruption in &report.corruptions {
+                    println!(
+                        "corrupt: generation {} offset {} ({} bytes)",
+                        corruption.gen, corruption.offset, corruption.length
+                    );
+                }
+                for warning in &report.warnings {
+                    println!("warning: {}", warning);
+                }
+            }
+            Format::Json => print_json(&ValidateOutput {
+                generations: report.generations,
+                records: report.records,
+                corruptions: report
+                    .corruptions
+                    .iter()
+                    .map(|corruption| CorruptionOutput {
+                        gen: corruption.gen,
+                        offset: corruption.offset,
+                        length: corruption.length,
+                    })
+                    .collect(),
+                warnings: report.warnings.clone(),
+            })?,
+        }
+        return Ok(if report.is_valid() { exitcode::OK } else { exitcode::IOERR });
+    }
+    let mut store = KvStore::open(path)?;
     match args.operation {
+        Operation::Stats => {
+            let stats = store.stats()?;
+            match args.format {
+                Format::Plain => {
+                    println!("live_keys: {}", stats.live_keys);
+                    println!("disk_bytes: {}", stats.disk_bytes);
+                    println!("dead_bytes: {}", stats.dead_bytes);
+                    println!("fragmentation: {:.3}", stats.fragmentation);
+                }
+                Format::Json => print_json(&StatsOutput {
+                    live_keys: stats.live_keys,
+                    disk_bytes: stats.disk_bytes,
+                    dead_bytes: stats.dead_bytes,
+                    fragmentation: stats.fragmentation,
+                })?,
+            }
+            Ok(exitcode::OK)
+        }
+        Operation::Compact => {
+            let before = store.stats()?.disk_bytes;
+            store.compact()?;
+            let after = store.stats()?.disk_bytes;
+            let reclaimed = before.saturating_sub(after);
+            match args.format {
+                Format::Plain => println!("reclaimed {} bytes ({} -> {})", reclaimed, before, after),
+                Format::Json => print_json(&CompactOutput { before, after, reclaimed })?,
+            }
+            Ok(exitcode::OK)
+        }
+        Operation::Backup(cmd) => {
+            store.backup(cmd.dest)?;
+            Ok(exitcode::OK)
+        }
+        Operation::Dump => {
+            store.export(BufWriter::new(io::stdout().lock()))?;
+            Ok(exitcode::OK)
+        }
+        Operation::Load => {
+            store.import(io::stdin().lock())?;
+            Ok(exitcode::OK)
+        }
+        operation => run(&mut store, operation, args.format),
+    }
+}
+
+/// Applies a single CLI operation to the given engine, returning the exit status for it
+fn run<E: KvsEngine>(store: &mut E, operation: Operation, format: Format) -> Result<i32> {
+    match operation {
         Operation::Get(cmd) => {
-            if let Some(value) = store.get(cmd.key)? {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
+            let value = store.get(cmd.key.clone())?;
+            match (format, value) {
+                (Format::Plain, Some(value)) => println!("{}", value),
+                (Format::Plain, None) => println!("Key not found"),
+                (Format::Json, value) => print_json(&Entry { key: cmd.key, value })?,
             }
-            std::process::exit(exitcode::OK);
+            Ok(exitcode::OK)
         }
         Operation::Set(cmd) => {
             store.set(cmd.key, cmd.value)?;
-            std::process::exit(exitcode::OK);
+            if let Format::Json = format {
+                print_json(&SetOutput { set: true })?;
+            }
+            Ok(exitcode::OK)
         }
         Operation::Remove(cmd) => {
-            // println!("<<< Removing >>>");
-            if let Ok(_) = store.remove(cmd.key) {
-                std::process::exit(exitcode::OK);
+            let removed = match store.remove(cmd.key) {
+                Ok(()) => true,
+                Err(KvsError::KeyNotFound { .. }) => false,
+                Err(err) => return Err(err),
+            };
+            match format {
+                Format::Plain if !removed => println!("Key not found"),
+                Format::Plain => {}
+                Format::Json => print_json(&RemoveOutput { removed })?,
             }
-            println!("Key not found");
-            std::process::exit(exitcode::CONFIG);
+            Ok(if removed { exitcode::OK } else { KEY_NOT_FOUND })
+        }
+        Operation::Exists(cmd) => {
+            let exists = store.contains_key(&cmd.key)?;
+            if let Format::Json = format {
+                print_json(&ExistsOutput { key: cmd.key, exists })?;
+            }
+            Ok(if exists { exitcode::OK } else { KEY_NOT_FOUND })
+        }
+        Operation::Keys => {
+            let keys = store.keys()?;
+            match format {
+                Format::Plain => keys.iter().for_each(|key| println!("{}", key)),
+                Format::Json => print_json(&keys)?,
+            }
+            Ok(exitcode::OK)
+        }
+        Operation::Len => {
+            let len = store.len()?;
+            match format {
+                Format::Plain => println!("{}", len),
+                Format::Json => print_json(&LenOutput { len })?,
+            }
+            Ok(exitcode::OK)
+        }
+        Operation::Scan(cmd) => {
+            let entries = store.scan_prefix(&cmd.prefix)?;
+            match format {
+                Format::Plain => entries.iter().for_each(|(key, value)| println!("{}={}", key, value)),
+                Format::Json => {
+                    let entries: Vec<_> = entries
+                        .into_iter()
+                        .map(|(key, value)| Entry { key, value: Some(value) })
+                        .collect();
+                    print_json(&entries)?;
+                }
+            }
+            Ok(exitcode::OK)
+        }
+        Operation::Replay(cmd) => {
+            let report = kvs::replay(store, File::open(cmd.request_log)?)?;
+            match format {
+                Format::Plain => {
+                    for failure in &report.failures {
+                        println!("request {} ({:?}): {}", failure.index, failure.logged.request, failure.error);
+                    }
+                    println!("replayed {} requests, {} failed", report.replayed, report.failures.len());
+                }
+                Format::Json => print_json(&ReplayOutput {
+                    replayed: report.replayed,
+                    failures: report
+                        .failures
+                        .into_iter()
+                        .map(|failure| ReplayFailureOutput {
+                            index: failure.index,
+                            at: failure.logged.at,
+                            error: failure.error,
+                        })
+                        .collect(),
+                })?,
+            }
+            Ok(exitcode::OK)
+        }
+        Operation::Compact
+        | Operation::Backup(_)
+        | Operation::Dump
+        | Operation::Load
+        | Operation::Stats
+        | Operation::Repair
+        | Operation::Validate => {
+            unreachable!("handled against the concrete store")
         }
     }
 }
 
+/// Prints a value as a single line of JSON
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
+
 /// Reads and Analyses Files
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, after_help = EXIT_STATUS)]
 struct KvArgs {
+    /// Directory holding the store, defaults to the current directory
+    #[clap(long, global = true, env = "KVS_PATH")]
+    pub path: Option<PathBuf>,
+
+    /// How to print results
+    #[clap(long, global = true, value_enum, default_value_t = Format::Plain)]
+    pub format: Format,
+
     /// Operation to perform on KV
     #[clap(subcommand)]
     pub operation: Operation,
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Format {
+    /// Bare values, one per line
+    Plain,
+    /// One JSON document per command
+    Json,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Operation {
     /// Get a value by key
@@ -54,6 +307,42 @@ pub enum Operation {
     /// Remove a value by key
     #[clap(name = "rm")]
     Remove(RemoveCliCommand),
+
+    /// Exit with status 0 if a key exists and 1 otherwise
+    Exists(ExistsCliCommand),
+
+    /// List every key, one per line
+    Keys,
+
+    /// Print the number of keys
+    Len,
+
+    /// Print every key starting with a prefix as key=value
+    Scan(ScanCliCommand),
+
+    /// Rewrite the logs without stale records and print the bytes reclaimed
+    Compact,
+
+    /// Copy the logs into another directory while the store stays writable
+    Backup(BackupCliCommand),
+
+    /// Write every key/value pair to stdout as JSON lines
+    Dump,
+
+    /// Set every key/value pair read from stdin as JSON lines
+    Load,
+
+    /// Print the live key count and how much of the logs is dead
+    Stats,
+
+    /// Trim corrupt records off the logs and print what each generation kept, without opening the store
+    Repair,
+
+    /// Check every log without changing anything, exiting with 74 if any record is corrupt
+    Validate,
+
+    /// Apply every request from a `kvs-server --request-log` file and print those that failed
+    Replay(ReplayCliCommand),
 }
 
 #[derive(Args, Debug, Deserialize, Serialize)]
@@ -75,3 +364,106 @@ pub struct RemoveCliCommand {
     /// Name of key to remove value for
     key: String,
 }
+
+#[derive(Args, Debug, Deserialize, Serialize)]
+pub struct ExistsCliCommand {
+    /// Name of key to check for
+    key: String,
+}
+
+#[derive(Args, Debug, Deserialize, Serialize)]
+pub struct ScanCliCommand {
+    /// Prefix of the keys to print, empty for every key
+    #[clap(default_value = "")]
+    prefix: String,
+}
+
+#[derive(Args, Debug, Deserialize, Serialize)]
+pub struct BackupCliCommand {
+    /// Directory to copy the store into, which must not already hold any logs
+    dest: PathBuf,
+}
+
+#[derive(Args, Debug, Deserialize, Serialize)]
+pub struct ReplayCliCommand {
+    /// Request log written by the server
+    request_log: PathBuf,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    key: String,
+    value: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SetOutput {
+    set: bool,
+}
+
+#[derive(Serialize)]
+struct RemoveOutput {
+    removed: bool,
+}
+
+#[derive(Serialize)]
+struct ExistsOutput {
+    key: String,
+    exists: bool,
+}
+
+#[derive(Serialize)]
+struct LenOutput {
+    len: usize,
+}
+
+#[derive(Serialize)]
+struct StatsOutput {
+    live_keys: usize,
+    disk_bytes: u64,
+    dead_bytes: u64,
+    fragmentation: f64,
+}
+
+#[derive(Serialize)]
+struct CompactOutput {
+    before: u64,
+    after: u64,
+    reclaimed: u64,
+}
+
+#[derive(Serialize)]
+struct RepairOutput {
+    gen: u64,
+    kept: u64,
+    dropped: u64,
+    trimmed_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct ValidateOutput {
+    generations: usize,
+    records: u64,
+    corruptions: Vec<CorruptionOutput>,
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CorruptionOutput {
+    gen: u64,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Serialize)]
+struct ReplayOutput {
+    replayed: usize,
+    failures: Vec<ReplayFailureOutput>,
+}
+
+#[derive(Serialize)]
+struct ReplayFailureOutput {
+    index: usize,
+    at: u64,
+    error: String,
+}
@@ -0,0 +1,413 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use crate::{
+    AnyEngine, CompactionStrategy, EngineKind, KeyHasher, KvStore, RepairReport, Result, SledKvsEngine, SyncMode,
+    ValidateReport, ValueHooks,
+};
+
+/// Configures how a `KvStore` is opened.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let mut store = KvStore::builder()
+///     .value_hooks(
+///         |value| value.iter().rev().cloned().collect(),
+///         |stored| Ok(stored.iter().rev().cloned().collect()),
+///     )
+///     .open(temp_dir.path())?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The path can be passed to `open`, as above, or set first with `path` so that `open`
+/// takes nothing:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, SyncMode};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::builder().path(temp_dir.path()).sync_mode(SyncMode::EveryWrite).open()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct KvStoreBuilder<P = NoPath> {
+    pub(crate) value_hooks: Option<ValueHooks>,
+    #[cfg(feature = "encryption")]
+    encryption_key: Option<[u8; 32]>,
+    #[cfg(feature = "encryption")]
+    record_encryption_key: Option<[u8; 32]>,
+    pub(crate) record_seal: Option<ValueHooks>,
+    pub(crate) dedup_min_size: Option<usize>,
+    pub(crate) compress_min_size: Option<usize>,
+    pub(crate) max_log_size: Option<u64>,
+    pub(crate) compaction_threshold: Option<u64>,
+    pub(crate) separator: Option<Vec<u8>>,
+    pub(crate) sync_mode: Option<SyncMode>,
+    pub(crate) read_only: bool,
+    pub(crate) mmap_reads: bool,
+    pub(crate) buffer_capacity: Option<usize>,
+    pub(crate) max_key_size: Option<usize>,
+    pub(crate) max_value_size: Option<u64>,
+    pub(crate) sweep_interval: Option<Duration>,
+    pub(crate) checkpoint_interval: Option<Duration>,
+    pub(crate) key_hasher: Option<KeyHasher>,
+    pub(crate) keep_versions: Option<usize>,
+    pub(crate) compaction_strategy: Option<CompactionStrategy>,
+    engine: Option<EngineKind>,
+    path: P,
+}
+
+/// The path of a `KvStoreBuilder` that has not been given one, so `open` takes it instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoPath;
+
+impl KvStoreBuilder {
+    /// Creates a builder with the default settings
+    pub fn new() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+
+    /// Opens the store at the given path with these settings
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_inner(path.into(), self.resolve_hooks(), None)
+    }
+
+    /// Opens the engine chosen with `engine` at the given path
+    ///
+    /// The engine is recorded in the directory, and opening it again with a different one
+    /// fails with `KvsError::WrongEngine`. Without `engine`, a directory is opened with the
+    /// engine it was created by, and a new one as a `KvStore`. A `KvStore` is opened with these
+    /// settings. Sled has settings of its own, so it ignores these.
+    pub fn open_engine(self, path: impl Into<PathBuf>) -> Result<AnyEngine> {
+        let path = path.into();
+        let kind = EngineKind::choose(&path, self.engine)?;
+        let read_only = self.read_only;
+        let engine = match kind {
+            EngineKind::Kvs => AnyEngine::Kvs(self.open(&path)?),
+            EngineKind::Sled => AnyEngine::Sled(SledKvsEngine::open(&path)?),
+        };
+        if !read_only {
+            kind.record(&path)?;
+        }
+        Ok(engine)
+    }
+
+    /// Repairs the store in the given directory, see `KvStore::repair`
+    ///
+    /// Only the record separator and record encryption key matter here.
+    pub fn repair(self, path: impl Into<PathBuf>) -> Result<RepairReport> {
+        KvStore::repair_inner(path.into(), self.resolve_hooks())
+    }
+
+    /// Validates the store in the given directory, see `KvStore::validate`
+    ///
+    /// Only the record separator and record encryption key matter here.
+    pub fn validate(self, path: impl Into<PathBuf>) -> Result<ValidateReport> {
+        KvStore::validate_inner(path.into(), self.resolve_hooks())
+    }
+
+    /// Layers value encryption beneath any configured value hooks and sets up record encryption
+    #[cfg(feature = "encryption")]
+    fn resolve_hooks(mut self) -> KvStoreBuilder {
+        if let Some(key) = self.encryption_key.take() {
+            self.value_hooks = Some(crate::encryption::encrypted_hooks(key, self.value_hooks.take()));
+        }
+        if let Some(key) = self.record_encryption_key.take() {
+            self.record_seal = Some(crate::encryption::encrypted_hooks(key, None));
+        }
+        self
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn resolve_hooks(self) -> KvStoreBuilder {
+        self
+    }
+}
+
+impl KvStoreBuilder<PathBuf> {
+    /// Opens the store at the path given to `path` with these settings
+    pub fn open(self) -> Result<KvStore> {
+        let (builder, path) = self.take_path();
+        builder.open(path)
+    }
+
+    /// Opens the engine chosen with `engine` at the path given to `path`, see `KvStoreBuilder::open_engine`
+    pub fn open_engine(self) -> Result<AnyEngine> {
+        let (builder, path) = self.take_path();
+        builder.open_engine(path)
+    }
+
+    /// Repairs the store at the path given to `path`, see `KvStore::repair`
+    pub fn repair(self) -> Result<RepairReport> {
+        let (builder, path) = self.take_path();
+        builder.repair(path)
+    }
+
+    /// Validates the store at the path given to `path`, see `KvStore::validate`
+    pub fn validate(self) -> Result<ValidateReport> {
+        let (builder, path) = self.take_path();
+        builder.validate(path)
+    }
+
+    fn take_path(mut self) -> (KvStoreBuilder, PathBuf) {
+        let path = std::mem::take(&mut self.path);
+        (self.with_path(NoPath), path)
+    }
+}
+
+impl<P> KvStoreBuilder<P> {
+    /// Sets the store directory, so `open` no longer takes it
+    pub fn path(self, path: impl Into<PathBuf>) -> KvStoreBuilder<PathBuf> {
+        self.with_path(path.into())
+    }
+
+    /// Chooses the engine `open_engine` opens, by default the one the directory was created by
+    ///
+    /// `open` always opens a `KvStore`.
+    pub fn engine(mut self, engine: EngineKind) -> KvStoreBuilder<P> {
+        self.engine = Some(engine);
+        self
+    }
+
+    /// Transforms value bytes with `on_write` before they are logged and with `on_read` after they are read back
+    ///
+    /// The same hooks must be supplied every time the store is opened.
+    pub fn value_hooks<W, R>(mut self, on_write: W, on_read: R) -> KvStoreBuilder<P>
+    where
+        W: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+        R: Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.value_hooks = Some(ValueHooks {
+            on_write: Box::new(on_write),
+            on_read: Box::new(on_read),
+        });
+        self
+    }
+
+    /// Encrypts every value at rest with AES-256-GCM using the given key
+    ///
+    /// The key is never written to disk, so the same key must be supplied whenever the store is opened.
+    /// Reading a value with the wrong key fails with `KvsError::DecryptionFailed`.
+    /// Any value hooks are applied to the plaintext.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> KvStoreBuilder<P> {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Encrypts every whole record at rest with AES-256-GCM, so keys are hidden as well as values
+    ///
+    /// Each record body is stored as a fresh nonce, the ciphertext and its tag, and record lengths
+    /// cover all three. Deduplicated values are encrypted the same way, though their file names
+    /// still reveal which keys share a value. Opening the store with the wrong key fails with
+    /// `KvsError::DecryptionFailed`. The same key must be supplied whenever the store is opened.
+    #[cfg(feature = "encryption")]
+    pub fn record_encryption_key(mut self, key: [u8; 32]) -> KvStoreBuilder<P> {
+        self.record_encryption_key = Some(key);
+        self
+    }
+
+    /// Stores values of at least `min_size` bytes once per distinct content, shared by every key that holds them
+    ///
+    /// Unreferenced values are removed when the store is compacted.
+    pub fn dedup_values(mut self, min_size: usize) -> KvStoreBuilder<P> {
+        self.dedup_min_size = Some(min_size);
+        self
+    }
+
+    /// Deflate compresses values of at least `min_size` bytes before they are logged
+    ///
+    /// A value is only stored compressed when that makes it smaller, and values stored
+    /// once through `dedup_values` are never compressed. Compressed records can be read
+    /// whether or not compression is enabled when the store is next opened.
+    pub fn compress_values(mut self, min_size: usize) -> KvStoreBuilder<P> {
+        self.compress_min_size = Some(min_size);
+        self
+    }
+
+    /// Starts a new generation once a write takes the current one past `max_log_size` bytes
+    ///
+    /// A single record larger than the limit still goes into one log, and the log written
+    /// by compaction holds every live record whatever its size.
+    pub fn max_log_size(mut self, max_log_size: u64) -> KvStoreBuilder<P> {
+        self.max_log_size = Some(max_log_size);
+        self
+    }
+
+    /// Compacts once a write leaves more than `threshold` bytes of replaced and removed records, 1 MiB by default
+    ///
+    /// On open, the newest log is only reused while it is smaller than this, and logs that
+    /// together are smaller are merged. A low threshold keeps the logs small at the cost of
+    /// compacting often.
+    pub fn compaction_threshold(mut self, threshold: u64) -> KvStoreBuilder<P> {
+        self.compaction_threshold = Some(threshold);
+        self
+    }
+
+    /// Chooses how automatic compaction picks the logs to rewrite, `CompactionStrategy::Full` by default
+    ///
+    /// See `CompactionStrategy` for what each strategy trades off. `KvStore::compact` and
+    /// `KvStore::defragment` always rewrite every log.
+    pub fn compaction_strategy(mut self, strategy: CompactionStrategy) -> KvStoreBuilder<P> {
+        self.compaction_strategy = Some(strategy);
+        self
+    }
+
+    /// Ends log records with the given bytes instead of a newline
+    ///
+    /// Records are length-prefixed, so the separator is only checked to catch misframed records.
+    /// Any bytes will do, and an empty separator leaves records with nothing after them.
+    /// The same separator must be supplied every time the store is opened.
+    pub fn record_separator(mut self, separator: impl Into<Vec<u8>>) -> KvStoreBuilder<P> {
+        self.separator = Some(separator.into());
+        self
+    }
+
+    /// Buffers up to `capacity` bytes in each log writer and in each sequential scan of a log, 8 KiB by default
+    ///
+    /// Larger buffers help bulk loads through `set_batch`, opening stores with big logs and
+    /// compaction. Single-key reads are unaffected, they would only read further ahead than they need.
+    pub fn buffer_capacity(mut self, capacity: usize) -> KvStoreBuilder<P> {
+        self.buffer_capacity = Some(capacity);
+        self
+    }
+
+    /// Refuses to set keys longer than `max` bytes with `KvsError::KeyTooLong`, 4 KiB by default
+    pub fn max_key_size(mut self, max: usize) -> KvStoreBuilder<P> {
+        self.max_key_size = Some(max);
+        self
+    }
+
+    /// Refuses to set values longer than `max` bytes with `KvsError::ValueTooLong`, 64 MiB by default
+    ///
+    /// Values streamed in through `set_reader` are held to the same limit.
+    pub fn max_value_size(mut self, max: u64) -> KvStoreBuilder<P> {
+        self.max_value_size = Some(max);
+        self
+    }
+
+    /// Drops expired keys from the index every `interval` on a background thread
+    ///
+    /// Without it, a key set with a TTL stays indexed until it is read or a compaction runs.
+    /// Swept records count as dead bytes, so a later write triggers compaction once enough
+    /// have built up. The thread is stopped when the last clone of the store is dropped.
+    pub fn sweep_expired(mut self, interval: Duration) -> KvStoreBuilder<P> {
+        self.sweep_interval = Some(interval);
+        self
+    }
+
+    /// Writes a checkpoint of the index every `interval` on a background thread, see `KvStore::checkpoint`
+    ///
+    /// Opening then only replays the records written since the last checkpoint. Writes wait
+    /// while each one is taken. Read-only stores never write one, though they open from one.
+    /// The thread is stopped when the last clone of the store is dropped.
+    pub fn checkpoint_every(mut self, interval: Duration) -> KvStoreBuilder<P> {
+        self.checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// Chooses when writes are fsynced, `SyncMode::Never` by default
+    ///
+    /// See `SyncMode` for what each mode trades off.
+    pub fn sync_mode(mut self, sync_mode: SyncMode) -> KvStoreBuilder<P> {
+        self.sync_mode = Some(sync_mode);
+        self
+    }
+
+    /// Keeps only a 64-bit hash of each key in memory instead of the key itself
+    ///
+    /// This saves memory on large key sets at the cost of a disk read to confirm every lookup
+    /// and every overwrite. A key whose hash is already taken by another key is held in full.
+    /// Sealed generations keep a bloom filter of their keys, so a hash match against another
+    /// key's record in one of them is usually ruled out without the read. Each filter is saved
+    /// next to its log as `<generation>.filter` and loaded on the next open.
+    /// Listing keys, range and prefix scans have to read every key back from the logs.
+    pub fn hashed_index(self) -> KvStoreBuilder<P> {
+        self.hashed_index_with(|key| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        })
+    }
+
+    /// Like `hashed_index`, but hashes keys with the given function
+    ///
+    /// The index only lives in memory, so the function may change between opens.
+    pub fn hashed_index_with<F>(mut self, hasher: F) -> KvStoreBuilder<P>
+    where
+        F: Fn(&str) -> u64 + Send + Sync + 'static,
+    {
+        self.key_hasher = Some(Box::new(hasher));
+        self
+    }
+
+    /// Keeps the last `versions` values of each key instead of only the latest, for `KvStore::get_versions`
+    ///
+    /// Compaction copies every kept value forward, and only values older than that count as
+    /// dead. Removing or expiring a key drops its older values too. The index holds each key
+    /// with older values in full, even a hashed index.
+    pub fn keep_versions(mut self, versions: usize) -> KvStoreBuilder<P> {
+        self.keep_versions = Some(versions);
+        self
+    }
+
+    /// Serves reads from sealed generations through memory maps instead of seek and read calls
+    ///
+    /// The generation being written to is still read through a buffered reader, since it keeps
+    /// growing. Logs are never truncated while the store is open, but another process truncating
+    /// a mapped log would crash this one.
+    pub fn mmap_reads(mut self) -> KvStoreBuilder<P> {
+        self.mmap_reads = true;
+        self
+    }
+
+    /// Opens the store without a writable generation, so several processes can read the same directory
+    ///
+    /// Nothing in the directory is created, truncated or deleted, other than a missing lock file,
+    /// and the store must already exist.
+    /// The index is a snapshot of the logs at open time. Every write, including compaction,
+    /// fails with `KvsError::ReadOnly`. Readers share the store lock, so opening one while a
+    /// writer is open, or a writer while one is open, fails with `KvsError::Locked`.
+    pub fn read_only(mut self) -> KvStoreBuilder<P> {
+        self.read_only = true;
+        self
+    }
+
+    fn with_path<Q>(self, path: Q) -> KvStoreBuilder<Q> {
+        KvStoreBuilder {
+            value_hooks: self.value_hooks,
+            #[cfg(feature = "encryption")]
+            encryption_key: self.encryption_key,
+            #[cfg(feature = "encryption")]
+            record_encryption_key: self.record_encryption_key,
+            record_seal: self.record_seal,
+            dedup_min_size: self.dedup_min_size,
+            compress_min_size: self.compress_min_size,
+            max_log_size: self.max_log_size,
+            compaction_threshold: self.compaction_threshold,
+            separator: self.separator,
+            sync_mode: self.sync_mode,
+            read_only: self.read_only,
+            mmap_reads: self.mmap_reads,
+            buffer_capacity: self.buffer_capacity,
+            max_key_size: self.max_key_size,
+            max_value_size: self.max_value_size,
+            sweep_interval: self.sweep_interval,
+            checkpoint_interval: self.checkpoint_interval,
+            key_hasher: self.key_hasher,
+            keep_versions: self.keep_versions,
+            compaction_strategy: self.compaction_strategy,
+            engine: self.engine,
+            path,
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpStream};
+use uuid::Uuid;
+use crate::protocol::{read_message, write_frame, write_message, Handshake, Request, Response, PROTOCOL_VERSION};
+use crate::{KvsError, Result};
+
+/// Sends requests to a `KvsServer`, opening one connection per request.
+pub struct KvsClient {
+    addr: SocketAddr,
+}
+
+impl KvsClient {
+    /// Creates a client for the server listening on the given address
+    pub fn new(addr: SocketAddr) -> KvsClient {
+        KvsClient { addr }
+    }
+
+    /// Gets the value of a key from the server
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.send(&Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Sets the value of a key on the server
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        match self.send(&Request::Set { key, value })? {
+            Response::Ok => Ok(()),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Sets every pair on the server in one request, applying all of them or none
+    pub fn set_all(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self.send(&Request::SetAll { pairs })? {
+            Response::Ok => Ok(()),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Removes a key on the server, returning `KeyNotFound` if it does not exist
+    pub fn remove(&self, key: String) -> Result<()> {
+        let not_found = KvsError::KeyNotFound { key: key.clone() };
+        match self.send(&Request::Remove { key }) {
+            Ok(Response::Ok) => Ok(()),
+            Ok(_) => Err(KvsError::UnexpectedCommandType),
+            Err(KvsError::Server { message }) if message == not_found.to_string() => Err(not_found),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Sends a request tagged with `token` and waits for the server's response
+    ///
+    /// A server that already applied a request with this token answers with the response it
+    /// gave then, so resending with the same token after a timeout or a dropped connection
+    /// cannot apply the request twice. A request that failed is applied again when resent.
+    /// Errors are returned as `send` returns them.
+    pub fn send_once(&self, token: Uuid, request: Request) -> Result<Response> {
+        self.send(&Request::Once { token, request: Box::new(request) })
+    }
+
+    /// Sends a single request and waits for the server's response
+    ///
+    /// The handshake goes out with the request, and `KvsError::ProtocolVersionMismatch` is
+    /// returned if the server's handshake names another version. A `Response::Err` is turned
+    /// into `KvsError::Server` and `Response::Busy` into `KvsError::ServerBusy`.
+    fn send(&self, request: &Request) -> Result<Response> {
+        let stream = TcpStream::connect(self.addr)?;
+        let mut writer = BufWriter::new(&stream);
+        write_frame(&mut writer, &Handshake { version: PROTOCOL_VERSION })?;
+        write_message(&mut writer, request)?;
+
+        let mut reader = BufReader::new(&stream);
+        let handshake: Handshake = read_message(&mut reader)?;
+        if handshake.version != PROTOCOL_VERSION {
+            return Err(KvsError::ProtocolVersionMismatch { client: PROTOCOL_VERSION, server: handshake.version });
+        }
+        let response = read_message(&mut reader)?;
+        match response {
+            Response::Err(message) => Err(KvsError::Server { message }),
+            Response::Busy => Err(KvsError::ServerBusy),
+            response => Ok(response),
+        }
+    }
+}
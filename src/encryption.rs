@@ -0,0 +1,46 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use crate::{KvsError, ValueHooks};
+
+const NONCE_LEN: usize = 12;
+
+/// Wraps the given value hooks so values are AES-256-GCM encrypted after `on_write` and decrypted before `on_read`.
+///
+/// Each value is stored as a random nonce followed by the ciphertext.
+pub(crate) fn encrypted_hooks(key: [u8; 32], inner: Option<ValueHooks>) -> ValueHooks {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    let decipher = cipher.clone();
+    let (inner_write, inner_read) = match inner {
+        Some(hooks) => (Some(hooks.on_write), Some(hooks.on_read)),
+        None => (None, None),
+    };
+
+    ValueHooks {
+        on_write: Box::new(move |value| {
+            let plaintext = match &inner_write {
+                Some(on_write) => on_write(value),
+                None => value.to_vec(),
+            };
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let ciphertext = cipher
+                .encrypt(&nonce, plaintext.as_slice())
+                .expect("AES-GCM encryption of an in-memory buffer cannot fail");
+            let mut stored = nonce.to_vec();
+            stored.extend_from_slice(&ciphertext);
+            stored
+        }),
+        on_read: Box::new(move |stored| {
+            if stored.len() < NONCE_LEN {
+                return Err(KvsError::DecryptionFailed);
+            }
+            let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+            let plaintext = decipher
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map_err(|_| KvsError::DecryptionFailed)?;
+            match &inner_read {
+                Some(on_read) => on_read(&plaintext),
+                None => Ok(plaintext),
+            }
+        }),
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Bits set per key.
+const PROBES: u64 = 7;
+/// Bits of filter per key, which with 7 probes gives about a 1% false positive rate.
+const BITS_PER_KEY: usize = 10;
+
+/// A bloom filter over the keys written to one generation.
+///
+/// `may_contain` is never wrong about a key that was added, it only sometimes
+/// claims keys that were not. Keys are hashed independently of any `KeyHasher`,
+/// so keys that collide in a hashed index are still told apart.
+pub(crate) struct KeyFilter {
+    bits: Vec<u64>,
+}
+
+impl KeyFilter {
+    /// Hashes a key the way the filter does, so callers can collect keys without holding onto them
+    pub(crate) fn hash(key: &str) -> u64 {
+        // Salted so keys that collide under the default `hashed_index` hash still set different bits
+        let mut hasher = DefaultHasher::new();
+        "bloom".hash(&mut hasher);
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Builds a filter sized for the given key hashes
+    pub(crate) fn from_hashes(hashes: &[u64]) -> KeyFilter {
+        let words = ((hashes.len() * BITS_PER_KEY + 63) / 64).max(1);
+        let mut filter = KeyFilter { bits: vec![0; words] };
+        for &hash in hashes {
+            for bit in filter.probes(hash) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    /// Encodes the filter's bits as big-endian words
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.bits.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    /// Decodes a filter encoded by `to_bytes`, returning `None` for bytes that cannot be one
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<KeyFilter> {
+        if bytes.is_empty() || bytes.len() % 8 != 0 {
+            return None;
+        }
+        let bits = bytes.chunks_exact(8).map(|word| u64::from_be_bytes(word.try_into().unwrap())).collect();
+        Some(KeyFilter { bits })
+    }
+
+    /// Returns false only if the key was never added
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.probes(KeyFilter::hash(key)).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits a hash sets, derived from its two halves
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let (low, high) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..PROBES).map(move |probe| (low.wrapping_add(probe.wrapping_mul(high)) % len) as usize)
+    }
+}
@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use crate::engines::bloom::KeyFilter;
+use crate::{KeyHasher, LogSection, Result};
+
+/// Reads back the key of the record a section points at.
+pub(crate) type ResolveKey<'a> = &'a dyn Fn(&LogSection) -> Result<String>;
+
+/// Maps each live key to the section holding its latest record, along with any older versions kept.
+#[derive(Clone)]
+pub(crate) struct KeyIndex {
+    map: KeyMap,
+    versions: Option<Versions>,
+}
+
+/// The sections of each key's older records, kept when a store retains more than one version.
+///
+/// Rings are keyed by the full key even under a hashed index.
+#[derive(Clone)]
+struct Versions {
+    /// Older sections kept per key.
+    keep: usize,
+    /// Newest first.
+    older: HashMap<String, VecDeque<LogSection>>,
+}
+
+/// The latest section of each live key.
+///
+/// By default every key is held in memory. A hashed index only holds a hash of
+/// each key and confirms a match by reading the record's key back through the
+/// resolver, so lookups cost a disk read. Keys whose hash is already taken by
+/// a different key are held in full. Each sealed generation also gets a bloom
+/// filter of its keys, so a hash match in a generation that never held the key
+/// is ruled out without the read.
+#[derive(Clone)]
+enum KeyMap {
+    Keys(BTreeMap<String, LogSection>),
+    Hashed {
+        /// Shared so a copy of the index hashes keys the same way.
+        hasher: Arc<KeyHasher>,
+        hashes: HashMap<u64, LogSection>,
+        collisions: BTreeMap<String, LogSection>,
+        /// Shared with copies of the index, a sealed generation's keys never change.
+        filters: HashMap<u64, Arc<KeyFilter>>,
+        /// Filter hashes of the keys written to each generation not sealed yet.
+        unsealed: HashMap<u64, Vec<u64>>,
+    },
+}
+
+impl KeyIndex {
+    /// Creates an index keeping `versions` values of each key, 1 for only the latest
+    pub(crate) fn new(hasher: Option<KeyHasher>, versions: usize) -> KeyIndex {
+        let versions = (versions > 1).then(|| Versions { keep: versions - 1, older: HashMap::new() });
+        KeyIndex { map: KeyMap::new(hasher), versions }
+    }
+
+    /// Wraps a plain key map, keeping only the latest version of each key
+    pub(crate) fn from_keys(keys: BTreeMap<String, LogSection>) -> KeyIndex {
+        KeyIndex { map: KeyMap::Keys(keys), versions: None }
+    }
+
+    /// Unwraps the key map of an index that holds every key in full
+    pub(crate) fn into_keys(self) -> Option<BTreeMap<String, LogSection>> {
+        match self.map {
+            KeyMap::Keys(keys) => Some(keys),
+            KeyMap::Hashed { .. } => None,
+        }
+    }
+
+    /// Returns the section for a key, `None` if it is not indexed
+    pub(crate) fn get(&self, key: &str, resolve: ResolveKey) -> Result<Option<LogSection>> {
+        self.map.get(key, resolve)
+    }
+
+    /// Points a key at a new section, returning the section that is no longer kept
+    ///
+    /// That is the section replaced, or when older versions are kept, the oldest one pushed out of them.
+    pub(crate) fn insert(&mut self, key: String, section: LogSection, resolve: ResolveKey) -> Result<Option<LogSection>> {
+        let versions = match &mut self.versions {
+            Some(versions) => versions,
+            None => return self.map.insert(key, section, resolve),
+        };
+        let replaced = match self.map.insert(key.clone(), section, resolve)? {
+            Some(replaced) => replaced,
+            None => {
+                // Left behind if the key expired without a remove, and not the history of this key
+                versions.older.remove(&key);
+                return Ok(None);
+            }
+        };
+        let ring = versions.older.entry(key).or_default();
+        ring.push_front(replaced);
+        Ok(if ring.len() > versions.keep { ring.pop_back() } else { None })
+    }
+
+    /// Drops a key and any older versions of it, returning the bytes of every record it held
+    ///
+    /// Returns `None` if the key is not indexed.
+    pub(crate) fn remove(&mut self, key: &str, resolve: ResolveKey) -> Result<Option<u64>> {
+        let older: u64 = match &mut self.versions {
+            Some(versions) => versions.older.remove(key).map_or(0, |ring| ring.iter().map(|section| section.length()).sum()),
+            None => 0,
+        };
+        Ok(self.map.remove(key, resolve)?.map(|section| section.length() + older))
+    }
+
+    /// Returns the older sections kept for a key, newest first
+    pub(crate) fn older_versions(&self, key: &str) -> impl DoubleEndedIterator<Item = &LogSection> {
+        self.versions.as_ref().and_then(|versions| versions.older.get(key)).into_iter().flatten()
+    }
+
+    /// Takes every key's older sections out of the index, for compaction to rewrite
+    pub(crate) fn take_older_versions(&mut self) -> HashMap<String, VecDeque<LogSection>> {
+        self.versions.as_mut().map(|versions| std::mem::take(&mut versions.older)).unwrap_or_default()
+    }
+
+    /// Puts back older sections taken with `take_older_versions`
+    pub(crate) fn restore_older_versions(&mut self, older: HashMap<String, VecDeque<LogSection>>) {
+        if let Some(versions) = &mut self.versions {
+            versions.older = older;
+        }
+    }
+
+    /// Returns every key and latest section between the bounds, in key order
+    ///
+    /// A hashed index has to read back every key to do this.
+    pub(crate) fn range(&self, start: Bound<&str>, end: Bound<&str>, resolve: ResolveKey) -> Result<Vec<(String, LogSection)>> {
+        self.map.range(start, end, resolve)
+    }
+
+    /// Returns the latest section of every key between the bounds, in key order, without copying the keys
+    ///
+    /// A hashed index still has to read back every key to do this.
+    pub(crate) fn range_sections(&self, start: Bound<&str>, end: Bound<&str>, resolve: ResolveKey) -> Result<Vec<LogSection>> {
+        match &self.map {
+            KeyMap::Keys(keys) => Ok(keys.range::<str, _>((start, end)).map(|(_, section)| *section).collect()),
+            map => Ok(map.range(start, end, resolve)?.into_iter().map(|(_, section)| section).collect()),
+        }
+    }
+
+    /// Returns every latest section, in no particular order
+    pub(crate) fn sections(&self) -> Box<dyn Iterator<Item = &LogSection> + '_> {
+        self.map.sections()
+    }
+
+    /// Returns every section including older versions for updating in place, in no particular order
+    pub(crate) fn sections_mut(&mut self) -> Box<dyn Iterator<Item = &mut LogSection> + '_> {
+        let older = self.versions.iter_mut().flat_map(|versions| versions.older.values_mut()).flatten();
+        Box::new(self.map.sections_mut().chain(older))
+    }
+
+    /// Keeps only the latest sections for which `keep` returns true, stopping at the first error
+    ///
+    /// Sections not yet visited when `keep` fails are all kept. Older versions are left alone.
+    pub(crate) fn try_retain(&mut self, keep: impl FnMut(&mut LogSection) -> Result<bool>) -> Result<()> {
+        self.map.try_retain(keep)
+    }
+
+    /// Builds the filter for a generation that is no longer written to
+    ///
+    /// `moved` holds filter hashes of keys whose sections were moved into it rather than inserted.
+    pub(crate) fn seal(&mut self, gen: u64, moved: Vec<u64>) {
+        self.map.seal(gen, moved)
+    }
+
+    /// The filter of a sealed generation, if the index keeps one
+    pub(crate) fn filter(&self, gen: u64) -> Option<Arc<KeyFilter>> {
+        self.map.filter(gen)
+    }
+
+    /// Seals a generation with a filter saved when it was first sealed, instead of building one
+    pub(crate) fn load_filter(&mut self, gen: u64, filter: KeyFilter) {
+        self.map.load_filter(gen, filter)
+    }
+
+    /// Forgets the filters of every generation before `gen`, once their logs are deleted
+    pub(crate) fn drop_filters_before(&mut self, gen: u64) {
+        self.map.drop_filters_before(gen)
+    }
+
+    /// Forgets the filter of a single generation, once its log is deleted
+    pub(crate) fn drop_filter(&mut self, gen: u64) {
+        self.map.drop_filter(gen)
+    }
+
+    /// Moves a generation's filter along with its log when the generation is renumbered
+    pub(crate) fn renumber_filter(&mut self, from: u64, to: u64) {
+        self.map.renumber_filter(from, to)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.map.clear();
+        if let Some(versions) = &mut self.versions {
+            versions.older.clear();
+        }
+    }
+}
+
+impl KeyMap {
+    fn new(hasher: Option<KeyHasher>) -> KeyMap {
+        match hasher {
+            Some(hasher) => KeyMap::Hashed {
+                hasher: Arc::new(hasher),
+                hashes: HashMap::new(),
+                collisions: BTreeMap::new(),
+                filters: HashMap::new(),
+                unsealed: HashMap::new(),
+            },
+            None => KeyMap::Keys(BTreeMap::new()),
+        }
+    }
+
+    fn get(&self, key: &str, resolve: ResolveKey) -> Result<Option<LogSection>> {
+        match self {
+            KeyMap::Keys(keys) => Ok(keys.get(key).copied()),
+            KeyMap::Hashed { hasher, hashes, collisions, filters, .. } => {
+                if let Some(section) = collisions.get(key) {
+                    return Ok(Some(*section));
+                }
+                match hashes.get(&hasher(key)) {
+                    Some(section) if holds(filters, section, key, resolve)? => Ok(Some(*section)),
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Points a key at a new section, returning the section it replaces
+    fn insert(&mut self, key: String, section: LogSection, resolve: ResolveKey) -> Result<Option<LogSection>> {
+        match self {
+            KeyMap::Keys(keys) => Ok(keys.insert(key, section)),
+            KeyMap::Hashed { hasher, hashes, collisions, filters, unsealed } => {
+                unsealed.entry(section.gen()).or_default().push(KeyFilter::hash(&key));
+                if let Some(existing) = collisions.get_mut(&key) {
+                    return Ok(Some(std::mem::replace(existing, section)));
+                }
+                let hash = hasher(&key);
+                match hashes.get_mut(&hash) {
+                    None => {
+                        hashes.insert(hash, section);
+                        Ok(None)
+                    }
+                    Some(existing) if holds(filters, existing, &key, resolve)? => Ok(Some(std::mem::replace(existing, section))),
+                    Some(_) => Ok(collisions.insert(key, section)),
+                }
+            }
+        }
+    }
+
+    /// Drops a key, returning the section it pointed at
+    fn remove(&mut self, key: &str, resolve: ResolveKey) -> Result<Option<LogSection>> {
+        match self {
+            KeyMap::Keys(keys) => Ok(keys.remove(key)),
+            KeyMap::Hashed { hasher, hashes, collisions, filters, .. } => {
+                if let Some(section) = collisions.remove(key) {
+                    return Ok(Some(section));
+                }
+                let hash = hasher(key);
+                match hashes.get(&hash) {
+                    Some(section) if holds(filters, section, key, resolve)? => Ok(hashes.remove(&hash)),
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+
+    fn range(&self, start: Bound<&str>, end: Bound<&str>, resolve: ResolveKey) -> Result<Vec<(String, LogSection)>> {
+        match self {
+            KeyMap::Keys(keys) => Ok(keys
+                .range::<str, _>((start, end))
+                .map(|(key, section)| (key.clone(), *section))
+                .collect()),
+            KeyMap::Hashed { hashes, collisions, .. } => {
+                let mut entries = Vec::new();
+                for section in hashes.values() {
+                    let key = resolve(section)?;
+                    if (start, end).contains(&key.as_str()) {
+                        entries.push((key, *section));
+                    }
+                }
+                entries.extend(collisions
+                    .range::<str, _>((start, end))
+                    .map(|(key, section)| (key.clone(), *section)));
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                Ok(entries)
+            }
+        }
+    }
+
+    /// Returns every section, in no particular order
+    fn sections(&self) -> Box<dyn Iterator<Item = &LogSection> + '_> {
+        match self {
+            KeyMap::Keys(keys) => Box::new(keys.values()),
+            KeyMap::Hashed { hashes, collisions, .. } => Box::new(hashes.values().chain(collisions.values())),
+        }
+    }
+
+    /// Returns every section for updating in place, in no particular order
+    fn sections_mut(&mut self) -> Box<dyn Iterator<Item = &mut LogSection> + '_> {
+        match self {
+            KeyMap::Keys(keys) => Box::new(keys.values_mut()),
+            KeyMap::Hashed { hashes, collisions, .. } => Box::new(hashes.values_mut().chain(collisions.values_mut())),
+        }
+    }
+
+    fn try_retain(&mut self, mut keep: impl FnMut(&mut LogSection) -> Result<bool>) -> Result<()> {
+        let mut result = Ok(());
+        let mut visit = |section: &mut LogSection| match result {
+            Ok(()) => keep(section).unwrap_or_else(|err| {
+                result = Err(err);
+                true
+            }),
+            Err(_) => true,
+        };
+        match self {
+            KeyMap::Keys(keys) => keys.retain(|_, section| visit(section)),
+            KeyMap::Hashed { hashes, collisions, .. } => {
+                hashes.retain(|_, section| visit(section));
+                collisions.retain(|_, section| visit(section));
+            }
+        }
+        result
+    }
+
+    fn seal(&mut self, gen: u64, mut moved: Vec<u64>) {
+        if let KeyMap::Hashed { filters, unsealed, .. } = self {
+            moved.extend(unsealed.remove(&gen).unwrap_or_default());
+            filters.insert(gen, Arc::new(KeyFilter::from_hashes(&moved)));
+        }
+    }
+
+    fn filter(&self, gen: u64) -> Option<Arc<KeyFilter>> {
+        match self {
+            KeyMap::Keys(_) => None,
+            KeyMap::Hashed { filters, .. } => filters.get(&gen).cloned(),
+        }
+    }
+
+    fn load_filter(&mut self, gen: u64, filter: KeyFilter) {
+        if let KeyMap::Hashed { filters, unsealed, .. } = self {
+            unsealed.remove(&gen);
+            filters.insert(gen, Arc::new(filter));
+        }
+    }
+
+    fn drop_filters_before(&mut self, gen: u64) {
+        if let KeyMap::Hashed { filters, unsealed, .. } = self {
+            filters.retain(|&filtered, _| filtered >= gen);
+            unsealed.retain(|&filtered, _| filtered >= gen);
+        }
+    }
+
+    fn drop_filter(&mut self, gen: u64) {
+        if let KeyMap::Hashed { filters, unsealed, .. } = self {
+            filters.remove(&gen);
+            unsealed.remove(&gen);
+        }
+    }
+
+    fn renumber_filter(&mut self, from: u64, to: u64) {
+        if let KeyMap::Hashed { filters, .. } = self {
+            if let Some(filter) = filters.remove(&from) {
+                filters.insert(to, filter);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            KeyMap::Keys(keys) => keys.clear(),
+            KeyMap::Hashed { hashes, collisions, filters, unsealed, .. } => {
+                hashes.clear();
+                collisions.clear();
+                filters.clear();
+                unsealed.clear();
+            }
+        }
+    }
+}
+
+/// Whether the record at a section is for the key, reading it back only if the generation's filter allows it
+fn holds(filters: &HashMap<u64, Arc<KeyFilter>>, section: &LogSection, key: &str, resolve: ResolveKey) -> Result<bool> {
+    match filters.get(&section.gen()) {
+        Some(filter) if !filter.may_contain(key) => Ok(false),
+        _ => Ok(resolve(section)? == key),
+    }
+}
@@ -0,0 +1,3785 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::{ File, self, OpenOptions };
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use fs2::FileExt;
+use memmap2::Mmap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::engines::bloom::KeyFilter;
+use crate::engines::index::{KeyIndex, ResolveKey};
+use crate::trace::{error_if_failed, warn_if_failed};
+use crate::{KvStoreBuilder, KvsEngine, KvsError, Namespace, Result, TypedKvStore};
+
+/// Callback invoked with the key and value size when a `set` exceeds the soft value threshold.
+///
+/// Returning `false` vetoes the write.
+pub type LargeValueHook = Box<dyn Fn(&str, usize) -> bool + Send + Sync>;
+
+/// Hashes a key for a hashed index, see `KvStoreBuilder::hashed_index`.
+pub type KeyHasher = Box<dyn Fn(&str) -> u64 + Send + Sync>;
+
+/// Transforms value bytes before they are written to the log.
+pub type WriteHook = Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Reverses a `WriteHook` on value bytes read back from the log.
+pub type ReadHook = Box<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+/// Uncompacted bytes that trigger a compaction unless `KvStoreBuilder::compaction_threshold` says otherwise.
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Generations a size-tiered merge rewrites at once, and the growth in size from one tier to the next.
+const TIER_FANOUT: usize = 4;
+pub(crate) const META_FILE: &str = "META";
+const CHECKPOINT_FILE: &str = "CHECKPOINT";
+/// Hashed into every saved filter, so filters saved by a build that hashes keys differently are rebuilt.
+const FILTER_PROBE_KEY: &str = "kvs filter probe";
+const LOCK_FILE: &str = "KVS_LOCK";
+const BLOB_DIR: &str = "blobs";
+const DEFAULT_SEPARATOR: &[u8] = b"\n";
+/// Marks the start of every log file, ahead of the format version.
+const LOG_MAGIC: &[u8; 4] = b"KVSL";
+/// Follows the magic as a 4-byte big-endian number, bumped whenever the record encoding changes.
+///
+/// Each record after the header is the 4-byte big-endian length and CRC32 of
+/// the bincode encoded `Command`, the command itself and the record separator.
+/// A `Command::SetStream` record is followed by its raw value and the value's
+/// 4-byte big-endian CRC32.
+const LOG_FORMAT_VERSION: u32 = 6;
+/// Version 5 logs are version 6 logs without `Command::SetBytes` records, version 4 records
+/// are version 5 records without a sequence number, and version 3 logs are version 4 logs
+/// without streamed values, so all three are still read.
+///
+/// Compaction copies records verbatim, so a log of any version may hold records without a
+/// sequence number. They decode as `LegacyCommand` and get sequence number 0.
+const OLDEST_LOG_FORMAT_VERSION: u32 = 3;
+/// Bytes of magic and version at the start of every log.
+const LOG_HEADER_LEN: usize = 8;
+/// Logs from before the magic start with just this version byte.
+///
+/// Their records are laid out exactly as in version 3, so they are read in place
+/// and pick up the current header when compaction rewrites them.
+const LEGACY_LOG_FORMAT_VERSION: u8 = 2;
+/// Bytes of length and checksum in front of every record body.
+const RECORD_HEADER_LEN: usize = 8;
+/// Bytes of checksum after a streamed value.
+const PAYLOAD_TRAILER_LEN: u64 = 4;
+/// Longest key and value accepted unless `KvStoreBuilder` sets other limits.
+const DEFAULT_MAX_KEY_SIZE: usize = 4 * 1024;
+const DEFAULT_MAX_VALUE_SIZE: u64 = 64 * 1024 * 1024;
+/// Buffer size for log readers and writers unless `KvStoreBuilder::buffer_capacity` says otherwise.
+const DEFAULT_BUFFER_CAPACITY: usize = 8 * 1024;
+/// Most bytes of a streamed value held in memory at once.
+const STREAM_CHUNK_LEN: usize = 64 * 1024;
+
+/// The `KvStore` stores string key/value pairs.
+///
+/// Key/value pairs are appended to logs on disk, with an in-memory index
+/// ordered by key pointing at each value's record.
+///
+/// Clones share the same index and writer, so a `KvStore` can be cloned into
+/// worker threads. Each clone keeps its own read handles onto the logs.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvStore {
+    config: Arc<StoreConfig>,
+    index: Arc<RwLock<KeyIndex>>,
+    writer: Arc<Mutex<LogWriter>>,
+    reader: LogReader,
+    /// Dropped after the fields above, so background threads are stopped once the last clone is gone.
+    _background: Vec<Arc<Periodic>>,
+}
+
+/// What the index holds for a key.
+enum Lookup {
+    Missing,
+    Expired { gen: u64, offset: u64 },
+    /// The value, with the sequence number of the write that set it.
+    Value(String, u64),
+}
+
+/// Settings fixed when the store is opened, shared by every clone.
+struct StoreConfig {
+    id: Uuid,
+    path: PathBuf,
+    value_hooks: Option<ValueHooks>,
+    dedup_min_size: Option<usize>,
+    compress_min_size: Option<usize>,
+    max_log_size: Option<u64>,
+    compaction_threshold: u64,
+    compaction_strategy: CompactionStrategy,
+    format: RecordFormat,
+    /// Buffer size for log writers and sequential log scans.
+    buffer_capacity: usize,
+    max_key_size: usize,
+    max_value_size: u64,
+    /// Number of live snapshots and backups, which may still read any blob.
+    snapshots: AtomicUsize,
+    /// Records replayed by the open, see `Stats::replayed_records`.
+    replayed_records: u64,
+}
+
+impl StoreConfig {
+    /// Checks a key and the length of its value against the store's limits before either is written
+    fn check_entry(&self, key: &str, value_len: u64) -> Result<()> {
+        if key.is_empty() {
+            return Err(KvsError::EmptyKey);
+        }
+        if key.len() > self.max_key_size {
+            return Err(KvsError::KeyTooLong { len: key.len(), max: self.max_key_size });
+        }
+        if value_len > self.max_value_size {
+            return Err(KvsError::ValueTooLong { key: key.to_owned(), len: value_len, max: self.max_value_size });
+        }
+        Ok(())
+    }
+
+    /// Reads and decodes the value stored in a section
+    fn read_value(&self, reader: &LogReader, section: &LogSection) -> Result<String> {
+        Ok(self.read_value_with_seq(reader, section)?.0)
+    }
+
+    /// Reads and decodes the value stored in a section, along with the sequence number of its write
+    fn read_value_with_seq(&self, reader: &LogReader, section: &LogSection) -> Result<(String, u64)> {
+        let buffer = reader.read_section(section)?;
+        let command = parse_record(&buffer, &self.format, section.gen, section.start)?;
+        let seq = command.seq();
+        Ok((self.command_value(reader, command, section)?, seq))
+    }
+
+    /// Decodes the value of a command read from a section
+    ///
+    /// The index only ever points at set records, as a removal drops the key from it, so a
+    /// remove record here means the section is wrong and `KvsError::Corrupt` is returned.
+    fn command_value(&self, reader: &LogReader, command: Command, section: &LogSection) -> Result<String> {
+        let (gen, offset) = (section.gen, section.start);
+        match command {
+            Command::Set { value, .. } => self.decode_value(value, gen, offset),
+            Command::SetRef { hash, .. } => self.read_blob_value(&hash, gen, offset),
+            Command::SetCompressed { value, .. } => self.decode_compressed(&value, gen, offset),
+            Command::SetBytes { value, .. } => self.decode_bytes(value, gen, offset),
+            Command::SetStream { len, .. } => {
+                let mut value = Vec::with_capacity(len as usize);
+                self.copy_payload(reader, section, len, &mut value)?;
+                Ok(String::from_utf8(value)?)
+            }
+            Command::Remove { .. } => Err(KvsError::Corrupt { gen, offset }),
+        }
+    }
+
+    /// Writes the streamed value of a section to `dst`, then checks it against its checksum
+    fn copy_payload(&self, reader: &LogReader, section: &LogSection, len: u64, dst: &mut impl Write) -> Result<()> {
+        let start = section.start + section.frame_length();
+        let checksum = reader.copy_range(section.gen, start, len, dst)?;
+        let stored = reader.read_at(section.gen, start + len, PAYLOAD_TRAILER_LEN)?;
+        if stored != checksum.to_be_bytes() {
+            return Err(KvsError::CorruptRecord { gen: section.gen, offset: section.start });
+        }
+        Ok(())
+    }
+
+    /// Reads and decodes a deduplicated value, reporting a missing or mangled blob against the record pointing at it
+    fn read_blob_value(&self, hash: &str, gen: u64, offset: u64) -> Result<String> {
+        let corrupt = || KvsError::Corrupt { gen, offset };
+        let stored = read_blob(&self.path, hash).map_err(|_| corrupt())?;
+        let stored = self.format.unseal(&stored)?;
+        let value = String::from_utf8(stored.into_owned()).map_err(|_| corrupt())?;
+        self.decode_value(value, gen, offset)
+    }
+
+    /// Reverses any value hooks applied to a stored value
+    fn decode_value(&self, value: String, gen: u64, offset: u64) -> Result<String> {
+        match &self.value_hooks {
+            Some(hooks) => hooks.decode(&value, gen, offset),
+            None => Ok(value),
+        }
+    }
+
+    /// Reverses any value hooks on a value stored as bytes
+    fn decode_bytes(&self, stored: Vec<u8>, gen: u64, offset: u64) -> Result<String> {
+        let value = match &self.value_hooks {
+            Some(hooks) => (hooks.on_read)(&stored)?,
+            None => stored,
+        };
+        String::from_utf8(value).map_err(|_| KvsError::Corrupt { gen, offset })
+    }
+
+    /// Reverses any value hooks on a compressed value, then inflates it
+    fn decode_compressed(&self, stored: &[u8], gen: u64, offset: u64) -> Result<String> {
+        let compressed = match &self.value_hooks {
+            Some(hooks) => (hooks.on_read)(stored)?,
+            None => stored.to_vec(),
+        };
+        let mut value = String::new();
+        DeflateDecoder::new(compressed.as_slice())
+            .read_to_string(&mut value)
+            .map_err(|_| KvsError::Corrupt { gen, offset })?;
+        Ok(value)
+    }
+}
+
+/// State owned by whoever currently holds the write lock.
+struct LogWriter {
+    gen: u64,
+    /// `None` when the store was opened read-only, or after a failed write could not be undone.
+    log: Option<TrackingBufWriter<File>>,
+    uncompacted: u64,
+    large_value_hook: Option<(usize, LargeValueHook)>,
+    subscribers: Vec<Sender<ChangeEvent>>,
+    sync_mode: SyncMode,
+    last_sync: Instant,
+    /// Sequence number of the last write, recovered on open from the logs and `StoreMeta`.
+    seq: u64,
+    /// The `LogReader` epoch, bumped when the log is cut so no reader keeps bytes from before the cut.
+    reader_epoch: Arc<AtomicU64>,
+    metrics: Metrics,
+    /// Locked by `lock_store` for as long as any clone is open, closing it releases the lock.
+    _lock: File,
+}
+
+impl LogWriter {
+    /// Returns the current generation's log, failing with `KvsError::ReadOnly` if there is none
+    fn log(&mut self) -> Result<&mut TrackingBufWriter<File>> {
+        self.log.as_mut().ok_or(KvsError::ReadOnly)
+    }
+
+    /// Flushes the records written since the last commit, then fsyncs as the sync mode asks
+    fn commit(&mut self) -> Result<()> {
+        self.log()?.flush()?;
+        let due = match self.sync_mode {
+            SyncMode::EveryWrite => true,
+            SyncMode::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncMode::Never => false,
+        };
+        if due {
+            self.log()?.sync_all()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Runs `write` against the current log and commits it, cutting the log back to where it was if either fails
+    ///
+    /// Records from a failed write never reach the index, so without the cut a partial record
+    /// would be followed by later ones and replay would stop at it. The original error is
+    /// returned. If the cut fails too the log is closed and every later write is refused.
+    fn write_and_commit<T>(&mut self, write: impl FnOnce(&mut LogWriter) -> Result<T>) -> Result<T> {
+        // Anything still buffered, such as a fresh log's header, goes out first so the cut never reaches it
+        self.log()?.flush()?;
+        let start = self.log()?.pos;
+        let result = write(self).and_then(|value| self.commit().map(|()| value));
+        if result.is_err() {
+            if let Some(log) = self.log.take() {
+                self.log = log.truncate(start).ok();
+            }
+            // Readers may have buffered the cut bytes, which later records will overwrite
+            self.reader_epoch.fetch_add(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Hands out the sequence number for the next write
+    ///
+    /// A number taken by a write that fails is never reused, so numbers only ever increase but may skip.
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    /// Sends an event to every subscriber, forgetting those whose receiver has been dropped
+    fn notify(&mut self, event: ChangeEvent) {
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+impl Drop for LogWriter {
+    /// Fsyncs the current generation once the last clone of the store is gone
+    ///
+    /// There is nowhere to report a failure from here, so it is ignored; use `KvStore::close` to see it.
+    fn drop(&mut self) {
+        if let Some(log) = &mut self.log {
+            let _ = log.sync_all();
+        }
+    }
+}
+
+/// When writes are forced from the OS page cache onto disk.
+///
+/// Every write is flushed to the OS before it returns, so it survives the
+/// process crashing whatever the mode. The mode decides what survives the
+/// machine losing power: `EveryWrite` loses nothing but pays for an fsync on
+/// every `set` and `remove`, `Interval` loses at most the writes since the
+/// last fsync, and `Never` leaves it to the OS. `KvStore::close` and dropping
+/// the last clone of a store always fsync.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Fsync after every write.
+    EveryWrite,
+    /// Fsync on a write when at least this long has passed since the last one.
+    Interval(Duration),
+    /// Never fsync, leaving it to the OS.
+    #[default]
+    Never,
+}
+
+/// How writes decide which logs to compact, see `KvStoreBuilder::compaction_strategy`.
+///
+/// `Full` rewrites every live record at once, which reclaims all the dead bytes but holds up
+/// writes for as long as copying the whole store takes. `SizeTiered` only ever merges a few
+/// logs of about the same size, so each pause is bounded by the size of those logs rather
+/// than the store. Dead bytes linger longer, and blobs no longer referenced are only
+/// deleted by a full compaction such as `KvStore::compact`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompactionStrategy {
+    /// Rewrite the live set into a new generation once `compaction_threshold` bytes are dead.
+    #[default]
+    Full,
+    /// Seal the current log once it passes `max_log_size`, or `compaction_threshold` without
+    /// one, then merge the oldest four neighbouring sealed logs that share a size tier.
+    /// Each tier holds logs up to four times the size of the one below.
+    SizeTiered,
+}
+
+impl KvStore {
+    /// Inserts the given file position for the given key
+    ///
+    /// If the key already exists, the previous position will be replaced.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        debug!(key = %key, len = value.len(), "set");
+        warn_if_failed("set", self.set_entry(key, value, None))
+    }
+
+    /// Sets a key that will read as absent once the given duration has passed
+    ///
+    /// Expired records are dropped from the index and reclaimed by compaction.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.set_entry(key, value, Some(expires_at))
+    }
+
+    fn set_entry(&self, key: String, value: String, expires_at: Option<u64>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.set_locked(&mut writer, key, value, expires_at)
+    }
+
+    /// Sets a key to `new` only if its current value is `expected`, returning whether it was set
+    ///
+    /// `None` as the expected value means the key must not exist. The check and the
+    /// write happen under the write lock, so no other write can come between them.
+    pub fn compare_and_swap(&self, key: String, expected: Option<String>, new: String) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        if self.get_locked(&mut writer, &key)? != expected {
+            return Ok(false);
+        }
+        self.set_locked(&mut writer, key, new, None)?;
+        Ok(true)
+    }
+
+    /// Adds `delta` to the integer stored at `key` and returns the new value
+    ///
+    /// A missing key counts as 0. The read and the write happen under the write lock, so
+    /// concurrent increments are never lost. Returns `KvsError::NotAnInteger` if the current
+    /// value does not parse as an `i64` or the sum overflows.
+    pub fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let mut writer = self.writer.lock().unwrap();
+        let current = match self.get_locked(&mut writer, &key)? {
+            Some(value) => value.parse::<i64>().ok(),
+            None => Some(0),
+        };
+        let next = match current.and_then(|current| current.checked_add(delta)) {
+            Some(next) => next,
+            None => return Err(KvsError::NotAnInteger { key }),
+        };
+        self.set_locked(&mut writer, key, next.to_string(), None)?;
+        Ok(next)
+    }
+
+    /// Concatenates `suffix` onto the value of a key, writing the combined value as a new record
+    ///
+    /// A missing key counts as empty. The read and the write happen under the write lock, so
+    /// concurrent appends are never lost. The record it replaces counts as dead, so repeated
+    /// appends are reclaimed by compaction like any other overwrite.
+    pub fn append(&self, key: String, suffix: String) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut value = self.get_locked(&mut writer, &key)?.unwrap_or_default();
+        value.push_str(&suffix);
+        self.set_locked(&mut writer, key, value, None)
+    }
+
+    /// Replaces the value of a key with the result of `f` applied to its current value
+    ///
+    /// `f` receives `None` for a missing key. Returning `Some` writes the new value and
+    /// returning `None` removes the key, or leaves a missing key missing. The read and the
+    /// write happen under the write lock, so `f` always sees the latest value.
+    pub fn merge<F: FnOnce(Option<String>) -> Option<String>>(&self, key: String, f: F) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let current = self.get_locked(&mut writer, &key)?;
+        let existed = current.is_some();
+        match f(current) {
+            Some(value) => self.set_locked(&mut writer, key, value, None),
+            None if existed => self.remove_locked(&mut writer, key),
+            None => Ok(()),
+        }
+    }
+
+    /// Gets the value of a key, or `default` if it does not exist
+    pub fn get_or(&self, key: String, default: String) -> Result<String> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// Gets the value of a key, first setting it to `default` if it does not exist
+    ///
+    /// The check and the write happen under the write lock, so when several callers race on
+    /// a missing key only one default is written and every caller gets that value back.
+    pub fn get_or_insert(&self, key: String, default: String) -> Result<String> {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(value) = self.get_locked(&mut writer, &key)? {
+            return Ok(value);
+        }
+        self.set_locked(&mut writer, key, default.clone(), None)?;
+        Ok(default)
+    }
+
+    fn set_locked(&self, writer: &mut LogWriter, key: String, value: String, expires_at: Option<u64>) -> Result<()> {
+        self.config.check_entry(&key, value.len() as u64)?;
+        if let Some((threshold, hook)) = &writer.large_value_hook {
+            if value.len() > *threshold && !hook(&key, value.len()) {
+                return Err(KvsError::LargeValueRejected { key, size: value.len() });
+            }
+        }
+
+        let event_value = (!writer.subscribers.is_empty()).then(|| value.clone());
+        let section = writer.write_and_commit(|writer| self.append_set(writer, &key, value, expires_at))?;
+        self.insert_section(writer, key.clone(), section)?;
+        if let Some(value) = event_value {
+            writer.notify(ChangeEvent::Set { key, value });
+        }
+
+        self.after_write(writer)?;
+
+        Ok(())
+    }
+
+    /// Exchanges the values of two keys with a single flush
+    ///
+    /// Both keys must exist, otherwise `KvsError::KeyNotFound` is returned and nothing is written.
+    /// Swapping a key with itself is a no-op.
+    pub fn swap(&self, a: String, b: String) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let value_a = self.get_locked(&mut writer, &a)?.ok_or_else(|| KvsError::KeyNotFound { key: a.clone() })?;
+        let value_b = self.get_locked(&mut writer, &b)?.ok_or_else(|| KvsError::KeyNotFound { key: b.clone() })?;
+        if a == b {
+            return Ok(());
+        }
+
+        let events = (!writer.subscribers.is_empty()).then(|| [
+            ChangeEvent::Set { key: a.clone(), value: value_b.clone() },
+            ChangeEvent::Set { key: b.clone(), value: value_a.clone() },
+        ]);
+        let (section_a, section_b) = writer.write_and_commit(|writer| {
+            Ok((self.append_set(writer, &a, value_b, None)?, self.append_set(writer, &b, value_a, None)?))
+        })?;
+        self.insert_section(&mut writer, a, section_a)?;
+        self.insert_section(&mut writer, b, section_b)?;
+        for event in events.into_iter().flatten() {
+            writer.notify(event);
+        }
+
+        self.after_write(&mut writer)?;
+
+        Ok(())
+    }
+
+    /// Sets every entry in order with a single flush
+    ///
+    /// The index is only updated once all records are written and committed, so a failure
+    /// part way through leaves every key pointing at its previous value. Later entries win
+    /// when a key appears more than once.
+    pub fn set_batch(&self, entries: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in &entries {
+            self.config.check_entry(key, value.len() as u64)?;
+        }
+        let mut writer = self.writer.lock().unwrap();
+        if let Some((threshold, hook)) = &writer.large_value_hook {
+            for (key, value) in &entries {
+                if value.len() > *threshold && !hook(key, value.len()) {
+                    return Err(KvsError::LargeValueRejected { key: key.clone(), size: value.len() });
+                }
+            }
+        }
+
+        let mut events = Vec::new();
+        let sections = writer.write_and_commit(|writer| {
+            let mut sections = Vec::with_capacity(entries.len());
+            for (key, value) in entries {
+                if !writer.subscribers.is_empty() {
+                    events.push(ChangeEvent::Set { key: key.clone(), value: value.clone() });
+                }
+                let section = self.append_set(writer, &key, value, None)?;
+                sections.push((key, section));
+            }
+            Ok(sections)
+        })?;
+        for (key, section) in sections {
+            self.insert_section(&mut writer, key, section)?;
+        }
+        for event in events {
+            writer.notify(event);
+        }
+
+        self.after_write(&mut writer)?;
+
+        Ok(())
+    }
+
+    /// Sets a key to `len` bytes read from `src`, copying them into the log a chunk at a time
+    ///
+    /// The value is never held in memory whole, so it can be far larger than a `String`
+    /// should be. `get` still returns it if it is valid UTF-8, while `get_writer` streams it
+    /// back out. Streamed values skip compression and deduplication, and stores with value
+    /// hooks or record encryption refuse them with `KvsError::StreamingUnsupported` since
+    /// those transform a value all at once. If `src` ends before `len` bytes nothing is set.
+    pub fn set_reader(&self, key: String, len: u64, mut src: impl Read) -> Result<()> {
+        if self.config.value_hooks.is_some() || self.config.format.seal.is_some() {
+            return Err(KvsError::StreamingUnsupported);
+        }
+        self.config.check_entry(&key, len)?;
+        let mut writer = self.writer.lock().unwrap();
+        if let Some((threshold, hook)) = &writer.large_value_hook {
+            if len > *threshold as u64 && !hook(&key, len as usize) {
+                return Err(KvsError::LargeValueRejected { key, size: len as usize });
+            }
+        }
+
+        let section = writer.write_and_commit(|writer| self.append_stream(writer, &key, len, &mut src))?;
+        self.insert_section(&mut writer, key.clone(), section)?;
+        writer.notify(ChangeEvent::SetStream { key, len });
+
+        self.after_write(&mut writer)?;
+
+        Ok(())
+    }
+
+    /// Appends a streamed set record followed by its value and checksum, without flushing
+    fn append_stream(&self, writer: &mut LogWriter, key: &str, len: u64, src: &mut impl Read) -> Result<LogSection> {
+        let gen = writer.gen;
+        let command = Command::SetStream { key: key.to_owned(), len, expires_at: None, seq: writer.next_seq() };
+        let log = writer.log()?;
+        let pos_start = log.pos;
+        write_record(log, &command, &self.config.format)?;
+        let payload_start = log.pos;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut chunk = vec![0; len.min(STREAM_CHUNK_LEN as u64) as usize];
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = &mut chunk[..remaining.min(STREAM_CHUNK_LEN as u64) as usize];
+            src.read_exact(chunk)?;
+            hasher.update(chunk);
+            log.write_all(chunk)?;
+            remaining -= chunk.len() as u64;
+        }
+        log.write_all(&hasher.finalize().to_be_bytes())?;
+        Ok(LogSection::from((gen, pos_start, log.pos)).with_payload(log.pos - payload_start))
+    }
+
+    /// Writes the value of a key to `dst` and returns true, or returns false if the key does not exist
+    ///
+    /// A value set with `set_reader` is copied out a chunk at a time and only checked against its
+    /// checksum once all of it has been written, so `dst` may have seen a corrupt value by the time
+    /// `KvsError::CorruptRecord` is returned. Any other value is read whole and written as UTF-8.
+    pub fn get_writer(&self, key: &str, mut dst: impl Write) -> Result<bool> {
+        let index = self.read_index();
+        let section = match index.get(key, &|section| self.read_key(section))? {
+            Some(section) => section,
+            None => return Ok(false),
+        };
+        if section.is_expired(now_millis()) {
+            drop(index);
+            self.expire(&mut self.writer.lock().unwrap(), key, section.gen, section.start)?;
+            return Ok(false);
+        }
+
+        let buffer = self.reader.read_section(&section)?;
+        match parse_record(&buffer, &self.config.format, section.gen, section.start)? {
+            Command::SetStream { len, .. } => self.config.copy_payload(&self.reader, &section, len, &mut dst)?,
+            command => dst.write_all(self.config.command_value(&self.reader, command, &section)?.as_bytes())?,
+        }
+        dst.flush()?;
+        Ok(true)
+    }
+
+    /// Compacts once enough bytes are dead, otherwise rolls over to a new generation once the current one is full
+    ///
+    /// Under `CompactionStrategy::SizeTiered` every roll over is followed by at most one merge instead.
+    fn after_write(&self, writer: &mut LogWriter) -> Result<()> {
+        if self.config.compaction_strategy == CompactionStrategy::SizeTiered {
+            if writer.log()?.pos <= self.config.max_log_size.unwrap_or(self.config.compaction_threshold) {
+                return Ok(());
+            }
+            self.rotate(writer)?;
+            return error_if_failed("compact", self.merge_tier(writer));
+        }
+        if writer.uncompacted > self.config.compaction_threshold {
+            return self.compact_locked(writer);
+        }
+        match self.config.max_log_size {
+            Some(max_log_size) if writer.log()?.pos > max_log_size => self.rotate(writer),
+            _ => Ok(()),
+        }
+    }
+
+    /// Seals the current generation and continues writing in the next one
+    ///
+    /// Sections in the sealed generation stay valid, it is only removed by compaction.
+    fn rotate(&self, writer: &mut LogWriter) -> Result<()> {
+        // Later interval syncs only reach the new log, so the sealed one is synced now.
+        // It is flushed either way, so its saved filter records the length it ends at.
+        match writer.sync_mode {
+            SyncMode::Never => writer.log()?.flush()?,
+            _ => writer.log()?.sync_all()?,
+        }
+        let mut index = self.index.write().unwrap();
+        index.seal(writer.gen, Vec::new());
+        // The filter is only a shortcut, so failing to save it leaves the next open to build it again
+        let _ = error_if_failed("save filter", save_filter(&self.config.path, self.config.id, writer.gen, &index));
+        drop(index);
+        writer.gen += 1;
+        let log_file = log_file_path(&self.config.path, writer.gen);
+        writer.log = Some(create_writer_with_capacity(&log_file, self.config.buffer_capacity)?);
+        self.reader.set_active_gen(writer.gen);
+        writer.commit()
+    }
+
+    /// Appends a set record to the current generation without flushing
+    fn append_set(&self, writer: &mut LogWriter, key: &str, value: String, expires_at: Option<u64>) -> Result<LogSection> {
+        let pos_start = writer.log()?.pos;
+        let seq = writer.next_seq();
+        let hash = match self.config.dedup_min_size {
+            Some(min_size) if value.len() >= min_size => Some(format!("{:x}", Sha256::digest(value.as_bytes()))),
+            _ => None,
+        };
+        if let (Some(min_size), None) = (self.config.compress_min_size, &hash) {
+            if value.len() >= min_size {
+                if let Some(compressed) = compress(value.as_bytes())? {
+                    let value = match &self.config.value_hooks {
+                        Some(hooks) => (hooks.on_write)(&compressed),
+                        None => compressed,
+                    };
+                    let command = Command::SetCompressed { key: key.to_owned(), value, expires_at, seq };
+                    write_record(writer.log()?, &command, &self.config.format)?;
+                    return Ok(LogSection::from((writer.gen, pos_start, writer.log()?.pos)).expiring(expires_at));
+                }
+            }
+        }
+        let command = match (hash, &self.config.value_hooks) {
+            (Some(hash), hooks) => {
+                // Blobs carry no format version, so hooked values in them stay hex encoded as they always were
+                let value = match hooks {
+                    Some(hooks) => hooks.encode(&value),
+                    None => value,
+                };
+                write_blob(&self.config.path, &hash, &self.config.format.seal(value.into_bytes()))?;
+                Command::SetRef { key: key.to_owned(), hash, expires_at, seq }
+            }
+            (None, Some(hooks)) => {
+                Command::SetBytes { key: key.to_owned(), value: (hooks.on_write)(value.as_bytes()), expires_at, seq }
+            }
+            (None, None) => Command::Set { key: key.to_owned(), value, expires_at, seq },
+        };
+        write_record(writer.log()?, &command, &self.config.format)?;
+        Ok(LogSection::from((writer.gen, pos_start, writer.log()?.pos)).expiring(expires_at))
+    }
+
+    /// Points the key at a newly written record, counting any record it replaces towards the uncompacted bytes
+    fn insert_section(&self, writer: &mut LogWriter, key: String, section: LogSection) -> Result<()> {
+        if let Some(section) = self.index.write().unwrap().insert(key, section, &|section| self.read_key(section))? {
+            writer.uncompacted += section.length
+        }
+        Ok(())
+    }
+
+    /// Reads back the key of the record at a section
+    fn read_key(&self, section: &LogSection) -> Result<String> {
+        read_record_key(&self.reader, section, &self.config.format)
+    }
+
+    /// Gets the string value for a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        debug!(key = %key, "get");
+        Ok(self.get_with_seq(key)?.map(|(value, _)| value))
+    }
+
+    /// Gets the value for a key along with the sequence number of the write that set it
+    ///
+    /// Every write is numbered by the store, each number higher than any before it, so
+    /// comparing numbers orders writes to the same store even across reopens. Values written
+    /// before sequence numbers were recorded, in logs older than format version 5, have 0.
+    pub fn get_with_seq(&self, key: String) -> Result<Option<(String, u64)>> {
+        match warn_if_failed("get", self.lookup(&key))? {
+            Lookup::Value(value, seq) => Ok(Some((value, seq))),
+            Lookup::Expired { gen, offset } => {
+                self.expire(&mut self.writer.lock().unwrap(), &key, gen, offset)?;
+                Ok(None)
+            }
+            Lookup::Missing => Ok(None),
+        }
+    }
+
+    /// Gets a value while the caller already holds the writer
+    fn get_locked(&self, writer: &mut LogWriter, key: &str) -> Result<Option<String>> {
+        match self.lookup(key)? {
+            Lookup::Value(value, _) => Ok(Some(value)),
+            Lookup::Expired { gen, offset } => {
+                self.expire(writer, key, gen, offset)?;
+                Ok(None)
+            }
+            Lookup::Missing => Ok(None),
+        }
+    }
+
+    /// Takes the index read lock before reading records back from the logs
+    ///
+    /// Compaction only removes a log under the index write lock, so every log the index points
+    /// at stays on disk for as long as the guard is held.
+    fn read_index(&self) -> RwLockReadGuard<'_, KeyIndex> {
+        self.index.read().unwrap()
+    }
+
+    /// Reads the value the index points at for a key, without taking the writer
+    fn lookup(&self, key: &str) -> Result<Lookup> {
+        let index = self.read_index();
+        if let Some(log_section) = &index.get(key, &|section| self.read_key(section))? {
+            let (gen, offset) = (log_section.gen, log_section.start);
+            if log_section.is_expired(now_millis()) {
+                return Ok(Lookup::Expired { gen, offset });
+            }
+            let (value, seq) = self.config.read_value_with_seq(&self.reader, log_section)?;
+            return Ok(Lookup::Value(value, seq));
+        }
+        Ok(Lookup::Missing)
+    }
+
+    /// Reads and decodes the value stored in a section
+    fn read_value(&self, section: &LogSection) -> Result<String> {
+        self.config.read_value(&self.reader, section)
+    }
+
+    /// Gets the values for many keys, in the same order as the keys
+    ///
+    /// Records are read in log order rather than key order, so each generation is
+    /// scanned forwards instead of seeking back and forth. Missing keys give `None`.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        let mut expired = Vec::new();
+        {
+            let index = self.read_index();
+            let now = now_millis();
+            let mut sections: Vec<(usize, LogSection)> = Vec::with_capacity(keys.len());
+            for (slot, key) in keys.iter().enumerate() {
+                match index.get(key, &|section| self.read_key(section))? {
+                    Some(section) if section.is_expired(now) => expired.push((slot, section)),
+                    Some(section) => sections.push((slot, section)),
+                    None => {}
+                }
+            }
+            sections.sort_by_key(|(_, section)| (section.gen, section.start));
+            for (slot, section) in sections {
+                values[slot] = Some(self.read_value(&section)?);
+            }
+        }
+
+        if !expired.is_empty() {
+            let mut writer = self.writer.lock().unwrap();
+            for (slot, section) in expired {
+                self.expire(&mut writer, &keys[slot], section.gen, section.start)?;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Drops an expired key from the index, counting its record towards the uncompacted bytes
+    ///
+    /// Nothing is dropped if the key was rewritten since it was found to be expired.
+    fn expire(&self, writer: &mut LogWriter, key: &str, gen: u64, offset: u64) -> Result<()> {
+        let resolve = |section: &LogSection| self.read_key(section);
+        let mut index = self.index.write().unwrap();
+        if let Some(section) = index.get(key, &resolve)? {
+            if section.gen == gen && section.start == offset {
+                writer.uncompacted += index.remove(key, &resolve)?.unwrap_or(0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns where the live record for a key is stored, `None` if the key does not exist
+    pub fn log_section(&self, key: &str) -> Result<Option<LogSection>> {
+        self.index.read().unwrap().get(key, &|section| self.read_key(section))
+    }
+
+    /// Returns whether a key is live, using only the in-memory index
+    ///
+    /// Removed and expired keys are not live. A hashed index reads the key back to confirm it.
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        match self.log_section(key)? {
+            Some(section) => Ok(!section.is_expired(now_millis())),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns every live key in sorted order, without reading any values
+    ///
+    /// Removed and expired keys are left out. A hashed index reads every key back from the logs.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        self.live_keys(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Returns every live key starting with the given prefix in sorted order, without reading any values
+    pub(crate) fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        let end = prefix_successor(prefix);
+        let end = match &end {
+            Some(end) => Bound::Excluded(end.as_str()),
+            None => Bound::Unbounded,
+        };
+        self.live_keys(Bound::Included(prefix), end)
+    }
+
+    /// Returns every live key between the bounds in sorted order
+    fn live_keys(&self, start: Bound<&str>, end: Bound<&str>) -> Result<Vec<String>> {
+        let now = now_millis();
+        Ok(self.index
+            .read()
+            .unwrap()
+            .range(start, end, &|section| self.read_key(section))?
+            .into_iter()
+            .filter(|(_, section)| !section.is_expired(now))
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    /// Returns a handle whose keys are kept apart from every other namespace in this store
+    ///
+    /// See `Namespace` for how namespaced keys are stored.
+    pub fn namespace(&self, name: &str) -> Result<Namespace> {
+        Namespace::new(self.clone(), name)
+    }
+
+    /// Returns the number of live keys, without reading any values
+    ///
+    /// Removed and expired keys are not counted.
+    pub fn len(&self) -> usize {
+        let now = now_millis();
+        self.index
+            .read()
+            .unwrap()
+            .sections()
+            .filter(|section| !section.is_expired(now))
+            .count()
+    }
+
+    /// Returns whether the store holds no live keys
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets every key/value pair with a key between the given bounds, in key order
+    ///
+    /// Bounds that describe an empty range, such as a start after the end, return nothing.
+    pub fn range(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        let is_empty = match (&start, &end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+            _ => false,
+        };
+        if is_empty {
+            return Ok(Vec::new());
+        }
+
+        let keys: Vec<String> = self.index
+            .read()
+            .unwrap()
+            .range(bound_as_str(&start), bound_as_str(&end), &|section| self.read_key(section))?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Gets every key/value pair whose key starts with the given prefix, in key order
+    ///
+    /// Only the matching band of the index is visited. An empty prefix returns everything.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let end = match prefix_successor(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.range(Bound::Included(prefix.to_owned()), end)
+    }
+
+    /// Yields every live key/value pair in key order, reading each value only when it is reached
+    ///
+    /// Only the keys are collected up front, so a store bigger than memory can be walked one
+    /// value at a time. Keys removed or expired after the iterator was made are skipped, and
+    /// values are read as they are when reached.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let (keys, failed) = match self.keys() {
+            Ok(keys) => (keys, None),
+            Err(err) => (Vec::new(), Some(err)),
+        };
+        failed.into_iter().map(Err).chain(keys.into_iter().filter_map(move |key| {
+            match self.get(key.clone()) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            }
+        }))
+    }
+
+    /// Gets the values of every key starting with the given prefix, in key order
+    ///
+    /// The index is walked directly and only the values are read, so no key is copied out of it.
+    /// Expired keys are skipped.
+    pub fn values_with_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        let end = prefix_successor(prefix);
+        let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        let index = self.read_index();
+        let now = now_millis();
+        index
+            .range_sections(Bound::Included(prefix), end, &|section| self.read_key(section))?
+            .iter()
+            .filter(|section| !section.is_expired(now))
+            .map(|section| self.read_value(section))
+            .collect()
+    }
+
+    /// Removes the given key.
+    pub fn remove(&self, key: String) -> Result<()> {
+        debug!(key = %key, "remove");
+        let mut writer = self.writer.lock().unwrap();
+        warn_if_failed("remove", self.remove_locked(&mut writer, key))
+    }
+
+    /// Removes every key that exists with a single flush, returning which of them did
+    ///
+    /// The flags are in the same order as the keys. A key listed twice only counts as
+    /// removed the first time. As with `set_batch`, a failed write leaves every key in place.
+    pub fn remove_batch(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.log()?;
+        let resolve = |section: &LogSection| self.read_key(section);
+        let now = now_millis();
+        let mut existed = Vec::with_capacity(keys.len());
+        let mut removing = HashSet::new();
+        {
+            let index = self.index.read().unwrap();
+            for key in &keys {
+                let live = !removing.contains(key.as_str())
+                    && index.get(key, &resolve)?.map_or(false, |section| !section.is_expired(now));
+                if live {
+                    removing.insert(key.as_str());
+                }
+                existed.push(live);
+            }
+        }
+        if removing.is_empty() {
+            return Ok(existed);
+        }
+
+        let removed = keys.into_iter().zip(&existed).filter(|(_, live)| **live).map(|(key, _)| key);
+        let records = writer.write_and_commit(|writer| {
+            let mut records = Vec::new();
+            for key in removed {
+                let start = writer.log()?.pos;
+                let command = Command::Remove { key: key.clone(), seq: writer.next_seq() };
+                write_record(writer.log()?, &command, &self.config.format)?;
+                records.push((key, writer.log()?.pos - start));
+            }
+            Ok(records)
+        })?;
+        for (key, record_length) in records {
+            if let Some(dead) = self.index.write().unwrap().remove(&key, &resolve)? {
+                writer.notify(ChangeEvent::Remove { key });
+                writer.uncompacted += dead + record_length;
+            }
+        }
+
+        self.after_write(&mut writer)?;
+
+        Ok(existed)
+    }
+
+    /// Removes a key only if its current value is `expected`, returning whether it was removed
+    ///
+    /// The check and the removal happen under the write lock, like `compare_and_swap`.
+    pub fn remove_if(&self, key: String, expected: String) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        if self.get_locked(&mut writer, &key)? != Some(expected) {
+            return Ok(false);
+        }
+        self.remove_locked(&mut writer, key)?;
+        Ok(true)
+    }
+
+    fn remove_locked(&self, writer: &mut LogWriter, key: String) -> Result<()> {
+        writer.log()?;
+        let resolve = |section: &LogSection| self.read_key(section);
+        // Only the index entry is needed, and expired keys are already absent, so removing one is a miss
+        let live = self.index.read().unwrap().get(&key, &resolve)?
+            .map_or(false, |section| !section.is_expired(now_millis()));
+        if !live {
+            return Err(KvsError::KeyNotFound { key });
+        }
+        // The index is only touched once the record is committed, so a failed write leaves the key in place
+        let record_length = writer.write_and_commit(|writer| {
+            let command = Command::Remove { key: key.clone(), seq: writer.next_seq() };
+            let start = writer.log()?.pos;
+            write_record(writer.log()?, &command, &self.config.format)?;
+            Ok(writer.log()?.pos - start)
+        })?;
+        let removed = self.index.write().unwrap().remove(&key, &resolve)?;
+        if let Some(dead) = removed {
+            writer.notify(ChangeEvent::Remove { key });
+            // Compaction drops the remove record as well as the values it hides, as replay counts them
+            writer.uncompacted += dead + record_length;
+
+            self.after_write(writer)?;
+
+            return Ok(())
+        }
+        Err(KvsError::KeyNotFound { key })
+    }
+
+    /// Opens a KV Store from disk
+    ///
+    /// When the directory holds several logs that together are smaller than the compaction
+    /// threshold, they are compacted into one before the store is returned.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::builder().open(path)
+    }
+
+    /// Opens an existing store for reading only
+    ///
+    /// See `KvStoreBuilder::read_only`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::builder().read_only().open(path)
+    }
+
+    /// Returns a builder for opening a store with non-default settings
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::new()
+    }
+
+    /// Opens a KV Store from disk, checking the integrity of every log while the index is rebuilt
+    ///
+    /// Corrupt records are skipped and listed in the returned `RecoveryReport` instead of failing the open.
+    pub fn open_with_report(path: impl Into<PathBuf>) -> Result<(KvStore, RecoveryReport)> {
+        let mut report = RecoveryReport::default();
+        let store = KvStore::open_inner(path.into(), KvStoreBuilder::new(), Some(&mut report))?;
+        Ok((store, report))
+    }
+
+    /// Checks every generation log in a store directory and trims whatever cannot be trusted
+    ///
+    /// Each log is scanned record by record without building an index, so this works on stores
+    /// that no longer open. The first record in a log that fails to frame, checksum or decode
+    /// is cut off along with everything after it, since later records cannot be framed reliably.
+    /// Streamed values are read through to check them against their checksum.
+    /// Logs whose header is not understood are left alone and fail the repair.
+    ///
+    /// Returns `KvsError::Locked` if the store is open anywhere. Stores with a custom record
+    /// separator or record encryption have to be repaired through `KvStoreBuilder::repair`.
+    pub fn repair(path: impl Into<PathBuf>) -> Result<RepairReport> {
+        KvStore::builder().repair(path)
+    }
+
+    pub(crate) fn repair_inner(path: PathBuf, builder: KvStoreBuilder) -> Result<RepairReport> {
+        let format = RecordFormat::new(builder.separator, builder.record_seal);
+        let buffer_capacity = builder.buffer_capacity.unwrap_or(DEFAULT_BUFFER_CAPACITY);
+        let _lock = lock_store(&path, true)?;
+        let mut report = RepairReport::default();
+        for gen in sorted_log_generations(&path)? {
+            let log_file = log_file_path(&path, gen);
+            let mut reader = create_reader_with_capacity(&log_file, buffer_capacity)?;
+            if read_log_header(&mut reader, gen)?.is_none() {
+                continue;
+            }
+            let mut repaired = GenerationRepair { gen, kept: 0, dropped: 0, trimmed_bytes: 0 };
+            let mut cut_at = None;
+            scan_generation(&mut reader, &format, gen, |pos, scanned, _| match scanned {
+                _ if cut_at.is_some() => repaired.dropped += 1,
+                Scanned::Valid => repaired.kept += 1,
+                Scanned::Damaged { .. } => {
+                    cut_at = Some(pos);
+                    repaired.dropped += 1;
+                }
+            })?;
+            if let Some(cut_at) = cut_at {
+                repaired.trimmed_bytes = reader.pos - cut_at;
+                let file = OpenOptions::new().write(true).open(&log_file)?;
+                file.set_len(cut_at)?;
+                file.sync_all()?;
+            }
+            report.generations.push(repaired);
+        }
+        Ok(report)
+    }
+
+    /// Checks that a store directory would open, without changing anything in it other than a missing lock file
+    ///
+    /// Every generation log is scanned like `repair` does, checking each record's frame,
+    /// checksum and encoding and reading streamed values through to their checksum, but
+    /// nothing is trimmed. Problems an open would tidy away by itself, such as a torn last
+    /// record, are reported as warnings rather than corruption.
+    ///
+    /// Takes the store lock shared, so this fails with `KvsError::Locked` while a writer has
+    /// the store open. Stores with a custom record separator or record encryption have to be
+    /// checked through `KvStoreBuilder::validate`.
+    pub fn validate(path: impl Into<PathBuf>) -> Result<ValidateReport> {
+        KvStore::builder().validate(path)
+    }
+
+    pub(crate) fn validate_inner(path: PathBuf, builder: KvStoreBuilder) -> Result<ValidateReport> {
+        let format = RecordFormat::new(builder.separator, builder.record_seal);
+        let buffer_capacity = builder.buffer_capacity.unwrap_or(DEFAULT_BUFFER_CAPACITY);
+        let _lock = lock_store(&path, false)?;
+        let mut report = ValidateReport::default();
+        if !path.join(META_FILE).is_file() {
+            report.warnings.push(format!("no {} file, opening the store writable would create one", META_FILE));
+        }
+        for gen in sorted_log_generations(&path)? {
+            let mut reader = create_reader_with_capacity(&log_file_path(&path, gen), buffer_capacity)?;
+            report.generations += 1;
+            if read_log_header(&mut reader, gen)?.is_none() {
+                report.warnings.push(format!("generation {} is empty, opening the store writable would remove it", gen));
+                continue;
+            }
+            scan_generation(&mut reader, &format, gen, |pos, scanned, end| match scanned {
+                Scanned::Valid => report.records += 1,
+                // What a crash part way through a write leaves, which opening cuts off
+                Scanned::Damaged { torn: true } => report.warnings.push(format!(
+                    "generation {} ends in a torn record at offset {}, opening the store writable would cut it off",
+                    gen, pos
+                )),
+                Scanned::Damaged { torn: false } => report.corruptions.push(Corruption { gen, offset: pos, length: end - pos }),
+            })?;
+        }
+        Ok(report)
+    }
+
+    pub(crate) fn open_inner(path: PathBuf, builder: KvStoreBuilder, mut report: Option<&mut RecoveryReport>) -> Result<KvStore> {
+        let format = RecordFormat::new(builder.separator, builder.record_seal);
+        let buffer_capacity = builder.buffer_capacity.unwrap_or(DEFAULT_BUFFER_CAPACITY);
+        let read_only = builder.read_only;
+        let (meta, lock) = if read_only {
+            let lock = lock_store(&path, false)?;
+            (StoreMeta::load(&path)?, lock)
+        } else {
+            fs::create_dir_all(&path)
+                .map_err(|cause| KvsError::CreateDir { path: path.clone(), cause })?;
+            let lock = lock_store(&path, true)?;
+            (StoreMeta::load_or_create(&path)?, lock)
+        };
+        let mut generations = sorted_log_generations(&path)?;
+        // Logs that never got their header written hold nothing, so they are just clutter
+        for &gen in &generations {
+            let log_file = log_file_path(&path, gen);
+            if !read_only && fs::metadata(&log_file)?.len() == 0 {
+                fs::remove_file(log_file)?;
+            }
+        }
+        generations.retain(|&gen| fs::metadata(log_file_path(&path, gen)).map_or(false, |meta| meta.len() > 0));
+
+        let mut index = KeyIndex::new(builder.key_hasher, builder.keep_versions.unwrap_or(1));
+        let reader = LogReader::new(path.clone(), builder.mmap_reads);
+        let resolve = |section: &LogSection| read_record_key(&reader, section, &format);
+        let mut uncompacted= 0;
+        let mut newest_version = None;
+        let mut seq = meta.seq;
+        let mut replayed_records = 0;
+        // A checkpoint stands in for every record before the point it was taken at, unless each one is to be checked
+        let mut resume_at = None;
+        if let Some(checkpoint) = report.is_none().then(|| Checkpoint::load(&path, &format, meta.id, &generations)).flatten() {
+            seq = seq.max(checkpoint.seq);
+            resume_at = checkpoint.logs.last().copied();
+            uncompacted = checkpoint.restore(&mut index, &resolve)?;
+        }
+        for &gen in &generations {
+            let from = match resume_at {
+                Some((resume_gen, _)) if gen < resume_gen => continue,
+                Some((resume_gen, offset)) if gen == resume_gen => offset,
+                _ => 0,
+            };
+            let old_log_file = log_file_path(&path, gen);
+            let mut old_gen_reader = create_reader_with_capacity(&old_log_file, buffer_capacity)?;
+            let replayed = replay(&mut index, &mut old_gen_reader, gen, from, &format, &resolve, report.as_deref_mut())?;
+            match replayed.torn_at {
+                // Cut the torn record off so new records never follow a partial one
+                Some(torn_at) if !read_only => {
+                    warn!(gen, torn_at, "cutting a torn record off the log");
+                    OpenOptions::new().write(true).open(&old_log_file)?.set_len(torn_at)?;
+                }
+                _ => {}
+            }
+            newest_version = replayed.version;
+            seq = seq.max(replayed.seq);
+            replayed_records += replayed.records;
+            debug!(gen, uncompacted = replayed.uncompacted, "replayed generation");
+            uncompacted += replayed.uncompacted;
+        }
+
+        // Keep appending to the newest log until it grows past the compaction threshold or the size limit.
+        // A log from an older format is left alone so it never holds records its header cannot describe.
+        let compaction_threshold = builder.compaction_threshold.unwrap_or(DEFAULT_COMPACTION_THRESHOLD);
+        let reuse_below = builder.max_log_size.map_or(compaction_threshold, |max| max.min(compaction_threshold));
+        let current_gen = match generations.last() {
+            Some(&gen) if newest_version == Some(LOG_FORMAT_VERSION)
+                && fs::metadata(log_file_path(&path, gen))?.len() < reuse_below => gen,
+            last => last.unwrap_or(&0) + 1,
+        };
+        // Filters saved when each generation was sealed stand in for ones built from the keys put in above
+        for &gen in generations.iter().filter(|&&gen| gen != current_gen) {
+            match load_filter(&path, meta.id, gen) {
+                Some(filter) => index.load_filter(gen, filter),
+                None => {
+                    index.seal(gen, Vec::new());
+                    if !read_only {
+                        let _ = error_if_failed("save filter", save_filter(&path, meta.id, gen, &index));
+                    }
+                }
+            }
+        }
+        reader.set_active_gen(current_gen);
+        let log = match read_only {
+            true => None,
+            false => Some(create_writer_with_capacity(&log_file_path(&path, current_gen), buffer_capacity)?),
+        };
+
+        let store = KvStore {
+            config: Arc::new(StoreConfig {
+                id: meta.id,
+                path,
+                value_hooks: builder.value_hooks,
+                dedup_min_size: builder.dedup_min_size,
+                compress_min_size: builder.compress_min_size,
+                max_log_size: builder.max_log_size,
+                compaction_threshold,
+                compaction_strategy: builder.compaction_strategy.unwrap_or_default(),
+                format,
+                buffer_capacity,
+                max_key_size: builder.max_key_size.unwrap_or(DEFAULT_MAX_KEY_SIZE),
+                max_value_size: builder.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE),
+                snapshots: AtomicUsize::new(0),
+                replayed_records,
+            }),
+            index: Arc::new(RwLock::new(index)),
+            writer: Arc::new(Mutex::new(LogWriter {
+                gen: current_gen,
+                log,
+                uncompacted,
+                large_value_hook: None,
+                subscribers: Vec::new(),
+                sync_mode: builder.sync_mode.unwrap_or_default(),
+                last_sync: Instant::now(),
+                seq,
+                reader_epoch: Arc::clone(&reader.epoch),
+                metrics: Metrics::default(),
+                _lock: lock,
+            })),
+            reader,
+            _background: Vec::new(),
+        };
+        // Every log costs a reader and a replay on each open, so logs that would fit in one are merged
+        if !read_only && generations.len() > 1 && store.disk_usage()? < reuse_below {
+            store.compact_locked(&mut store.writer.lock().unwrap())?;
+        }
+        info!(path = ?store.config.path, generations = generations.len(), keys = store.len(), read_only, "opened store");
+        let mut background = Vec::new();
+        if let Some(interval) = builder.sweep_interval {
+            background.push(Arc::new(Periodic::start(&store, "kvs-sweeper", interval, |store| {
+                sweep_expired(&store.index, &store.writer)
+            })?));
+        }
+        if let (Some(interval), false) = (builder.checkpoint_interval, read_only) {
+            background.push(Arc::new(Periodic::start(&store, "kvs-checkpointer", interval, |store| {
+                let _ = error_if_failed("checkpoint", store.checkpoint());
+            })?));
+        }
+        let store = KvStore { _background: background, ..store };
+
+        Ok(store)
+    }
+    /// Flushes and fsyncs the current generation, consuming this handle, then releases the
+    /// store lock if this was the last clone
+    ///
+    /// Other clones stay usable, and the store stays locked until the last of them is closed
+    /// or dropped. Dropping the last clone also releases the lock, but only `close` reports an
+    /// error from the fsync or the unlock.
+    pub fn close(self) -> Result<()> {
+        self.flush()?;
+        let KvStore { writer, _background, .. } = self;
+        // Background threads only hold the writer while a task runs, and stopping them waits for it
+        drop(_background);
+        if let Ok(writer) = Arc::try_unwrap(writer) {
+            let writer = writer.into_inner().unwrap();
+            FileExt::unlock(&writer._lock)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes and fsyncs the current generation, whatever the sync mode
+    pub fn flush(&self) -> Result<()> {
+        if let Some(log) = &mut self.writer.lock().unwrap().log {
+            log.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Returns a view of this store whose keys and values are any serde types
+    ///
+    /// See `TypedKvStore` for how typed entries are stored.
+    pub fn typed<K, V>(&self) -> TypedKvStore<K, V>
+    where
+        K: Serialize + DeserializeOwned,
+        V: Serialize + DeserializeOwned,
+    {
+        TypedKvStore::new(self.clone())
+    }
+
+    /// Returns the unique id generated when this store was first opened
+    pub fn store_id(&self) -> Uuid {
+        self.config.id
+    }
+
+    /// Registers a hook that is called whenever a value larger than `threshold` bytes is set
+    ///
+    /// The hook receives the key and the value size and returns whether the write should proceed.
+    /// The hook is shared by every clone of the store.
+    pub fn on_large_value<F>(&self, threshold: usize, hook: F)
+    where
+        F: Fn(&str, usize) -> bool + Send + Sync + 'static,
+    {
+        self.writer.lock().unwrap().large_value_hook = Some((threshold, Box::new(hook)));
+    }
+
+    /// Returns a receiver for every later set and remove, from any clone of the store
+    ///
+    /// An event is sent once its record is committed and the index points at it, so a
+    /// subscriber that reads the key back sees the new value or something later. Batches,
+    /// swaps and other compound writes send one event per key. A value streamed in with
+    /// `set_reader` is only announced by its length. Expiry and `clear` send none.
+    /// Dropping the receiver unsubscribes it on the next write.
+    pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.writer.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    /// Starts a transaction that stages sets in memory until it is committed
+    ///
+    /// See `Transaction` for savepoints.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction { store: self, staged: Vec::new() }
+    }
+
+    /// Returns a read-only view of the store as it is now, unaffected by later writes
+    ///
+    /// Taking a snapshot copies the index and opens every generation log. See `Snapshot`
+    /// for how it stays consistent.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        // Logs are only removed under the index write lock, so none go missing before they are opened
+        let index = self.index.read().unwrap();
+        let reader = self.reader.pinned(&sorted_log_generations(&self.config.path)?)?;
+        Ok(Snapshot {
+            config: Arc::clone(&self.config),
+            index: index.clone(),
+            reader,
+            taken_at: now_millis(),
+            _blobs: BlobPin::new(&self.config),
+        })
+    }
+
+    /// Rewrites every live record densely into a fresh generation and removes the old logs
+    ///
+    /// Returns the number of bytes saved on disk.
+    pub fn defragment(&self) -> Result<u64> {
+        let mut writer = self.writer.lock().unwrap();
+        let size_before = self.disk_usage()?;
+        self.compact_locked(&mut writer)?;
+        Ok(size_before.saturating_sub(self.disk_usage()?))
+    }
+
+    /// Records the last sequence number handed out in META, ahead of deleting logs that may hold the record carrying it
+    fn save_seq(&self, writer: &LogWriter) -> Result<()> {
+        StoreMeta { id: self.config.id, seq: writer.seq }.save(&self.config.path)
+    }
+
+    /// Copies every live record into a fresh generation, then removes the stale logs and their readers
+    ///
+    /// Keys whose last record is a remove are not copied forward.
+    /// Writes continue in a brand new generation after the compacted one.
+    pub fn compact(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.compact_locked(&mut writer)
+    }
+
+    fn compact_locked(&self, writer: &mut LogWriter) -> Result<()> {
+        let started = Instant::now();
+        let bytes_before = self.disk_usage().unwrap_or(0);
+        error_if_failed("compact", self.compact_logs(writer))?;
+        let bytes_after = self.disk_usage().unwrap_or(0);
+        writer.metrics.add_compaction(started, bytes_before, bytes_after);
+        info!(before = bytes_before, after = bytes_after, "compacted");
+        Ok(())
+    }
+
+    /// Rewrites the live records into a new generation and deletes every older log
+    fn compact_logs(&self, writer: &mut LogWriter) -> Result<()> {
+        writer.log()?;
+        let path = &self.config.path;
+        let mut index = self.index.write().unwrap();
+
+        // (a) create writer for current_gen + 1
+        let compaction_gen = writer.gen + 1;
+        let compaction_log_file = log_file_path(path, compaction_gen);
+        let mut compaction_writer = create_writer_with_capacity(&compaction_log_file, self.config.buffer_capacity)?;
+
+        // (b) copy every live record to (a), leaving expired records behind
+        let mut live_blobs = HashSet::new();
+        let mut moved = HashMap::new();
+        let mut older = index.take_older_versions();
+        let copied = self.write_compacted_log(&index, &older, compaction_gen, &mut compaction_writer, &mut moved, &mut live_blobs);
+        let compacted_keys = match copied {
+            Ok(compacted_keys) => compacted_keys,
+            Err(err) => {
+                index.restore_older_versions(older);
+                // Nothing points into the partial log, and the next rotation would reuse its generation
+                drop(compaction_writer);
+                let _ = fs::remove_file(&compaction_log_file);
+                return Err(err);
+            }
+        };
+
+        // Only once the copy is on disk are sections pointed at it, dropping those left behind
+        let relocate = |section: &mut LogSection| match moved.get(&(section.gen, section.start)) {
+            Some(copy) => {
+                *section = *copy;
+                true
+            }
+            None => false,
+        };
+        for ring in older.values_mut() {
+            ring.retain_mut(relocate);
+        }
+        older.retain(|_, ring| !ring.is_empty());
+        index.restore_older_versions(older);
+        index.try_retain(|section| Ok(relocate(section)))?;
+        index.seal(compaction_gen, compacted_keys);
+        let _ = error_if_failed("save filter", save_filter(path, self.config.id, compaction_gen, &index));
+
+        // (c) move current_gen to + 2 so the compacted log stays dense
+        writer.gen = compaction_gen + 1;
+        writer.log = Some(create_writer_with_capacity(&log_file_path(path, writer.gen), self.config.buffer_capacity)?);
+        self.reader.set_active_gen(writer.gen);
+
+        // (d) delete files older than (a), telling every clone to drop its readers
+        self.save_seq(writer)?;
+        Checkpoint::remove(path)?;
+        for gen in sorted_log_generations(path)? {
+            if gen < compaction_gen {
+                remove_filter(path, gen)?;
+                fs::remove_file(log_file_path(path, gen))?;
+            }
+        }
+        index.drop_filters_before(compaction_gen);
+        self.reader.invalidate();
+        writer.uncompacted = 0;
+
+        // Blobs are only referenced from live records, anything else is garbage unless a snapshot still reads it
+        let blob_dir = path.join(BLOB_DIR);
+        if blob_dir.is_dir() && self.config.snapshots.load(Ordering::SeqCst) == 0 {
+            for entry in fs::read_dir(blob_dir)? {
+                let entry = entry?;
+                let is_live = entry.file_name().to_str().map_or(false, |hash| live_blobs.contains(hash));
+                if !is_live {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies every live record into the compaction log and syncs it, leaving the index untouched
+    ///
+    /// The older versions of live keys go first, so replaying the log rebuilds them in order.
+    /// Fills `moved` with the copy of each section by its old generation and offset, leaving out
+    /// expired records and the versions of keys that are gone, and `live_blobs` with every blob
+    /// still referenced. Returns the filter hash of every key copied.
+    fn write_compacted_log(
+        &self,
+        index: &KeyIndex,
+        older: &HashMap<String, VecDeque<LogSection>>,
+        compaction_gen: u64,
+        compaction_writer: &mut TrackingBufWriter<File>,
+        moved: &mut HashMap<(u64, u64), LogSection>,
+        live_blobs: &mut HashSet<String>,
+    ) -> Result<Vec<u64>> {
+        let now = now_millis();
+        let mut copy = |section: &LogSection, compaction_writer: &mut TrackingBufWriter<File>| -> Result<Option<Command>> {
+            let start = compaction_writer.pos;
+            let command = copy_record(&self.reader, section, &self.config.format, now, compaction_writer)?;
+            if let Some(command) = &command {
+                if let Command::SetRef { hash, .. } = command {
+                    live_blobs.insert(hash.clone());
+                }
+                moved.insert((section.gen, section.start), LogSection::from((compaction_gen, start, compaction_writer.pos))
+                    .expiring(section.expires_at)
+                    .with_payload(section.payload));
+            }
+            Ok(command)
+        };
+        for (key, ring) in older {
+            match index.get(key, &|section| self.read_key(section))? {
+                Some(section) if !section.is_expired(now) => {}
+                _ => continue,
+            }
+            for section in ring.iter().rev() {
+                copy(section, compaction_writer)?;
+            }
+        }
+        let mut compacted_keys = Vec::new();
+        for section in index.sections() {
+            if let Some(command) = copy(section, compaction_writer)? {
+                compacted_keys.push(KeyFilter::hash(command.key()));
+            }
+        }
+        // The compacted log has to be on disk before the logs it replaces are deleted
+        compaction_writer.sync_all()?;
+        Ok(compacted_keys)
+    }
+
+    /// Merges the oldest run of neighbouring sealed generations that share a size tier, if one has filled up
+    fn merge_tier(&self, writer: &mut LogWriter) -> Result<()> {
+        let path = &self.config.path;
+        let base = self.config.max_log_size.unwrap_or(self.config.compaction_threshold);
+        let mut sealed = Vec::new();
+        for gen in sorted_log_generations(path)? {
+            if gen < writer.gen {
+                sealed.push((gen, size_tier(fs::metadata(log_file_path(path, gen))?.len(), base)));
+            }
+        }
+        // Only neighbours are merged, so the merged log replays in the place of the logs it replaces
+        let run: Vec<u64> = match sealed.windows(TIER_FANOUT).find(|run| run.iter().all(|&(_, tier)| tier == run[0].1)) {
+            Some(run) => run.iter().map(|&(gen, _)| gen).collect(),
+            None => return Ok(()),
+        };
+        let started = Instant::now();
+        let bytes_before = self.disk_usage().unwrap_or(0);
+        self.merge_generations(writer, &run, run[0] == sealed[0].0)?;
+        let bytes_after = self.disk_usage().unwrap_or(0);
+        writer.metrics.add_compaction(started, bytes_before, bytes_after);
+        info!(generations = ?run, before = bytes_before, after = bytes_after, "merged");
+        Ok(())
+    }
+
+    /// Rewrites a run of neighbouring sealed generations into the newest of them and deletes the rest
+    ///
+    /// The merged log holds any older versions kept from the run followed by the last record of
+    /// every key written in it, removes included, so replaying it among the other logs rebuilds
+    /// the same index. Removes and expired sets are only dropped when the run is the `oldest`
+    /// there is, leaving nothing for them to hide. Only sections in the run move in the index.
+    fn merge_generations(&self, writer: &mut LogWriter, run: &[u64], oldest: bool) -> Result<()> {
+        let path = &self.config.path;
+        let target = run[run.len() - 1];
+        let mut index = self.index.write().unwrap();
+
+        // (a) find the last record of each key written in the run
+        let now = now_millis();
+        let mut run_bytes = 0;
+        let mut last = HashMap::new();
+        for &gen in run {
+            run_bytes += fs::metadata(log_file_path(path, gen))?.len();
+            self.scan_last_records(gen, now, &mut last)?;
+        }
+        let mut last: Vec<(String, LogSection, bool)> =
+            last.into_iter().map(|(key, (section, dead))| (key, section, dead)).collect();
+        last.sort_unstable_by_key(|(_, section, _)| (section.gen, section.start));
+
+        // (b) write them into a log that replaces the newest one of the run
+        self.save_seq(writer)?;
+        let mut older = index.take_older_versions();
+        let mut moved = HashMap::new();
+        let mut hashes = Vec::new();
+        let merged = self.write_merged_log(&older, &last, run, oldest, &mut moved, &mut hashes);
+
+        // (c) point sections in the run at their copies, dropping those left behind
+        let relocate = |section: &mut LogSection| {
+            if !run.contains(&section.gen) {
+                return true;
+            }
+            match moved.get(&(section.gen, section.start)) {
+                Some(copy) => {
+                    *section = *copy;
+                    true
+                }
+                None => false,
+            }
+        };
+        if merged.is_ok() {
+            for ring in older.values_mut() {
+                ring.retain_mut(relocate);
+            }
+            older.retain(|_, ring| !ring.is_empty());
+        }
+        index.restore_older_versions(older);
+        let merged_bytes = merged?;
+        index.try_retain(|section| Ok(relocate(section)))?;
+        index.seal(target, hashes);
+        let _ = error_if_failed("save filter", save_filter(path, self.config.id, target, &index));
+        for &gen in &run[..run.len() - 1] {
+            index.drop_filter(gen);
+        }
+        self.reader.invalidate();
+
+        // (d) delete the rest of the run oldest first, so a crash part way leaves logs that replay the same
+        for &gen in &run[..run.len() - 1] {
+            remove_filter(path, gen)?;
+            fs::remove_file(log_file_path(path, gen))?;
+        }
+        writer.uncompacted = writer.uncompacted.saturating_sub(run_bytes.saturating_sub(merged_bytes));
+        Ok(())
+    }
+
+    /// Notes the section of each key's last record in a generation, and whether that record leaves it unset by `now`
+    fn scan_last_records(&self, gen: u64, now: u64, last: &mut HashMap<String, (LogSection, bool)>) -> Result<()> {
+        let format = &self.config.format;
+        let mut reader = create_reader_with_capacity(&log_file_path(&self.config.path, gen), self.config.buffer_capacity)?;
+        read_log_header(&mut reader, gen)?;
+        let mut record = Vec::new();
+        let mut pos = reader.pos;
+        while reader.read_record(&mut record, &format.separator)? > 0 {
+            let mut parsed = parse_index_entry(&record, format, gen, pos);
+            let frame_end = reader.pos;
+            if let Ok(IndexEntry::Set { streamed: Some(len), .. }) = &parsed {
+                if !reader.skip(len + PAYLOAD_TRAILER_LEN)? {
+                    parsed = Err(KvsError::Corrupt { gen, offset: pos });
+                }
+            }
+            // Replay stopped at an unreadable last record too, so nothing after it is indexed
+            let entry = match parsed {
+                Err(_) if reader.at_end()? => break,
+                parsed => parsed?,
+            };
+            let section = LogSection::new(gen, pos, reader.pos).with_payload(reader.pos - frame_end);
+            let (key, section, dead) = match entry {
+                IndexEntry::Set { key, expires_at, .. } => {
+                    (key, section.expiring(expires_at), expires_at.map_or(false, |at| at <= now))
+                }
+                IndexEntry::Remove { key, .. } => (key, section, true),
+            };
+            last.insert(key, (section, dead));
+            pos = reader.pos;
+            record.clear();
+        }
+        Ok(())
+    }
+
+    /// Copies a run's older versions and last records into a new log, then renames it over the newest log of the run
+    ///
+    /// Fills `moved` with the copy of each section by its old generation and offset, and `hashes`
+    /// with the filter hash of every key copied. Returns the length of the merged log.
+    fn write_merged_log(
+        &self,
+        older: &HashMap<String, VecDeque<LogSection>>,
+        last: &[(String, LogSection, bool)],
+        run: &[u64],
+        oldest: bool,
+        moved: &mut HashMap<(u64, u64), LogSection>,
+        hashes: &mut Vec<u64>,
+    ) -> Result<u64> {
+        let target = run[run.len() - 1];
+        let merge_file = self.config.path.join(format!("{}.log.merging", target));
+        // Left behind by a merge that was cut short
+        match fs::remove_file(&merge_file) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        let mut merge_writer = create_writer_with_capacity(&merge_file, self.config.buffer_capacity)?;
+
+        // Until the rest of the run is deleted a crash can leave a value behind in it, so a removal
+        // in the newest log stays. Keys whose removal goes take their older versions with them.
+        let dropped: HashSet<&str> = last
+            .iter()
+            .filter(|(_, section, dead)| *dead && oldest && section.gen != target)
+            .map(|(key, _, _)| key.as_str())
+            .collect();
+        let older = older
+            .iter()
+            .filter(|(key, _)| !dropped.contains(key.as_str()))
+            .flat_map(|(key, ring)| ring.iter().rev().map(move |section| (key, section)))
+            .filter(|(_, section)| run.contains(&section.gen));
+        let last = last
+            .iter()
+            .filter(|(key, _, _)| !dropped.contains(key.as_str()))
+            .map(|(key, section, _)| (key, section));
+        for (key, section) in older.chain(last) {
+            // A key's last record in the run may be one of its older versions, copied already
+            if moved.contains_key(&(section.gen, section.start)) {
+                continue;
+            }
+            let start = merge_writer.pos;
+            copy_section(&self.reader, section, &mut merge_writer)?;
+            moved.insert((section.gen, section.start), LogSection::from((target, start, merge_writer.pos))
+                .expiring(section.expires_at)
+                .with_payload(section.payload));
+            hashes.push(KeyFilter::hash(key));
+        }
+        // The merged log has to be on disk before it replaces a log
+        merge_writer.sync_all()?;
+        Checkpoint::remove(&self.config.path)?;
+        fs::rename(&merge_file, log_file_path(&self.config.path, target))?;
+        Ok(merge_writer.pos)
+    }
+
+    /// Writes the live set into a new store directory, leaving this store untouched
+    ///
+    /// The destination keeps this store's id and must not already contain any logs.
+    pub fn compact_into(&self, dest: impl Into<PathBuf>) -> Result<CompactionStats> {
+        let dest = dest.into();
+        fs::create_dir_all(&dest)
+            .map_err(|cause| KvsError::CreateDir { path: dest.clone(), cause })?;
+        if !sorted_log_generations(&dest)?.is_empty() {
+            return Err(KvsError::DestinationNotEmpty { path: dest });
+        }
+
+        let store_writer = self.writer.lock().unwrap();
+        let index = self.index.read().unwrap();
+        let mut writer = create_writer_with_capacity(&log_file_path(&dest, 1), self.config.buffer_capacity)?;
+        let mut keys = 0;
+        let now = now_millis();
+        for section in index.sections() {
+            match copy_record(&self.reader, section, &self.config.format, now, &mut writer)? {
+                Some(Command::SetRef { hash, .. }) => {
+                    write_blob(&dest, &hash, &read_blob(&self.config.path, &hash)?)?;
+                }
+                Some(_) => {}
+                None => continue,
+            }
+            keys += 1;
+        }
+        writer.flush()?;
+        // Records dropped here may have held the newest sequence numbers
+        StoreMeta { id: self.config.id, seq: store_writer.seq }.save(&dest)?;
+
+        Ok(CompactionStats { keys, bytes: writer.pos })
+    }
+
+    /// Copies the logs into a new store directory as they are now, without stopping writes
+    ///
+    /// The writer is only held while the current log is flushed and every log is opened.
+    /// Writes made after that may or may not reach the copy, which opens to the state at the
+    /// moment the logs were opened. Compaction running meanwhile deletes nothing the copy still
+    /// needs. The destination keeps this store's id and must not already contain any logs.
+    pub fn backup(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)
+            .map_err(|cause| KvsError::CreateDir { path: dest.to_owned(), cause })?;
+        if !sorted_log_generations(dest)?.is_empty() {
+            return Err(KvsError::DestinationNotEmpty { path: dest.to_owned() });
+        }
+
+        let (logs, _blobs) = {
+            let mut writer = self.writer.lock().unwrap();
+            let active_end = match &mut writer.log {
+                Some(log) => {
+                    log.flush()?;
+                    Some(log.pos)
+                }
+                None => None,
+            };
+            let mut logs = Vec::new();
+            for gen in sorted_log_generations(&self.config.path)? {
+                let file = File::open(log_file_path(&self.config.path, gen))?;
+                let len = match active_end {
+                    Some(end) if gen == writer.gen => end,
+                    _ => file.metadata()?.len(),
+                };
+                logs.push((gen, file, len));
+            }
+            (logs, BlobPin::new(&self.config))
+        };
+
+        for (gen, file, len) in logs {
+            let mut copy = File::create(log_file_path(dest, gen))?;
+            io::copy(&mut file.take(len), &mut copy)?;
+            copy.sync_all()?;
+        }
+        // Blobs never change once written, and none are deleted while they are pinned
+        let blob_dir = self.config.path.join(BLOB_DIR);
+        if blob_dir.is_dir() {
+            fs::create_dir_all(dest.join(BLOB_DIR))?;
+            for entry in fs::read_dir(blob_dir)? {
+                let entry = entry?;
+                fs::copy(entry.path(), dest.join(BLOB_DIR).join(entry.file_name()))?;
+            }
+        }
+        fs::copy(self.config.path.join(META_FILE), dest.join(META_FILE))?;
+        Ok(())
+    }
+
+    /// Returns every command recorded for the given key across all generations, oldest first
+    ///
+    /// Compressed and streamed values are read in and come back as `Command::Set`.
+    pub fn key_history(&self, key: &str) -> Result<Vec<HistoryEntry>> {
+        let format = &self.config.format;
+        let _index = self.read_index();
+
+        let mut history = Vec::new();
+        for gen in sorted_log_generations(&self.config.path)? {
+            let log_file = log_file_path(&self.config.path, gen);
+            let mut reader = create_reader_with_capacity(&log_file, self.config.buffer_capacity)?;
+            read_log_header(&mut reader, gen)?;
+            let mut record = Vec::new();
+            let mut offset = reader.pos;
+            while reader.read_record(&mut record, &format.separator)? > 0 {
+                // Only the key is decoded until the record turns out to be one of its own
+                let (record_key, streamed) = match parse_index_entry(&record, format, gen, offset)? {
+                    IndexEntry::Set { key, streamed, .. } => (key, streamed),
+                    IndexEntry::Remove { key, .. } => (key, None),
+                };
+                if record_key != key {
+                    if let Some(len) = streamed {
+                        reader.skip(len + PAYLOAD_TRAILER_LEN)?;
+                    }
+                    offset = reader.pos;
+                    record.clear();
+                    continue;
+                }
+                let command = match (parse_record(&record, format, gen, offset)?, &self.config.value_hooks) {
+                    (Command::SetStream { key, len, expires_at, seq }, _) => {
+                        let mut value = Vec::with_capacity(len as usize);
+                        if !read_payload(&mut reader, len, &mut value)? {
+                            return Err(KvsError::CorruptRecord { gen, offset });
+                        }
+                        Command::Set { key, value: String::from_utf8(value)?, expires_at, seq }
+                    }
+                    (Command::Set { key, value, expires_at, seq }, Some(hooks)) => {
+                        Command::Set { key, value: hooks.decode(&value, gen, offset)?, expires_at, seq }
+                    }
+                    (Command::SetCompressed { key, value, expires_at, seq }, _) => {
+                        Command::Set { key, value: self.config.decode_compressed(&value, gen, offset)?, expires_at, seq }
+                    }
+                    (Command::SetBytes { key, value, expires_at, seq }, _) => {
+                        Command::Set { key, value: self.config.decode_bytes(value, gen, offset)?, expires_at, seq }
+                    }
+                    (command, _) => command,
+                };
+                history.push(HistoryEntry { gen, offset, command });
+                offset = reader.pos;
+                record.clear();
+            }
+        }
+        Ok(history)
+    }
+
+    /// Decodes the record at the given coordinates, such as those from `log_section` or a `Corruption`
+    ///
+    /// The command is returned as stored, so values are not passed back through any value
+    /// hooks, decompressed or fetched from the blob area. `length` may take in the value
+    /// streamed after a `Command::SetStream` record, which is left unread. Returns
+    /// `KvsError::ReaderNotFound` if the generation has no log and `KvsError::Corrupt` if the
+    /// coordinates fall outside the log or do not hold exactly one record.
+    pub fn read_record(&self, gen: u64, start: u64, length: u64) -> Result<Command> {
+        let _index = self.read_index();
+        let log_len = match fs::metadata(log_file_path(&self.config.path, gen)) {
+            Ok(meta) => meta.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(KvsError::ReaderNotFound { gen }),
+            Err(err) => return Err(err.into()),
+        };
+        let corrupt = || KvsError::Corrupt { gen, offset: start };
+        if length < RECORD_HEADER_LEN as u64 || start.checked_add(length).map_or(true, |end| end > log_len) {
+            return Err(corrupt());
+        }
+
+        let header = self.reader.read_at(gen, start, RECORD_HEADER_LEN as u64)?;
+        let body_len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let frame_len = RECORD_HEADER_LEN as u64 + body_len + self.config.format.separator.len() as u64;
+        if frame_len > length {
+            return Err(corrupt());
+        }
+        self.reader.record_reads.fetch_add(1, Ordering::Relaxed);
+        let command = parse_record(&self.reader.read_at(gen, start, frame_len)?, &self.config.format, gen, start)?;
+        let record_len = match command {
+            Command::SetStream { len, .. } => len.checked_add(frame_len + PAYLOAD_TRAILER_LEN),
+            _ => Some(frame_len),
+        };
+        if record_len != Some(length) {
+            return Err(corrupt());
+        }
+        Ok(command)
+    }
+
+    /// Collapses the store into a single generation numbered 1, with writes continuing in generation 2
+    ///
+    /// This compacts the store first, then renumbers the surviving generation.
+    pub fn reset_generation_counter(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.compact_locked(&mut writer)?;
+        let dense_gen = writer.gen - 1;
+        if dense_gen == 1 {
+            return Ok(());
+        }
+
+        let path = &self.config.path;
+        let mut index = self.index.write().unwrap();
+        Checkpoint::remove(path)?;
+        fs::rename(log_file_path(path, dense_gen), log_file_path(path, 1))?;
+        for section in index.sections_mut() {
+            section.gen = 1;
+        }
+        index.renumber_filter(dense_gen, 1);
+        remove_filter(path, dense_gen)?;
+        let _ = error_if_failed("save filter", save_filter(path, self.config.id, 1, &index));
+
+        // The current generation is empty straight after compacting, so it can simply be replaced
+        let empty_gen = writer.gen;
+        writer.gen = 2;
+        writer.log = Some(create_writer_with_capacity(&log_file_path(path, writer.gen), self.config.buffer_capacity)?);
+        fs::remove_file(log_file_path(path, empty_gen))?;
+        self.reader.set_active_gen(writer.gen);
+        self.reader.invalidate();
+        Ok(())
+    }
+
+    /// Reads every live record once so the first reads after it find the logs in the OS page cache
+    ///
+    /// Records are read in log order, a generation at a time, and records close enough
+    /// together are read as one run so the disk sees long sequential reads. Nothing is
+    /// decoded or changed, so it is safe at any time, though it only helps while the pages
+    /// stay cached.
+    pub fn warmup(&self) -> Result<()> {
+        let index = self.read_index();
+        let mut sections: Vec<&LogSection> = index.sections().collect();
+        sections.sort_by_key(|section| (section.gen, section.start));
+
+        // Gaps of less than a chunk are read through, as that costs less than a seek
+        let gap = STREAM_CHUNK_LEN as u64;
+        let mut run: Option<(u64, u64, u64)> = None;
+        for section in sections {
+            let end = section.start + section.length;
+            match &mut run {
+                Some((gen, _, run_end)) if *gen == section.gen && section.start <= *run_end + gap => {
+                    *run_end = (*run_end).max(end);
+                }
+                _ => {
+                    if let Some((gen, start, run_end)) = run.replace((section.gen, section.start, end)) {
+                        self.reader.copy_range(gen, start, run_end - start, &mut io::sink())?;
+                    }
+                }
+            }
+        }
+        if let Some((gen, start, end)) = run {
+            self.reader.copy_range(gen, start, end - start, &mut io::sink())?;
+        }
+        Ok(())
+    }
+
+    /// Writes every live key/value pair as one JSON object per line, in key order
+    pub fn export(&self, mut writer: impl Write) -> Result<()> {
+        let index = self.read_index();
+        let now = now_millis();
+        for (key, section) in index.range(Bound::Unbounded, Bound::Unbounded, &|section| self.read_key(section))? {
+            if section.is_expired(now) {
+                continue;
+            }
+            serde_json::to_writer(&mut writer, &ExportEntry { key, value: self.read_value(&section)? })?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Sets every pair from a stream written by `export`, skipping blank lines
+    pub fn import(&self, reader: impl Read) -> Result<()> {
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: ExportEntry = serde_json::from_str(&line)?;
+            self.set(entry.key, entry.value)?;
+        }
+        Ok(())
+    }
+
+    /// Removes every key, deleting all logs and blobs and starting again from generation 1
+    ///
+    /// The store id is kept.
+    pub fn clear(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.log()?;
+        let mut index = self.index.write().unwrap();
+        let path = &self.config.path;
+        index.clear();
+        self.save_seq(&writer)?;
+        Checkpoint::remove(path)?;
+        for gen in sorted_log_generations(path)? {
+            remove_filter(path, gen)?;
+            fs::remove_file(log_file_path(path, gen))?;
+        }
+        // With snapshots alive the blobs are left for a later compaction to collect
+        let blob_dir = path.join(BLOB_DIR);
+        if blob_dir.is_dir() && self.config.snapshots.load(Ordering::SeqCst) == 0 {
+            fs::remove_dir_all(blob_dir)?;
+        }
+        self.reader.invalidate();
+
+        writer.gen = 1;
+        writer.log = Some(create_writer_with_capacity(&log_file_path(path, writer.gen), self.config.buffer_capacity)?);
+        self.reader.set_active_gen(writer.gen);
+        writer.uncompacted = 0;
+        writer.commit()
+    }
+
+    /// Gets the value a key held at the end of the given generation
+    ///
+    /// Generations are immutable once sealed, so this is a point-in-time read of any sealed generation still on disk.
+    /// Returns `KvsError::VersionNotFound` for the generation still being written to, whose answer
+    /// would keep changing, and once the generation has been compacted away.
+    pub fn get_version(&self, key: &str, version: u64) -> Result<Option<String>> {
+        let active_gen = self.writer.lock().unwrap().gen;
+        if version >= active_gen || !sorted_log_generations(&self.config.path)?.contains(&version) {
+            return Err(KvsError::VersionNotFound { version });
+        }
+
+        let latest = self.key_history(key)?
+            .into_iter()
+            .rev()
+            .find(|entry| entry.gen <= version);
+        match latest {
+            Some(HistoryEntry { command: Command::Set { value, .. }, .. }) => Ok(Some(value)),
+            Some(HistoryEntry { gen, offset, command: Command::SetRef { hash, .. } }) => {
+                Ok(Some(self.config.read_blob_value(&hash, gen, offset)?))
+            }
+            Some(HistoryEntry { gen, offset, command: Command::SetCompressed { value, .. } }) => {
+                Ok(Some(self.config.decode_compressed(&value, gen, offset)?))
+            }
+            Some(HistoryEntry { command: Command::Remove { .. }, .. }) | None => Ok(None),
+            // `key_history` reads streamed and hooked values in as plain sets
+            Some(HistoryEntry { gen, offset, command: Command::SetStream { .. } | Command::SetBytes { .. } }) => {
+                Err(KvsError::Corrupt { gen, offset })
+            }
+        }
+    }
+
+    /// Returns up to `n` of the latest values of a key, newest first
+    ///
+    /// Values before the current one are only kept with `KvStoreBuilder::keep_versions`,
+    /// otherwise this returns at most the current value. A removed or expired key has no
+    /// values, and older values that have expired themselves are left out.
+    pub fn get_versions(&self, key: &str, n: usize) -> Result<Vec<String>> {
+        let index = self.read_index();
+        let now = now_millis();
+        let latest = match index.get(key, &|section| self.read_key(section))? {
+            Some(section) if !section.is_expired(now) => section,
+            _ => return Ok(Vec::new()),
+        };
+        std::iter::once(&latest)
+            .chain(index.older_versions(key))
+            .filter(|section| !section.is_expired(now))
+            .take(n)
+            .map(|section| self.read_value(section))
+            .collect()
+    }
+
+    /// Reports the live key count and how much of the logs compaction could reclaim
+    ///
+    /// Dead bytes are tracked as records are replaced, so this does no log reads.
+    pub fn stats(&self) -> Result<Stats> {
+        let writer = self.writer.lock().unwrap();
+        let disk_bytes = self.disk_usage()?;
+        let dead_bytes = writer.uncompacted;
+        let fragmentation = match disk_bytes {
+            0 => 0.0,
+            _ => (dead_bytes as f64 / disk_bytes as f64).min(1.0),
+        };
+        let record_reads = self.reader.record_reads.load(Ordering::Relaxed);
+        let replayed_records = self.config.replayed_records;
+        Ok(Stats { live_keys: self.len(), disk_bytes, dead_bytes, fragmentation, record_reads, replayed_records })
+    }
+
+    /// Returns running totals of the compactions this store has done since it was opened
+    pub fn metrics(&self) -> Metrics {
+        self.writer.lock().unwrap().metrics
+    }
+
+    /// Writes the index to a checkpoint file, so the next open only replays records written after it
+    ///
+    /// Writes wait while it is taken, and a hashed index has to read every key back for it.
+    /// Compacting, or anything else that deletes or rewrites the logs it covers, removes the
+    /// checkpoint, and one that no longer matches the logs is ignored, so an open replays
+    /// everything again until the next checkpoint. `open_with_report` never uses one.
+    pub fn checkpoint(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.log()?.flush()?;
+        let index = self.index.read().unwrap();
+        let path = &self.config.path;
+        let mut logs = Vec::new();
+        for gen in sorted_log_generations(path)? {
+            logs.push((gen, fs::metadata(log_file_path(path, gen))?.len()));
+        }
+        let mut keys = Vec::new();
+        for (key, latest) in index.range(Bound::Unbounded, Bound::Unbounded, &|section| self.read_key(section))? {
+            let mut sections: Vec<LogSection> = index.older_versions(&key).rev().copied().collect();
+            sections.push(latest);
+            keys.push((key, sections));
+        }
+        debug!(keys = keys.len(), "writing checkpoint");
+        Checkpoint { id: self.config.id, logs, seq: writer.seq, uncompacted: writer.uncompacted, keys }
+            .save(path, &self.config.format)
+    }
+
+    /// Total size in bytes of all generation logs
+    fn disk_usage(&self) -> Result<u64> {
+        let mut total = 0;
+        for gen in sorted_log_generations(&self.config.path)? {
+            total += fs::metadata(log_file_path(&self.config.path, gen))?.len();
+        }
+        Ok(total)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn set_all(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        KvStore::set_batch(self, pairs)
+    }
+
+    fn contains_key(&mut self, key: &str) -> Result<bool> {
+        KvStore::contains_key(self, key)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        KvStore::keys(self)
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        KvStore::scan_prefix(self, prefix)
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(KvStore::len(self))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        KvStore::flush(self)
+    }
+}
+
+/// A read-only view of a `KvStore` as it was when `KvStore::snapshot` was called.
+///
+/// A snapshot keeps its own copy of the index and its own handle on every
+/// generation log, so later sets, removes, compaction and `clear` never change
+/// what it reads. Logs are append-only and compaction only unlinks the ones it
+/// replaces, so the handles stay readable, with the disk space coming back once
+/// the snapshot is dropped. Deduplicated values are kept while any snapshot is
+/// alive. Keys with a TTL are judged against the time the snapshot was taken.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("key".to_owned(), "before".to_owned())?;
+/// let snapshot = store.snapshot()?;
+/// store.set("key".to_owned(), "after".to_owned())?;
+/// assert_eq!(snapshot.get("key".to_owned())?, Some("before".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Snapshot {
+    config: Arc<StoreConfig>,
+    index: KeyIndex,
+    reader: LogReader,
+    /// When the snapshot was taken, in unix milliseconds.
+    taken_at: u64,
+    _blobs: BlobPin,
+}
+
+impl Snapshot {
+    /// Gets the value a key had when the snapshot was taken, or `None` if it did not exist
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.index.get(&key, &|section| self.read_key(section))? {
+            Some(section) if !section.is_expired(self.taken_at) => {
+                Ok(Some(self.config.read_value(&self.reader, &section)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns every key that was live when the snapshot was taken, in sorted order
+    pub fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.index
+            .range(Bound::Unbounded, Bound::Unbounded, &|section| self.read_key(section))?
+            .into_iter()
+            .filter(|(_, section)| !section.is_expired(self.taken_at))
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    fn read_key(&self, section: &LogSection) -> Result<String> {
+        read_record_key(&self.reader, section, &self.config.format)
+    }
+}
+
+/// Sets staged against a store by `KvStore::transaction`, written together on `commit`
+///
+/// Nothing reaches the log until `commit`, which goes through `KvStore::set_batch`, so either
+/// every staged set lands or none does. Dropping the transaction discards them. A savepoint is
+/// just a position in the staged buffer, and rolling back to it drops everything staged since.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// let mut tx = store.transaction();
+/// tx.set("kept".to_owned(), "value".to_owned());
+/// let savepoint = tx.savepoint();
+/// tx.set("dropped".to_owned(), "value".to_owned());
+/// tx.rollback_to(savepoint);
+/// tx.commit()?;
+/// assert_eq!(store.get("dropped".to_owned())?, None);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<'a> {
+    store: &'a KvStore,
+    staged: Vec<(String, String)>,
+}
+
+impl Transaction<'_> {
+    /// Stages a set, to be written on `commit`
+    pub fn set(&mut self, key: String, value: String) {
+        self.staged.push((key, value));
+    }
+
+    /// Marks the current point in the transaction, to roll back to later
+    pub fn savepoint(&self) -> usize {
+        self.staged.len()
+    }
+
+    /// Discards every set staged since the savepoint was taken
+    ///
+    /// Savepoints taken after this one no longer mark anything staged, and rolling back to
+    /// them does nothing.
+    pub fn rollback_to(&mut self, savepoint: usize) {
+        self.staged.truncate(savepoint);
+    }
+
+    /// Writes every staged set with a single flush
+    pub fn commit(self) -> Result<()> {
+        self.store.set_batch(self.staged)
+    }
+}
+
+/// A background thread running a task against the store every interval, started by
+/// `KvStoreBuilder::sweep_expired` and `KvStoreBuilder::checkpoint_every`.
+///
+/// It only holds weak references to the store between runs, and exits once its stop channel is dropped.
+struct Periodic {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    fn start(store: &KvStore, name: &str, interval: Duration, task: fn(&KvStore)) -> Result<Periodic> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let config: Weak<StoreConfig> = Arc::downgrade(&store.config);
+        let index: Weak<RwLock<KeyIndex>> = Arc::downgrade(&store.index);
+        let writer: Weak<Mutex<LogWriter>> = Arc::downgrade(&store.writer);
+        let reader = store.reader.clone();
+        let thread = thread::Builder::new().name(name.to_owned()).spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                match (config.upgrade(), index.upgrade(), writer.upgrade()) {
+                    (Some(config), Some(index), Some(writer)) => {
+                        task(&KvStore { config, index, writer, reader: reader.clone(), _background: Vec::new() })
+                    }
+                    _ => return,
+                }
+            }
+        })?;
+        Ok(Periodic { stop: Some(stop), thread: Some(thread) })
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Drops every expired key from the index, counting its record towards the uncompacted bytes
+///
+/// Needs no reader, so a hashed index does not read back any keys.
+fn sweep_expired(index: &RwLock<KeyIndex>, writer: &Mutex<LogWriter>) {
+    let mut writer = writer.lock().unwrap();
+    let mut index = index.write().unwrap();
+    let now = now_millis();
+    let mut dead = 0;
+    // Deciding from the section alone never fails
+    let _ = index.try_retain(|section| match section.is_expired(now) {
+        true => {
+            dead += section.length;
+            Ok(false)
+        }
+        false => Ok(true),
+    });
+    writer.uncompacted += dead;
+}
+
+/// Keeps compaction and `clear` from deleting any blob while it is alive.
+struct BlobPin {
+    config: Arc<StoreConfig>,
+}
+
+impl BlobPin {
+    fn new(config: &Arc<StoreConfig>) -> BlobPin {
+        config.snapshots.fetch_add(1, Ordering::SeqCst);
+        BlobPin { config: Arc::clone(config) }
+    }
+}
+
+impl Drop for BlobPin {
+    fn drop(&mut self) {
+        self.config.snapshots.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A clone's own handles onto the generation logs, opened lazily.
+struct LogReader {
+    path: PathBuf,
+    /// Bumped whenever logs are removed or renumbered, shared by every clone.
+    epoch: Arc<AtomicU64>,
+    seen_epoch: Cell<u64>,
+    /// This clone's readers onto the generation being written to.
+    readers: RefCell<HashMap<u64, TrackingBufReader<File>>>,
+    /// Whether sealed generations are memory mapped rather than read from a shared handle.
+    mmap: bool,
+    /// The generation being written to, shared by every clone. Older ones no longer change.
+    active_gen: Arc<AtomicU64>,
+    /// Sealed generations, opened once and shared by every clone.
+    sealed: Arc<RwLock<HashMap<u64, Arc<SealedLog>>>>,
+    /// Records read through any clone, reported by `KvStore::stats`.
+    record_reads: Arc<AtomicU64>,
+}
+
+/// A handle onto a generation that is no longer written to.
+///
+/// Neither kind keeps a file position, so any number of threads can read through one at once.
+enum SealedLog {
+    File(File),
+    Map(Mmap),
+}
+
+impl LogReader {
+    fn new(path: PathBuf, mmap: bool) -> LogReader {
+        LogReader {
+            path,
+            epoch: Arc::new(AtomicU64::new(0)),
+            seen_epoch: Cell::new(0),
+            readers: RefCell::new(HashMap::new()),
+            mmap,
+            active_gen: Arc::new(AtomicU64::new(0)),
+            sealed: Arc::new(RwLock::new(HashMap::new())),
+            record_reads: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Reads the framed record of a section, leaving out any streamed value after it
+    fn read_section(&self, section: &LogSection) -> Result<Vec<u8>> {
+        self.record_reads.fetch_add(1, Ordering::Relaxed);
+        self.read_at(section.gen, section.start, section.frame_length())
+    }
+
+    /// Streams `length` bytes of a generation from `start` into `dst` a chunk at a time, returning their CRC32
+    fn copy_range(&self, gen: u64, start: u64, length: u64, dst: &mut impl Write) -> Result<u32> {
+        let mut hasher = crc32fast::Hasher::new();
+        let mut copied = 0;
+        while copied < length {
+            let chunk = self.read_at(gen, start + copied, (length - copied).min(STREAM_CHUNK_LEN as u64))?;
+            hasher.update(&chunk);
+            dst.write_all(&chunk)?;
+            copied += chunk.len() as u64;
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Reads raw bytes of a generation, opening it first if needed
+    fn read_at(&self, gen: u64, start: u64, length: u64) -> Result<Vec<u8>> {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        if self.seen_epoch.get() != epoch {
+            readers.clear();
+            self.seen_epoch.set(epoch);
+        }
+
+        if gen < self.active_gen.load(Ordering::SeqCst) {
+            // A reader opened while the generation was still active is no longer needed
+            readers.remove(&gen);
+            return self.read_sealed(gen, start, length);
+        }
+
+        let reader = match readers.entry(gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(create_reader(&log_file_path(&self.path, gen))?)
+            }
+        };
+        reader.seek(SeekFrom::Start(start))?;
+        let mut buffer = vec![0; length as usize];
+        reader.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Reads raw bytes of a sealed generation through the shared handle, opening it first if needed
+    fn read_sealed(&self, gen: u64, start: u64, length: u64) -> Result<Vec<u8>> {
+        let shared = self.sealed.read().unwrap().get(&gen).cloned();
+        let log = match shared {
+            Some(log) => log,
+            None => {
+                let mut sealed = self.sealed.write().unwrap();
+                match sealed.entry(gen) {
+                    Entry::Occupied(entry) => Arc::clone(entry.get()),
+                    Entry::Vacant(entry) => Arc::clone(entry.insert(Arc::new(self.open_sealed(gen)?))),
+                }
+            }
+        };
+
+        match &*log {
+            SealedLog::File(file) => {
+                let mut buffer = vec![0; length as usize];
+                read_exact_at(file, &mut buffer, start)?;
+                Ok(buffer)
+            }
+            SealedLog::Map(map) => {
+                let offset = start as usize;
+                map.get(offset..offset + length as usize)
+                    .map(|bytes| bytes.to_vec())
+                    .ok_or(KvsError::Corrupt { gen, offset: start })
+            }
+        }
+    }
+
+    fn open_sealed(&self, gen: u64) -> Result<SealedLog> {
+        let file = File::open(log_file_path(&self.path, gen))?;
+        if !self.mmap {
+            return Ok(SealedLog::File(file));
+        }
+        // Sealed logs are never written or truncated again while the store is open, and
+        // compaction only unlinks them or renames a merged log over them, which leaves
+        // existing maps readable
+        Ok(SealedLog::Map(unsafe { Mmap::map(&file)? }))
+    }
+
+    /// Makes every clone drop its open readers and the shared sealed handles before the next read
+    ///
+    /// Callers hold the index write lock, so no read is part way through a handle being dropped.
+    fn invalidate(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.sealed.write().unwrap().clear();
+    }
+
+    /// Records the generation now being written to, sealing every one before it
+    fn set_active_gen(&self, gen: u64) {
+        self.active_gen.store(gen, Ordering::SeqCst);
+    }
+
+    /// Returns a reader holding its own handle on each of the given generations, all opened now
+    ///
+    /// The handles stay readable after compaction unlinks their logs.
+    fn pinned(&self, gens: &[u64]) -> Result<LogReader> {
+        let mut sealed = HashMap::new();
+        for &gen in gens {
+            sealed.insert(gen, Arc::new(SealedLog::File(File::open(log_file_path(&self.path, gen))?)));
+        }
+        Ok(LogReader {
+            path: self.path.clone(),
+            epoch: Arc::new(AtomicU64::new(0)),
+            seen_epoch: Cell::new(0),
+            readers: RefCell::new(HashMap::new()),
+            mmap: false,
+            // Every generation counts as sealed, so reads only ever go through the handles above
+            active_gen: Arc::new(AtomicU64::new(u64::MAX)),
+            sealed: Arc::new(RwLock::new(sealed)),
+            record_reads: Arc::clone(&self.record_reads),
+        })
+    }
+}
+
+/// Fills `buffer` from the file at `offset` without moving any shared file position
+#[cfg(unix)]
+fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buffer = &mut buffer[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Clone for LogReader {
+    fn clone(&self) -> Self {
+        LogReader {
+            path: self.path.clone(),
+            epoch: Arc::clone(&self.epoch),
+            seen_epoch: Cell::new(self.epoch.load(Ordering::SeqCst)),
+            readers: RefCell::new(HashMap::new()),
+            mmap: self.mmap,
+            active_gen: Arc::clone(&self.active_gen),
+            sealed: Arc::clone(&self.sealed),
+            record_reads: Arc::clone(&self.record_reads),
+        }
+    }
+}
+
+/// Value transformation hooks configured through `KvStoreBuilder::value_hooks`.
+///
+/// Transformed bytes are hex encoded so they can be stored as the record's string value.
+pub(crate) struct ValueHooks {
+    pub(crate) on_write: WriteHook,
+    pub(crate) on_read: ReadHook,
+}
+
+impl ValueHooks {
+    /// Hex encodes the transformed bytes, the way hooked values were stored before `Command::SetBytes`
+    fn encode(&self, value: &str) -> String {
+        const HEX: &[u8; 16] = b"0123456789abcdef";
+        let bytes = (self.on_write)(value.as_bytes());
+        let mut encoded = String::with_capacity(bytes.len() * 2);
+        for byte in bytes {
+            encoded.push(HEX[(byte >> 4) as usize] as char);
+            encoded.push(HEX[(byte & 0xf) as usize] as char);
+        }
+        encoded
+    }
+
+    fn decode(&self, stored: &str, gen: u64, offset: u64) -> Result<String> {
+        let corrupt = || KvsError::Corrupt { gen, offset };
+        if stored.len() % 2 != 0 {
+            return Err(corrupt());
+        }
+        let bytes = (0..stored.len())
+            .step_by(2)
+            .map(|i| stored.get(i..i + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(corrupt)?;
+        let value = (self.on_read)(&bytes)?;
+        String::from_utf8(value).map_err(|_| corrupt())
+    }
+}
+
+/// How records are framed on disk, shared by everything that reads or writes them.
+pub(crate) struct RecordFormat {
+    separator: Vec<u8>,
+    /// Applied to every encoded command and deduplicated value before it is stored, see
+    /// `KvStoreBuilder::record_encryption_key`.
+    seal: Option<ValueHooks>,
+}
+
+impl RecordFormat {
+    fn new(separator: Option<Vec<u8>>, seal: Option<ValueHooks>) -> RecordFormat {
+        RecordFormat { separator: separator.unwrap_or_else(|| DEFAULT_SEPARATOR.to_vec()), seal }
+    }
+
+    fn seal(&self, bytes: Vec<u8>) -> Vec<u8> {
+        match &self.seal {
+            Some(seal) => (seal.on_write)(&bytes),
+            None => bytes,
+        }
+    }
+
+    fn unseal<'a>(&self, stored: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        match &self.seal {
+            Some(seal) => Ok(Cow::Owned((seal.on_read)(stored)?)),
+            None => Ok(Cow::Borrowed(stored)),
+        }
+    }
+}
+
+/// Takes the store directory's lock file, exclusively for a writer and shared for a reader
+///
+/// The lock is advisory and only keeps out other kvs opens, in this process or any other.
+/// It is released when the returned file is closed.
+fn lock_store(path: &Path, exclusive: bool) -> Result<File> {
+    let lock_file = path.join(LOCK_FILE);
+    let create = || OpenOptions::new().write(true).create(true).truncate(false).open(&lock_file);
+    let file = match exclusive {
+        true => create()?,
+        // A reader only has to create the lock file when no writer ever has
+        false => File::open(&lock_file).or_else(|_| create())?,
+    };
+    // Called through the trait, as newer std has inherent lock methods that would shadow it
+    let locked = match exclusive {
+        true => FileExt::try_lock_exclusive(&file),
+        false => FileExt::try_lock_shared(&file),
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(err) if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+            Err(KvsError::Locked { path: path.to_owned() })
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// One line of an `export`, independent of the log format.
+#[derive(Debug, Deserialize, Serialize)]
+struct ExportEntry {
+    key: String,
+    value: String,
+}
+
+/// Store-wide metadata persisted alongside the generation logs.
+#[derive(Debug, Deserialize, Serialize)]
+struct StoreMeta {
+    id: Uuid,
+    /// Sequence number of the last write before logs were last deleted, which may have taken
+    /// the record holding it with them. Missing from stores created before sequence numbers.
+    #[serde(default)]
+    seq: u64,
+}
+
+impl StoreMeta {
+    /// Reads the metadata file, creating it with a fresh id on first open
+    fn load_or_create(path: &Path) -> Result<StoreMeta> {
+        if path.join(META_FILE).is_file() {
+            return StoreMeta::load(path);
+        }
+
+        let meta = StoreMeta { id: Uuid::new_v4(), seq: 0 };
+        meta.save(path)?;
+        Ok(meta)
+    }
+
+    /// Replaces the metadata file
+    fn save(&self, path: &Path) -> Result<()> {
+        // Write to a temporary file first so a crash never leaves a half-written file behind
+        let tmp_file = path.join(format!("{}.tmp", META_FILE));
+        let mut writer = BufWriter::new(File::create(&tmp_file)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp_file, path.join(META_FILE))?;
+        Ok(())
+    }
+
+    /// Loads the metadata of an existing store
+    fn load(path: &Path) -> Result<StoreMeta> {
+        Ok(serde_json::from_reader(File::open(path.join(META_FILE))?)?)
+    }
+}
+
+/// The index as of a point in the logs, written by `KvStore::checkpoint` so an open only replays what came after.
+///
+/// Stored as the CRC32 of the sealed bincode body followed by the body itself.
+#[derive(Deserialize, Serialize)]
+struct Checkpoint {
+    id: Uuid,
+    /// Every log and its length when the checkpoint was taken, ending with the one written to.
+    logs: Vec<(u64, u64)>,
+    seq: u64,
+    uncompacted: u64,
+    /// Each key's older versions oldest first, followed by its latest section.
+    keys: Vec<(String, Vec<LogSection>)>,
+}
+
+impl Checkpoint {
+    /// Replaces the checkpoint file
+    fn save(&self, path: &Path, format: &RecordFormat) -> Result<()> {
+        let body = format.seal(bincode::serialize(self)?);
+        let tmp_file = path.join(format!("{}.tmp", CHECKPOINT_FILE));
+        let mut writer = BufWriter::new(File::create(&tmp_file)?);
+        writer.write_all(&crc32fast::hash(&body).to_be_bytes())?;
+        writer.write_all(&body)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp_file, path.join(CHECKPOINT_FILE))?;
+        Ok(())
+    }
+
+    /// Loads the checkpoint file if it still describes the given logs
+    ///
+    /// Every log but the last has to be as it was, and the last may only have grown since.
+    /// A missing, damaged or stale checkpoint returns `None`, leaving the open to replay everything.
+    fn load(path: &Path, format: &RecordFormat, id: Uuid, generations: &[u64]) -> Option<Checkpoint> {
+        let stored = fs::read(path.join(CHECKPOINT_FILE)).ok()?;
+        let checkpoint = Some(stored.as_slice())
+            .filter(|stored| stored.len() >= 4)
+            .map(|stored| stored.split_at(4))
+            .filter(|(checksum, body)| *checksum == crc32fast::hash(body).to_be_bytes())
+            .and_then(|(_, body)| format.unseal(body).ok())
+            .and_then(|body| bincode::deserialize::<Checkpoint>(&body).ok());
+        let checkpoint = match checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => {
+                warn!("ignoring a damaged checkpoint");
+                return None;
+            }
+        };
+        let log_len = |gen| fs::metadata(log_file_path(path, gen)).ok().map(|meta| meta.len());
+        let (&(last_gen, last_len), sealed) = checkpoint.logs.split_last()?;
+        let current = checkpoint.id == id
+            && generations.len() >= checkpoint.logs.len()
+            && checkpoint.logs.iter().zip(generations).all(|(&(gen, _), &found)| gen == found)
+            && sealed.iter().all(|&(gen, len)| log_len(gen) == Some(len))
+            && log_len(last_gen).map_or(false, |len| len >= last_len);
+        if !current {
+            debug!("ignoring a stale checkpoint");
+            return None;
+        }
+        Some(checkpoint)
+    }
+
+    /// Puts the checkpointed keys into an empty index, returning the uncompacted bytes
+    ///
+    /// Keys that have expired since count as dead, just as replaying their records would make them.
+    fn restore(self, index: &mut KeyIndex, resolve: ResolveKey) -> Result<u64> {
+        let now = now_millis();
+        let mut uncompacted = self.uncompacted;
+        for (key, sections) in self.keys {
+            match sections.last() {
+                Some(latest) if latest.is_expired(now) => {
+                    uncompacted += sections.iter().map(|section| section.length).sum::<u64>();
+                }
+                _ => {
+                    for section in sections {
+                        // Only when fewer versions are kept than when the checkpoint was taken
+                        if let Some(dropped) = index.insert(key.clone(), section, resolve)? {
+                            uncompacted += dropped.length;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(uncompacted)
+    }
+
+    /// Deletes the checkpoint file, if there is one, ahead of changing logs it covers
+    fn remove(path: &Path) -> Result<()> {
+        match fs::remove_file(path.join(CHECKPOINT_FILE)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Copies the record at the given section verbatim to the end of the writer, returning the copied command
+///
+/// Records that have expired by `now` are not copied and `None` is returned.
+fn copy_record(
+    reader: &LogReader,
+    section: &LogSection,
+    format: &RecordFormat,
+    now: u64,
+    writer: &mut TrackingBufWriter<File>,
+) -> Result<Option<Command>> {
+    let buffer = reader.read_section(section)?;
+    let command = parse_record(&buffer, format, section.gen, section.start)?;
+    if command.is_expired(now) {
+        return Ok(None);
+    }
+    writer.write_all(&buffer)?;
+    if section.payload > 0 {
+        reader.copy_range(section.gen, section.start + section.frame_length(), section.payload, writer)?;
+    }
+    Ok(Some(command))
+}
+
+/// Copies the record at the given section verbatim to the end of the writer, expired or not
+fn copy_section(reader: &LogReader, section: &LogSection, writer: &mut TrackingBufWriter<File>) -> Result<()> {
+    writer.write_all(&reader.read_section(section)?)?;
+    if section.payload > 0 {
+        reader.copy_range(section.gen, section.start + section.frame_length(), section.payload, writer)?;
+    }
+    Ok(())
+}
+
+/// The size tier of a log, 0 below `TIER_FANOUT` times `base` bytes and one more for each further factor of `TIER_FANOUT`
+fn size_tier(len: u64, base: u64) -> u32 {
+    let mut tier = 0;
+    let mut bound = base.max(1).saturating_mul(TIER_FANOUT as u64);
+    while len >= bound && bound < u64::MAX {
+        bound = bound.saturating_mul(TIER_FANOUT as u64);
+        tier += 1;
+    }
+    tier
+}
+
+/// Deflates a value, returning `None` if that would not make it smaller
+fn compress(value: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(value)?;
+    let compressed = encoder.finish()?;
+    Ok(Some(compressed).filter(|compressed| compressed.len() < value.len()))
+}
+
+/// Reads back the key of the record at the given section
+fn read_record_key(reader: &LogReader, section: &LogSection, format: &RecordFormat) -> Result<String> {
+    let buffer = reader.read_section(section)?;
+    match parse_index_entry(&buffer, format, section.gen, section.start)? {
+        IndexEntry::Set { key, .. } | IndexEntry::Remove { key, .. } => Ok(key),
+    }
+}
+
+/// Borrows the string inside a bound
+fn bound_as_str(bound: &Bound<String>) -> Bound<&str> {
+    match bound {
+        Bound::Included(value) => Bound::Included(value),
+        Bound::Excluded(value) => Bound::Excluded(value),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Returns the smallest string greater than every string starting with `prefix`
+///
+/// `None` means there is no such string, so a scan has to run to the end of the keys.
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // Skip over the surrogate gap, which holds no valid chars
+        let next = match last as u32 {
+            0xD7FF => Some('\u{E000}'),
+            code => char::from_u32(code + 1),
+        };
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// Milliseconds since the unix epoch, the unit of `expires_at`
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Appends a length-prefixed, checksummed record followed by the separator, without flushing
+fn write_record<W: Write>(writer: &mut W, command: &Command, format: &RecordFormat) -> Result<()> {
+    let body = format.seal(bincode::serialize(command)?);
+    writer.write_all(&(body.len() as u32).to_be_bytes())?;
+    writer.write_all(&crc32fast::hash(&body).to_be_bytes())?;
+    writer.write_all(&body)?;
+    writer.write_all(&format.separator)?;
+    Ok(())
+}
+
+/// Deserializes a single framed record, checking its length, checksum and trailing separator
+///
+/// A checksum mismatch is reported as `KvsError::CorruptRecord`, a sealed body that does not open
+/// as `KvsError::DecryptionFailed` and any other failure as `KvsError::Corrupt`.
+fn parse_record(record: &[u8], format: &RecordFormat, gen: u64, offset: u64) -> Result<Command> {
+    let body = record_body(record, format, gen, offset)?;
+    bincode::deserialize(&body)
+        .or_else(|_| bincode::deserialize::<LegacyCommand>(&body).map(Command::from))
+        .map_err(|_| KvsError::Corrupt { gen, offset })
+}
+
+/// Decodes just what indexing a framed record needs, after the same checks as `parse_record`
+///
+/// The value is borrowed from the record while decoding rather than copied out of it.
+fn parse_index_entry(record: &[u8], format: &RecordFormat, gen: u64, offset: u64) -> Result<IndexEntry> {
+    let body = record_body(record, format, gen, offset)?;
+    if let Ok(head) = bincode::deserialize::<CommandHead>(&body) {
+        return Ok(match head {
+            CommandHead::Set { key, expires_at, seq, .. }
+            | CommandHead::SetRef { key, expires_at, seq, .. }
+            | CommandHead::SetCompressed { key, expires_at, seq, .. }
+            | CommandHead::SetBytes { key, expires_at, seq, .. } => {
+                IndexEntry::Set { key, expires_at, streamed: None, seq }
+            }
+            CommandHead::SetStream { key, len, expires_at, seq } => {
+                IndexEntry::Set { key, expires_at, streamed: Some(len), seq }
+            }
+            CommandHead::Remove { key, seq } => IndexEntry::Remove { key, seq },
+        });
+    }
+    let head: LegacyCommandHead = bincode::deserialize(&body).map_err(|_| KvsError::Corrupt { gen, offset })?;
+    Ok(match head {
+        LegacyCommandHead::Set { key, expires_at, .. }
+        | LegacyCommandHead::SetRef { key, expires_at, .. }
+        | LegacyCommandHead::SetCompressed { key, expires_at, .. } => {
+            IndexEntry::Set { key, expires_at, streamed: None, seq: 0 }
+        }
+        LegacyCommandHead::SetStream { key, len, expires_at } => {
+            IndexEntry::Set { key, expires_at, streamed: Some(len), seq: 0 }
+        }
+        LegacyCommandHead::Remove { key } => IndexEntry::Remove { key, seq: 0 },
+    })
+}
+
+/// Checks a framed record's length, checksum and trailing separator, returning its unsealed body
+fn record_body<'a>(record: &'a [u8], format: &RecordFormat, gen: u64, offset: u64) -> Result<Cow<'a, [u8]>> {
+    let corrupt = || KvsError::Corrupt { gen, offset };
+    if record.len() < RECORD_HEADER_LEN {
+        return Err(corrupt());
+    }
+    let (header, rest) = record.split_at(RECORD_HEADER_LEN);
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let checksum = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let body = rest
+        .strip_suffix(format.separator.as_slice())
+        .filter(|body| body.len() == length)
+        .ok_or_else(corrupt)?;
+    if crc32fast::hash(body) != checksum {
+        return Err(KvsError::CorruptRecord { gen, offset });
+    }
+    format.unseal(body)
+}
+
+/// Checks the format version at the start of a log, leaving the reader at the first record
+///
+/// Returns the version, or `None` for an empty log, which has not been written to yet.
+fn read_log_header(reader: &mut TrackingBufReader<File>, gen: u64) -> Result<Option<u32>> {
+    let mut header = Vec::with_capacity(LOG_HEADER_LEN);
+    (&mut *reader).take(LOG_HEADER_LEN as u64).read_to_end(&mut header)?;
+    if header.is_empty() {
+        return Ok(None);
+    }
+    if let Some(version) = header.strip_prefix(LOG_MAGIC.as_slice()).filter(|version| version.len() == 4) {
+        let found = u32::from_be_bytes([version[0], version[1], version[2], version[3]]);
+        if !(OLDEST_LOG_FORMAT_VERSION..=LOG_FORMAT_VERSION).contains(&found) {
+            return Err(KvsError::UnsupportedFormat { gen, found, expected: LOG_FORMAT_VERSION });
+        }
+        return Ok(Some(found));
+    }
+    if header[0] == LEGACY_LOG_FORMAT_VERSION {
+        reader.seek(SeekFrom::Start(1))?;
+        return Ok(Some(LEGACY_LOG_FORMAT_VERSION as u32));
+    }
+    Err(KvsError::UnrecognizedLog { gen })
+}
+
+/// How a record checked by `scan_generation` turned out
+enum Scanned {
+    /// The record parsed, and any value streamed after it matched its checksum.
+    Valid,
+    /// The record could not be read back, `torn` if nothing in the log follows it.
+    Damaged { torn: bool },
+}
+
+/// Checks each record of a generation log after its header, passing `visit` its offset, how
+/// it turned out and the offset just past it
+///
+/// Each record's frame, checksum and encoding are checked, and streamed values are read
+/// through to their checksum. A record that fails to decrypt ends the scan with
+/// `KvsError::DecryptionFailed`, as every record would fail the same way, so this is a wrong
+/// key rather than damage.
+fn scan_generation(
+    reader: &mut TrackingBufReader<File>,
+    format: &RecordFormat,
+    gen: u64,
+    mut visit: impl FnMut(u64, Scanned, u64),
+) -> Result<()> {
+    let mut record = Vec::new();
+    let mut pos = reader.pos;
+    while reader.read_record(&mut record, &format.separator)? > 0 {
+        let parsed = match parse_record(&record, format, gen, pos) {
+            Ok(Command::SetStream { len, .. }) if !read_payload(reader, len, &mut std::io::sink())? => {
+                Err(KvsError::CorruptRecord { gen, offset: pos })
+            }
+            parsed => parsed,
+        };
+        let scanned = match parsed {
+            Ok(_) => Scanned::Valid,
+            Err(KvsError::DecryptionFailed) => return Err(KvsError::DecryptionFailed),
+            Err(_) => Scanned::Damaged { torn: reader.at_end()? },
+        };
+        visit(pos, scanned, reader.pos);
+        pos = reader.pos;
+        record.clear();
+    }
+    Ok(())
+}
+
+/// Copies a streamed value from the reader into `dst`, returning false if the log ends first or the checksum after it disagrees
+fn read_payload(reader: &mut TrackingBufReader<File>, len: u64, dst: &mut impl Write) -> Result<bool> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut chunk = vec![0; len.min(STREAM_CHUNK_LEN as u64) as usize];
+    let mut remaining = len;
+    while remaining > 0 {
+        let wanted = remaining.min(STREAM_CHUNK_LEN as u64) as usize;
+        let read = reader.read(&mut chunk[..wanted])?;
+        if read == 0 {
+            return Ok(false);
+        }
+        hasher.update(&chunk[..read]);
+        dst.write_all(&chunk[..read])?;
+        remaining -= read as u64;
+    }
+    let mut stored = Vec::with_capacity(PAYLOAD_TRAILER_LEN as usize);
+    (&mut *reader).take(PAYLOAD_TRAILER_LEN).read_to_end(&mut stored)?;
+    Ok(stored == hasher.finalize().to_be_bytes())
+}
+
+fn blob_file_path(path: &Path, hash: &str) -> PathBuf {
+    path.join(BLOB_DIR).join(hash)
+}
+
+/// Stores a deduplicated value under its content hash unless it is already present
+fn write_blob(path: &Path, hash: &str, value: &[u8]) -> Result<()> {
+    let blob_file = blob_file_path(path, hash);
+    if blob_file.is_file() {
+        return Ok(());
+    }
+    fs::create_dir_all(path.join(BLOB_DIR))?;
+    let tmp_file = blob_file.with_extension("tmp");
+    let mut file = File::create(&tmp_file)?;
+    file.write_all(value)?;
+    file.sync_all()?;
+    fs::rename(tmp_file, blob_file)?;
+    Ok(())
+}
+
+fn read_blob(path: &Path, hash: &str) -> Result<Vec<u8>> {
+    Ok(fs::read(blob_file_path(path, hash))?)
+}
+
+pub fn log_file_path(path: &Path, generation: u64) -> PathBuf {
+    path.join(format!("{}.log", generation))
+}
+
+fn filter_file_path(path: &Path, generation: u64) -> PathBuf {
+    path.join(format!("{}.filter", generation))
+}
+
+/// Saves the bloom filter of a sealed generation next to its log, if the index keeps one
+///
+/// Stored as the CRC32 of the body followed by the body: the store id, the length of the log
+/// it covers, the filter hash of `FILTER_PROBE_KEY` and the filter's bits.
+fn save_filter(path: &Path, id: Uuid, gen: u64, index: &KeyIndex) -> Result<()> {
+    let filter = match index.filter(gen) {
+        Some(filter) => filter,
+        None => return Ok(()),
+    };
+    let mut body = id.as_bytes().to_vec();
+    body.extend(fs::metadata(log_file_path(path, gen))?.len().to_be_bytes());
+    body.extend(KeyFilter::hash(FILTER_PROBE_KEY).to_be_bytes());
+    body.extend(filter.to_bytes());
+    let filter_file = filter_file_path(path, gen);
+    let tmp_file = filter_file.with_extension("tmp");
+    let mut file = File::create(&tmp_file)?;
+    file.write_all(&crc32fast::hash(&body).to_be_bytes())?;
+    file.write_all(&body)?;
+    file.sync_all()?;
+    fs::rename(tmp_file, filter_file)?;
+    Ok(())
+}
+
+/// Loads the saved bloom filter of a sealed generation if it still describes the log
+///
+/// A missing or damaged filter, one saved for another store or log length, or one whose keys
+/// would hash differently now returns `None`, leaving the open to build the filter again.
+fn load_filter(path: &Path, id: Uuid, gen: u64) -> Option<KeyFilter> {
+    let stored = fs::read(filter_file_path(path, gen)).ok()?;
+    let log_len = fs::metadata(log_file_path(path, gen)).ok()?.len();
+    let (checksum, body) = Some(stored.as_slice()).filter(|stored| stored.len() >= 36)?.split_at(4);
+    let (header, bits) = body.split_at(32);
+    let mut expected = id.as_bytes().to_vec();
+    expected.extend(log_len.to_be_bytes());
+    expected.extend(KeyFilter::hash(FILTER_PROBE_KEY).to_be_bytes());
+    if checksum != crc32fast::hash(body).to_be_bytes() || header != expected.as_slice() {
+        debug!(gen, "ignoring a stale or damaged filter");
+        return None;
+    }
+    KeyFilter::from_bytes(bits)
+}
+
+/// Deletes a generation's saved bloom filter, if there is one, ahead of deleting its log
+fn remove_filter(path: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(filter_file_path(path, gen)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+pub fn create_reader(old_log_file: &Path) -> Result<TrackingBufReader<File>> {
+    create_reader_with_capacity(old_log_file, DEFAULT_BUFFER_CAPACITY)
+}
+
+fn create_reader_with_capacity(old_log_file: &Path, capacity: usize) -> Result<TrackingBufReader<File>> {
+    let old_gen_reader = TrackingBufReader::with_capacity(
+        capacity,
+        OpenOptions::new()
+            .read(true)
+            .open(old_log_file)?)?;
+    Ok(old_gen_reader)
+}
+
+pub fn create_writer(new_log_file: &Path) -> Result<TrackingBufWriter<File>> {
+    create_writer_with_capacity(new_log_file, DEFAULT_BUFFER_CAPACITY)
+}
+
+fn create_writer_with_capacity(new_log_file: &Path, capacity: usize) -> Result<TrackingBufWriter<File>> {
+    let mut writer = TrackingBufWriter::with_capacity(
+        capacity,
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(new_log_file)?)?;
+    // The header goes out with the first flush, like any other write
+    if writer.pos == 0 {
+        writer.write_all(LOG_MAGIC)?;
+        writer.write_all(&LOG_FORMAT_VERSION.to_be_bytes())?;
+    }
+    Ok(writer)
+}
+
+pub fn sorted_log_generations<P: AsRef<Path>>(path: P) -> Result<Vec<u64>> {
+    let mut log_files = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file()
+            && entry.path().extension() == Some("log".as_ref()))
+        .filter_map(|entry| {
+            entry
+                .path()
+                .file_stem()
+                .map(|os_str| os_str.to_os_string())
+                .and_then(|os_str| os_str.into_string().ok())
+        })
+        .filter_map(|s| s.parse().ok())
+        .collect::<Vec<u64>>();
+
+    log_files.sort_unstable();
+    Ok(log_files)
+}
+
+/// Reads the log file and populates the in-memory map
+/// Records are expected to end with a newline
+pub fn load(index: &mut BTreeMap<String, LogSection>, reader: &mut TrackingBufReader<File>, gen: u64) -> Result<u64>{
+    let mut keys = KeyIndex::from_keys(std::mem::take(index));
+    // Every key is held in full, so nothing ever has to be read back
+    let resolve = |section: &LogSection| Err(KvsError::Corrupt { gen: section.gen, offset: section.start });
+    let format = RecordFormat { separator: DEFAULT_SEPARATOR.to_vec(), seal: None };
+    let replayed = replay(&mut keys, reader, gen, 0, &format, &resolve, None);
+    if let Some(keys) = keys.into_keys() {
+        *index = keys;
+    }
+    replayed.map(|replayed| replayed.uncompacted)
+}
+
+/// What replaying a single generation found.
+struct Replayed {
+    /// Bytes of records that are no longer live.
+    uncompacted: u64,
+    /// Offset of the unreadable record replay stopped at, if the log ended in one.
+    torn_at: Option<u64>,
+    /// Format version from the log's header.
+    version: Option<u32>,
+    /// Highest sequence number of any record read, 0 if none had one.
+    seq: u64,
+    /// Records read, not counting any unreadable ones.
+    records: u64,
+}
+
+/// Replays a log file into the index, checking that every record is terminated by the separator and parses
+///
+/// Without a report the first corrupt record fails the replay, with one it is recorded and skipped.
+/// An unreadable last record is what a crash in the middle of a write leaves behind, so replay
+/// stops there instead and the rest of the log is kept. Records before offset `from` are
+/// skipped, as the index already holds them.
+fn replay(
+    index: &mut KeyIndex,
+    reader: &mut TrackingBufReader<File>,
+    gen: u64,
+    from: u64,
+    format: &RecordFormat,
+    resolve: ResolveKey,
+    mut report: Option<&mut RecoveryReport>,
+) -> Result<Replayed> {
+    let mut record = Vec::new();
+    let mut uncompacted: u64 = 0;
+    let mut seq = 0;
+    let now = now_millis();
+    if let Some(report) = report.as_deref_mut() {
+        report.generations += 1;
+    }
+    let version = read_log_header(reader, gen)?;
+    if from > reader.pos {
+        reader.seek(SeekFrom::Start(from))?;
+    }
+    let mut records = 0;
+    let mut pos = reader.pos;
+    while reader.read_record(&mut record, &format.separator)? > 0 {
+        let mut parsed = parse_index_entry(&record, format, gen, pos);
+        // A record that frames correctly but will not decrypt means the wrong key, not damage
+        if let Err(KvsError::DecryptionFailed) = parsed {
+            return Err(KvsError::DecryptionFailed);
+        }
+        // Streamed values are stepped over unread, only a log ending inside one is noticed here
+        let frame_end = reader.pos;
+        if let Ok(IndexEntry::Set { streamed: Some(len), .. }) = &parsed {
+            if !reader.skip(len + PAYLOAD_TRAILER_LEN)? {
+                parsed = Err(KvsError::Corrupt { gen, offset: pos });
+            }
+        }
+        if parsed.is_err() && reader.at_end()? {
+            if let Some(report) = report.as_deref_mut() {
+                report.corruptions.push(Corruption { gen, offset: pos, length: reader.pos - pos });
+            }
+            return Ok(Replayed { uncompacted, torn_at: Some(pos), version, seq, records });
+        }
+        let entry = match (parsed, report.as_deref_mut()) {
+            (Ok(entry), report) => {
+                if let Some(report) = report {
+                    report.records += 1;
+                }
+                entry
+            }
+            (Err(_), Some(report)) => {
+                report.corruptions.push(Corruption { gen, offset: pos, length: reader.pos - pos });
+                uncompacted += reader.pos - pos;
+                pos = reader.pos;
+                record.clear();
+                continue;
+            }
+            (Err(err), None) => return Err(err),
+        };
+        let payload = reader.pos - frame_end;
+        records += 1;
+        seq = seq.max(entry.seq());
+        match entry {
+            IndexEntry::Set { key, expires_at: Some(expires_at), .. } if expires_at <= now => {
+                // An expired set hides any older value just like a remove
+                if let Some(dead) = index.remove(&key, resolve)? {
+                    uncompacted += dead;
+                }
+                uncompacted += reader.pos - pos;
+            },
+            IndexEntry::Set { key, expires_at, .. } => {
+                let section = LogSection::new(gen, pos, reader.pos).expiring(expires_at).with_payload(payload);
+                if let Some(old_section) = index.insert(key, section, resolve)? {
+                    uncompacted += old_section.length;
+                }
+            },
+            IndexEntry::Remove { key, .. } => {
+                if let Some(dead) = index.remove(&key, resolve)? {
+                    uncompacted += dead;
+                }
+                uncompacted += reader.pos - pos; // The rm command can also be removed during compaction as absence === final removal
+            }
+        }
+        pos = reader.pos;
+        record.clear();
+    }
+    Ok(Replayed { uncompacted, torn_at: None, version, seq, records })
+}
+
+/// A record in a generation log.
+///
+/// `seq` is the store's sequence number for the write, see `KvStore::get_with_seq`.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Command {
+    /// `expires_at` is in unix milliseconds, `None` for keys that never expire.
+    Set { key: String, value: String, expires_at: Option<u64>, seq: u64 },
+    /// A set whose value is stored once in the blob area under its content hash.
+    SetRef { key: String, hash: String, expires_at: Option<u64>, seq: u64 },
+    Remove { key: String, seq: u64 },
+    /// A set whose value is deflate compressed, then passed through any value hooks.
+    SetCompressed { key: String, value: Vec<u8>, expires_at: Option<u64>, seq: u64 },
+    /// A set whose `len` value bytes follow the record in the log, written by `KvStore::set_reader`.
+    SetStream { key: String, len: u64, expires_at: Option<u64>, seq: u64 },
+    /// A set whose value is kept as the bytes the value hooks turned it into.
+    SetBytes { key: String, value: Vec<u8>, expires_at: Option<u64>, seq: u64 },
+}
+
+/// `Command` as written before format version 5, without a sequence number.
+///
+/// A record in this layout always ends where a `Command` would go on to its `seq`, so it
+/// never decodes as one and the two can be told apart by trying `Command` first.
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Set { key: String, value: String, expires_at: Option<u64> },
+    SetRef { key: String, hash: String, expires_at: Option<u64> },
+    Remove { key: String },
+    SetCompressed { key: String, value: Vec<u8>, expires_at: Option<u64> },
+    SetStream { key: String, len: u64, expires_at: Option<u64> },
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(command: LegacyCommand) -> Command {
+        match command {
+            LegacyCommand::Set { key, value, expires_at } => Command::Set { key, value, expires_at, seq: 0 },
+            LegacyCommand::SetRef { key, hash, expires_at } => Command::SetRef { key, hash, expires_at, seq: 0 },
+            LegacyCommand::Remove { key } => Command::Remove { key, seq: 0 },
+            LegacyCommand::SetCompressed { key, value, expires_at } => {
+                Command::SetCompressed { key, value, expires_at, seq: 0 }
+            }
+            LegacyCommand::SetStream { key, len, expires_at } => Command::SetStream { key, len, expires_at, seq: 0 },
+        }
+    }
+}
+
+impl Command {
+    /// Returns the key this command applies to
+    fn key(&self) -> &str {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetRef { key, .. }
+            | Command::SetCompressed { key, .. }
+            | Command::SetStream { key, .. }
+            | Command::SetBytes { key, .. }
+            | Command::Remove { key, .. } => key,
+        }
+    }
+
+    /// Returns the sequence number of the write
+    fn seq(&self) -> u64 {
+        match self {
+            Command::Set { seq, .. }
+            | Command::SetRef { seq, .. }
+            | Command::SetCompressed { seq, .. }
+            | Command::SetStream { seq, .. }
+            | Command::SetBytes { seq, .. }
+            | Command::Remove { seq, .. } => *seq,
+        }
+    }
+
+    /// Returns the expiry time of a set, `None` for removes and keys that never expire
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::Set { expires_at, .. }
+            | Command::SetRef { expires_at, .. }
+            | Command::SetCompressed { expires_at, .. }
+            | Command::SetStream { expires_at, .. }
+            | Command::SetBytes { expires_at, .. } => *expires_at,
+            Command::Remove { .. } => None,
+        }
+    }
+
+    /// Returns true if this is a set whose expiry time is before `now`
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at().map_or(false, |expires_at| expires_at <= now)
+    }
+}
+
+/// Mirrors `Command` variant for variant and field for field, so it decodes the same bincode
+/// while borrowing values from the record instead of copying them.
+#[derive(Deserialize)]
+enum CommandHead<'a> {
+    Set { key: String, _value: &'a [u8], expires_at: Option<u64>, seq: u64 },
+    SetRef { key: String, _hash: &'a [u8], expires_at: Option<u64>, seq: u64 },
+    Remove { key: String, seq: u64 },
+    SetCompressed { key: String, _value: &'a [u8], expires_at: Option<u64>, seq: u64 },
+    SetStream { key: String, len: u64, expires_at: Option<u64>, seq: u64 },
+    SetBytes { key: String, _value: &'a [u8], expires_at: Option<u64>, seq: u64 },
+}
+
+/// Mirrors `LegacyCommand` the way `CommandHead` mirrors `Command`.
+#[derive(Deserialize)]
+enum LegacyCommandHead<'a> {
+    Set { key: String, _value: &'a [u8], expires_at: Option<u64> },
+    SetRef { key: String, _hash: &'a [u8], expires_at: Option<u64> },
+    Remove { key: String },
+    SetCompressed { key: String, _value: &'a [u8], expires_at: Option<u64> },
+    SetStream { key: String, len: u64, expires_at: Option<u64> },
+}
+
+/// What the index needs from a record.
+enum IndexEntry {
+    /// `streamed` is the length of the value following a `Command::SetStream` record.
+    Set { key: String, expires_at: Option<u64>, streamed: Option<u64>, seq: u64 },
+    Remove { key: String, seq: u64 },
+}
+
+impl IndexEntry {
+    fn seq(&self) -> u64 {
+        match self {
+            IndexEntry::Set { seq, .. } | IndexEntry::Remove { seq, .. } => *seq,
+        }
+    }
+}
+
+pub struct TrackingBufWriter<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> TrackingBufWriter<W> {
+    /// Creates a writer that buffers up to `capacity` bytes before writing through
+    fn with_capacity(capacity: usize, mut inner: W) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::End(0))?;
+        Ok(TrackingBufWriter { writer: BufWriter::with_capacity(capacity, inner), pos })
+    }
+}
+
+impl TrackingBufWriter<File> {
+    /// Flushes the buffer and waits for the file contents to reach the disk
+    fn sync_all(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+
+    /// Throws away anything still buffered and shortens the file to `pos` bytes
+    fn truncate(self, pos: u64) -> std::io::Result<Self> {
+        let capacity = self.writer.capacity();
+        let (file, _unwritten) = self.writer.into_parts();
+        file.set_len(pos)?;
+        // The file is opened for appending, so later writes land at the new end
+        Ok(TrackingBufWriter { writer: BufWriter::with_capacity(capacity, file), pos })
+    }
+}
+
+impl<W: Write + Seek> Write for TrackingBufWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let bytes_written = self.writer.write(buf)?;
+        self.pos += bytes_written as u64;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for TrackingBufWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl<R: Read + Seek> TrackingBufReader<R> {
+    /// Creates a reader that reads ahead up to `capacity` bytes at a time
+    fn with_capacity(capacity: usize, mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        // `at_end` peeks through the buffer, so it needs room for at least a byte
+        Ok(TrackingBufReader { reader: BufReader::with_capacity(capacity.max(1), inner), pos })
+    }
+
+    /// Returns true once there is nothing left to read
+    fn at_end(&mut self) -> Result<bool> {
+        Ok(self.reader.fill_buf()?.is_empty())
+    }
+
+    /// Reads the next length-prefixed record and its separator, or whatever is left of it at the end of the file
+    fn read_record(&mut self, buf: &mut Vec<u8>, separator: &[u8]) -> Result<usize> {
+        let start = buf.len();
+        let mut bytes_read = (&mut self.reader).take(RECORD_HEADER_LEN as u64).read_to_end(buf)?;
+        if bytes_read == RECORD_HEADER_LEN {
+            let mut length = [0u8; 4];
+            length.copy_from_slice(&buf[start..start + 4]);
+            // Reading through `take` only allocates for bytes that are actually there
+            let remaining = u32::from_be_bytes(length) as u64 + separator.len() as u64;
+            bytes_read += (&mut self.reader).take(remaining).read_to_end(buf)?;
+        }
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl TrackingBufReader<File> {
+    /// Moves past `len` bytes without reading them, stopping at the end of the file
+    ///
+    /// Returns false if the file ended first.
+    fn skip(&mut self, len: u64) -> Result<bool> {
+        let end = self.reader.get_ref().metadata()?.len().max(self.pos);
+        let wanted = self.pos.saturating_add(len);
+        let target = wanted.min(end);
+        self.reader.seek_relative((target - self.pos) as i64)?;
+        self.pos = target;
+        Ok(target == wanted)
+    }
+}
+
+pub struct TrackingBufReader<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> Read for TrackingBufReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.reader.read(buf)?;
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl<R: Read + Seek> Seek for TrackingBufReader<R> {
+    /// Seeks from the start of the file without a system call when the target is where the reader already is, or still buffered
+    ///
+    /// Reads of the active generation seek before every record, so this lets a run of gets
+    /// over neighbouring records be served from one buffer fill.
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        if let SeekFrom::Start(target) = pos {
+            // Only moves the inner file if the target is outside the buffer
+            self.reader.seek_relative(target.wrapping_sub(self.pos) as i64)?;
+            self.pos = target;
+            return Ok(target);
+        }
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Running totals kept since a store was opened, returned by `KvStore::metrics`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Compactions run, counting each size-tiered merge as one.
+    pub compactions: u64,
+    /// Log bytes those compactions freed on disk.
+    pub reclaimed_bytes: u64,
+    /// Time spent in those compactions.
+    pub compaction_time: Duration,
+}
+
+impl Metrics {
+    fn add_compaction(&mut self, started: Instant, bytes_before: u64, bytes_after: u64) {
+        self.compactions += 1;
+        self.reclaimed_bytes += bytes_before.saturating_sub(bytes_after);
+        self.compaction_time += started.elapsed();
+    }
+}
+
+/// How much of a store is live, returned by `KvStore::stats`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stats {
+    /// Number of live keys.
+    pub live_keys: usize,
+    /// Total size in bytes of every generation log.
+    pub disk_bytes: u64,
+    /// Bytes of overwritten, removed and expired records that compaction would reclaim.
+    pub dead_bytes: u64,
+    /// Share of `disk_bytes` that is dead, from 0.0 to 1.0.
+    pub fragmentation: f64,
+    /// Records read back from the logs since the store was opened, by every clone and snapshot.
+    pub record_reads: u64,
+    /// Records replayed to rebuild the index when the store was opened, only those after the
+    /// checkpoint if it opened from one.
+    pub replayed_records: u64,
+}
+
+/// What was written by `KvStore::compact_into`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Number of live keys written.
+    pub keys: usize,
+    /// Number of log bytes written.
+    pub bytes: u64,
+}
+
+/// A write seen by `KvStore::subscribe`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeEvent {
+    Set { key: String, value: String },
+    /// A value of `len` bytes streamed in with `set_reader`, which `get_writer` reads back.
+    SetStream { key: String, len: u64 },
+    Remove { key: String },
+}
+
+/// A single command for a key, located by its generation and offset in that generation's log.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub gen: u64,
+    pub offset: u64,
+    pub command: Command,
+}
+
+/// Summary of the log integrity checks run while opening a store with `KvStore::open_with_report`.
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// Number of generation logs replayed.
+    pub generations: usize,
+    /// Number of valid records replayed.
+    pub records: u64,
+    /// Records that failed the integrity checks and were skipped.
+    pub corruptions: Vec<Corruption>,
+}
+
+impl RecoveryReport {
+    /// Returns true if no corruption was found.
+    pub fn is_healthy(&self) -> bool {
+        self.corruptions.is_empty()
+    }
+}
+
+/// What `KvStore::repair` found and trimmed, one entry per generation log that holds records.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub generations: Vec<GenerationRepair>,
+}
+
+impl RepairReport {
+    /// Returns true if nothing had to be dropped.
+    pub fn is_clean(&self) -> bool {
+        self.generations.iter().all(|gen| gen.dropped == 0)
+    }
+}
+
+/// What `KvStore::validate` found in a store directory, which it leaves untouched.
+#[derive(Debug, Default)]
+pub struct ValidateReport {
+    /// Number of generation logs checked.
+    pub generations: usize,
+    /// Number of records that passed every check.
+    pub records: u64,
+    /// Records that failed a check somewhere other than the end of their log, which stop the store opening.
+    pub corruptions: Vec<Corruption>,
+    /// Problems the next writable open fixes by itself, such as a torn last record.
+    pub warnings: Vec<String>,
+}
+
+impl ValidateReport {
+    /// Returns true if no corruption was found, whatever the warnings.
+    pub fn is_valid(&self) -> bool {
+        self.corruptions.is_empty()
+    }
+}
+
+/// The outcome of repairing one generation log.
+#[derive(Debug, PartialEq, Eq)]
+pub struct GenerationRepair {
+    pub gen: u64,
+    /// Records before the first bad one, which are all kept.
+    pub kept: u64,
+    /// The first bad record and every record framed after it.
+    pub dropped: u64,
+    /// Bytes cut off the end of the log.
+    pub trimmed_bytes: u64,
+}
+
+/// Location of a corrupt record found during recovery.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Corruption {
+    pub gen: u64,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Where a record lives in the logs.
+///
+/// A section spans the whole framed record, from its length prefix up to and
+/// including the trailing separator, both when written and when replayed. For
+/// a streamed value it also spans the value and checksum after the record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LogSection {
+    gen: u64,
+    start: u64,
+    length: u64,
+    /// Copied from the record so expiry can be checked without reading it.
+    expires_at: Option<u64>,
+    /// Bytes of streamed value and checksum after the framed record, 0 for any other record.
+    payload: u64,
+}
+
+impl LogSection {
+    fn new(gen: u64, start: u64, end: u64) -> Self {
+        LogSection { gen, start, length: end - start, expires_at: None, payload: 0 }
+    }
+
+    /// Generation whose log holds the record
+    pub fn gen(&self) -> u64 {
+        self.gen
+    }
+
+    /// Offset of the record in its log
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Length of the record, including its framing
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    fn expiring(mut self, expires_at: Option<u64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    fn with_payload(mut self, payload: u64) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Length of just the framed record, without any streamed value after it
+    fn frame_length(&self) -> u64 {
+        self.length - self.payload
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now)
+    }
+}
+
+impl From<(u64, u64, u64)> for LogSection {
+    fn from((gen, start, end): (u64, u64, u64)) -> Self {
+        LogSection { gen, start, length: end - start, expires_at: None, payload: 0 }
+    }
+}
+
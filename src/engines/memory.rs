@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+use crate::{KvsEngine, KvsError, Result};
+
+/// A `KvsEngine` that keeps every key in memory and never touches the disk.
+///
+/// Nothing outlives the last clone, which makes it a quick stand-in for a real engine in
+/// tests, or a cache in front of one. Clones share the same keys, like clones of `KvStore`.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{InMemoryKvsEngine, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut engine = InMemoryKvsEngine::new();
+/// engine.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct InMemoryKvsEngine {
+    // Ordered, as `keys` and `scan_prefix` return keys in sorted order
+    map: Arc<RwLock<BTreeMap<String, String>>>,
+}
+
+impl InMemoryKvsEngine {
+    /// Creates an engine holding no keys
+    pub fn new() -> InMemoryKvsEngine {
+        InMemoryKvsEngine::default()
+    }
+}
+
+impl KvsEngine for InMemoryKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.map.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.map.read().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.map.write().unwrap().remove(&key).ok_or(KvsError::KeyNotFound { key })?;
+        Ok(())
+    }
+
+    fn set_all(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.map.write().unwrap().extend(pairs);
+        Ok(())
+    }
+
+    fn contains_key(&mut self, key: &str) -> Result<bool> {
+        Ok(self.map.read().unwrap().contains_key(key))
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(self.map.read().unwrap().keys().cloned().collect())
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        Ok(self.map
+            .read()
+            .unwrap()
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(self.map.read().unwrap().len())
+    }
+}
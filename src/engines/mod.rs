@@ -0,0 +1,196 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use crate::engines::kvs::META_FILE;
+use crate::{KvStore, KvsError, Result, SledKvsEngine};
+
+mod bloom;
+mod index;
+pub mod kvs;
+pub mod memory;
+pub mod sled;
+
+/// A storage backend for string key/value pairs.
+pub trait KvsEngine {
+    /// Sets the value of a string key to a string
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Gets the string value of a given string key
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Removes a given key
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key does not exist.
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Sets every pair, later pairs winning when a key appears more than once
+    ///
+    /// The default sets them one at a time, so a failure part way leaves the earlier pairs
+    /// set. Every engine in this crate applies the whole batch or none of it.
+    fn set_all(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the given key is present
+    fn contains_key(&mut self, key: &str) -> Result<bool> {
+        Ok(self.get(key.to_owned())?.is_some())
+    }
+
+    /// Returns every key currently present, in sorted order
+    fn keys(&mut self) -> Result<Vec<String>>;
+
+    /// Returns every key starting with the given prefix together with its value, in key order
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>>;
+
+    /// Returns the number of keys currently present
+    fn len(&mut self) -> Result<usize>;
+
+    /// Returns whether no keys are present
+    fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Makes every write so far durable
+    ///
+    /// The default does nothing, for engines that already flush on every write.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Names the engine a directory was opened with by `KvStoreBuilder::open_engine`.
+const ENGINE_FILE: &str = "engine";
+/// Kept by sled in every directory it opens.
+const SLED_CONF_FILE: &str = "conf";
+
+/// The engines `KvStoreBuilder::open_engine` can open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EngineKind {
+    /// The log-structured `KvStore`.
+    #[default]
+    Kvs,
+    /// The `sled` embedded database, see `SledKvsEngine`.
+    Sled,
+}
+
+impl EngineKind {
+    /// The name the engine is recorded under, as `kvs-server --engine` takes it
+    pub fn name(self) -> &'static str {
+        match self {
+            EngineKind::Kvs => "kvs",
+            EngineKind::Sled => "sled",
+        }
+    }
+
+    /// Picks the engine to open a directory with, refusing one created by a different engine
+    ///
+    /// The engine is read from the marker file `open_engine` leaves, or for a directory without
+    /// one, from the files a `KvStore` or sled keeps. A new directory gets `requested` or `Kvs`.
+    pub(crate) fn choose(path: &Path, requested: Option<EngineKind>) -> Result<EngineKind> {
+        let found = match fs::read_to_string(path.join(ENGINE_FILE)) {
+            Ok(name) => match [EngineKind::Kvs, EngineKind::Sled].into_iter().find(|kind| kind.name() == name.trim()) {
+                Some(kind) => Some(kind),
+                None => {
+                    return Err(KvsError::WrongEngine {
+                        found: name.trim().to_owned(),
+                        requested: requested.unwrap_or_default().name().to_owned(),
+                    })
+                }
+            },
+            Err(_) if path.join(META_FILE).is_file() => Some(EngineKind::Kvs),
+            Err(_) if path.join(SLED_CONF_FILE).is_file() => Some(EngineKind::Sled),
+            Err(_) => None,
+        };
+        match (found, requested) {
+            (Some(found), Some(requested)) if found != requested => {
+                Err(KvsError::WrongEngine { found: found.name().to_owned(), requested: requested.name().to_owned() })
+            }
+            (Some(found), _) => Ok(found),
+            (None, requested) => Ok(requested.unwrap_or_default()),
+        }
+    }
+
+    /// Records the engine in the directory for `choose` to find next time
+    pub(crate) fn record(self, path: &Path) -> Result<()> {
+        fs::write(path.join(ENGINE_FILE), self.name())?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Whichever engine `KvStoreBuilder::open_engine` opened, usable wherever a `KvsEngine` is.
+#[derive(Clone)]
+pub enum AnyEngine {
+    Kvs(KvStore),
+    Sled(SledKvsEngine),
+}
+
+impl AnyEngine {
+    /// Returns which engine this is
+    pub fn kind(&self) -> EngineKind {
+        match self {
+            AnyEngine::Kvs(_) => EngineKind::Kvs,
+            AnyEngine::Sled(_) => EngineKind::Sled,
+        }
+    }
+}
+
+/// Calls the same method on whichever engine is inside
+macro_rules! delegate {
+    ($engine:expr, $inner:ident => $call:expr) => {
+        match $engine {
+            AnyEngine::Kvs($inner) => $call,
+            AnyEngine::Sled($inner) => $call,
+        }
+    };
+}
+
+impl KvsEngine for AnyEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        delegate!(self, engine => KvsEngine::set(engine, key, value))
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        delegate!(self, engine => KvsEngine::get(engine, key))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        delegate!(self, engine => KvsEngine::remove(engine, key))
+    }
+
+    fn set_all(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        delegate!(self, engine => engine.set_all(pairs))
+    }
+
+    fn contains_key(&mut self, key: &str) -> Result<bool> {
+        delegate!(self, engine => KvsEngine::contains_key(engine, key))
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        delegate!(self, engine => KvsEngine::keys(engine))
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        delegate!(self, engine => KvsEngine::scan_prefix(engine, prefix))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        delegate!(self, engine => KvsEngine::len(engine))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        delegate!(self, engine => KvsEngine::flush(engine))
+    }
+}
@@ -0,0 +1,88 @@
+use std::path::PathBuf;
+use sled::Db;
+use crate::{KvsEngine, KvsError, Result};
+
+/// A `KvsEngine` backed by the `sled` embedded database.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvsEngine, Result, SledKvsEngine};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let mut engine = SledKvsEngine::open(temp_dir.path())?;
+/// engine.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+}
+
+impl SledKvsEngine {
+    /// Opens or creates a sled database in the given directory
+    pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        let db = sled::open(path.into())?;
+        Ok(SledKvsEngine { db })
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.db.insert(key, value.into_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.db
+            .get(key)?
+            .map(|value| String::from_utf8(value.to_vec()))
+            .transpose()?)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.db.remove(&key)?.ok_or(KvsError::KeyNotFound { key })?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn set_all(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.into_bytes());
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn contains_key(&mut self, key: &str) -> Result<bool> {
+        Ok(self.db.contains_key(key)?)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        // sled iterates in byte order, which matches `String` ordering
+        self.db
+            .iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.db
+            .scan_prefix(prefix)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((String::from_utf8(key.to_vec())?, String::from_utf8(value.to_vec())?))
+            })
+            .collect()
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(self.db.len())
+    }
+}
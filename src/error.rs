@@ -1,4 +1,8 @@
+#![allow(non_local_definitions)]
+
 use std::io;
+use std::string::FromUtf8Error;
+use std::path::PathBuf;
 use failure::Fail;
 
 /// Error type for kvs.
@@ -10,12 +14,93 @@ pub enum KvsError {
     /// Serialization or deserialization error.
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
-    #[fail(display = "Key not found")]
-    KeyNotFound,
-    #[fail(display = "Reader not found")]
-    ReaderNotFound,
+    /// Log record encoding or decoding error.
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
+    /// Sled error.
+    #[fail(display = "{}", _0)]
+    Sled(#[cause] sled::Error),
+    /// A stored value was not valid UTF-8.
+    #[fail(display = "{}", _0)]
+    Utf8(#[cause] FromUtf8Error),
+    /// The key does not exist, or has expired.
+    #[fail(display = "Key not found: {}", key)]
+    KeyNotFound { key: String },
+    /// No reader is open for the generation a section points at, or it has no log on disk.
+    #[fail(display = "Reader not found for generation {}", gen)]
+    ReaderNotFound { gen: u64 },
+    /// A record or response was not of the kind expected.
     #[fail(display = "Unexpected Command Type")]
     UnexpectedCommandType,
+    /// The store directory could not be created on first open.
+    #[fail(display = "Unable to create store directory {:?}: {}", path, cause)]
+    CreateDir {
+        path: PathBuf,
+        #[cause] cause: io::Error,
+    },
+    /// An existing log could not be replayed on reopen, or the index pointed at a remove record.
+    #[fail(display = "Corrupt log in generation {} at offset {}", gen, offset)]
+    Corrupt { gen: u64, offset: u64 },
+    /// A log record failed its checksum, for example after a partial write or bit rot.
+    #[fail(display = "Checksum mismatch for record in generation {} at offset {}", gen, offset)]
+    CorruptRecord { gen: u64, offset: u64 },
+    /// A log's header names a format version this version cannot read.
+    #[fail(display = "Log for generation {} has format version {}, expected {}", gen, found, expected)]
+    UnsupportedFormat { gen: u64, found: u32, expected: u32 },
+    /// A log has no format header, such as the old JSON logs, so its records cannot be trusted.
+    #[fail(display = "Log for generation {} has no format header and was not written by this version of kvs", gen)]
+    UnrecognizedLog { gen: u64 },
+    /// The directory was created by a different engine than the one requested.
+    #[fail(display = "Directory was created by the {} engine, not {}", found, requested)]
+    WrongEngine { found: String, requested: String },
+    /// The server failed to apply a request.
+    #[fail(display = "Server error: {}", message)]
+    Server { message: String },
+    /// The client and server speak different versions of the protocol.
+    #[fail(display = "Protocol version mismatch: client speaks {} but server speaks {}", client, server)]
+    ProtocolVersionMismatch { client: u32, server: u32 },
+    /// The server's queue was full, so it turned the request away without applying it.
+    #[fail(display = "Server is busy, try again later")]
+    ServerBusy,
+    /// A key was set with no characters.
+    #[fail(display = "Key must not be empty")]
+    EmptyKey,
+    /// A key was longer than the store's `max_key_size`.
+    #[fail(display = "Key of {} bytes is over the limit of {} bytes", len, max)]
+    KeyTooLong { len: usize, max: usize },
+    /// A value was longer than the store's `max_value_size`.
+    #[fail(display = "Value of {} bytes for key {} is over the limit of {} bytes", len, key, max)]
+    ValueTooLong { key: String, len: u64, max: u64 },
+    /// A large value was vetoed by the large value hook.
+    #[fail(display = "Value of {} bytes for key {} was rejected", size, key)]
+    LargeValueRejected { key: String, size: usize },
+    /// The destination directory of a `compact_into` already holds a store.
+    #[fail(display = "Destination {:?} already contains logs", path)]
+    DestinationNotEmpty { path: PathBuf },
+    /// The requested historical version is no longer on disk.
+    #[fail(display = "Version {} no longer exists", version)]
+    VersionNotFound { version: u64 },
+    /// A value or record could not be decrypted, usually because the wrong key was supplied.
+    #[fail(display = "Unable to decrypt stored data")]
+    DecryptionFailed,
+    /// The value being incremented is not an integer, or the result would overflow.
+    #[fail(display = "Value for key {} is not an integer", key)]
+    NotAnInteger { key: String },
+    /// A value was streamed into a store whose value hooks or record encryption need the whole value at once.
+    #[fail(display = "Streamed values cannot be used with value hooks or record encryption")]
+    StreamingUnsupported,
+    /// The store directory is already open for writing, or open for reading when a writer asked.
+    #[fail(display = "Store {:?} is locked by another open", path)]
+    Locked { path: PathBuf },
+    /// A write was attempted on a store opened read-only.
+    #[fail(display = "Store is read-only")]
+    ReadOnly,
+    /// A namespace name was empty or contained the NUL separator.
+    #[fail(display = "Invalid namespace name {:?}", name)]
+    InvalidNamespace { name: String },
+    /// A server was still serving requests when its drain timeout ran out.
+    #[fail(display = "Requests were still in flight when the shutdown timeout ran out")]
+    ShutdownTimedOut,
 }
 
 impl From<io::Error> for KvsError {
@@ -28,4 +113,22 @@ impl From<serde_json::Error> for KvsError {
     fn from(err: serde_json::Error) -> KvsError {
         KvsError::Serde(err)
     }
-}
\ No newline at end of file
+}
+
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> KvsError {
+        KvsError::Sled(err)
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> KvsError {
+        KvsError::Utf8(err)
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{KvStore, KvsError, Result};
+
+/// A handle onto one key space of a `KvStore`.
+///
+/// Every key is stored as the namespace name, a NUL byte and then the key,
+/// in the same logs as the rest of the store. Namespace names cannot
+/// contain NUL, so keys from two namespaces never collide. Plain access to
+/// the store sees namespaced keys with their prefix.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// let sessions = store.namespace("sessions")?;
+/// let cache = store.namespace("cache")?;
+/// sessions.set("id".to_owned(), "abc".to_owned())?;
+/// assert_eq!(cache.get("id".to_owned())?, None);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Namespace {
+    store: KvStore,
+    prefix: String,
+}
+
+impl Namespace {
+    pub(crate) fn new(store: KvStore, name: &str) -> Result<Namespace> {
+        if name.is_empty() || name.contains('\0') {
+            return Err(KvsError::InvalidNamespace { name: name.to_owned() });
+        }
+        Ok(Namespace { store, prefix: format!("{}\0", name) })
+    }
+
+    /// Returns the name this namespace was opened with
+    pub fn name(&self) -> &str {
+        &self.prefix[..self.prefix.len() - 1]
+    }
+
+    /// Sets the value of a key in this namespace
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set(self.full_key(&key), value)
+    }
+
+    /// Gets the value of a key in this namespace, or `None` if it does not exist
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(self.full_key(&key))
+    }
+
+    /// Removes a key in this namespace, returning `KvsError::KeyNotFound` if it does not exist
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(self.full_key(&key)).map_err(|err| match err {
+            KvsError::KeyNotFound { .. } => KvsError::KeyNotFound { key },
+            err => err,
+        })
+    }
+
+    /// Returns every live key in this namespace in sorted order, without the namespace prefix
+    pub fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.store
+            .keys_with_prefix(&self.prefix)?
+            .into_iter()
+            .map(|key| key[self.prefix.len()..].to_owned())
+            .collect())
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
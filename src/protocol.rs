@@ -0,0 +1,92 @@
+use std::io::{Read, Write};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::Result;
+
+/// The version of this protocol, which a client and server have to agree on.
+///
+/// Each side only speaks its own version so far, so any difference is a mismatch.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The longest message body either side sends or accepts, twice the store's largest value by default.
+pub const MAX_MESSAGE_LEN: u32 = 128 * 1024 * 1024;
+
+/// The first message each side sends on a connection, naming the protocol version it speaks.
+///
+/// A client sends its handshake followed by its `Request` without waiting. The server reads
+/// both, replies with its own handshake, and then with a `Response` only if the versions match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Handshake {
+    pub version: u32,
+}
+
+/// A request sent from a client to the server.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Request {
+    Get { key: String },
+    Set { key: String, value: String },
+    Remove { key: String },
+    /// Sets every pair at once, so either all of them are set or none are.
+    SetAll { pairs: Vec<(String, String)> },
+    /// Applies `request` at most once for `token`, however many times it is resent.
+    ///
+    /// A server that has already applied a request with this token answers with the response
+    /// it gave then, as long as the token is still among the last ones it remembers, see
+    /// `KvsServer::token_window`. A request that failed is not remembered, so resending it
+    /// retries. Only the outermost token counts if these are nested.
+    Once { token: Uuid, request: Box<Request> },
+}
+
+/// The server's reply to a single `Request`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Response {
+    /// The value of a `Get`, `None` if the key does not exist.
+    Value(Option<String>),
+    /// A `Set`, `Remove` or `SetAll` succeeded.
+    Ok,
+    /// The request failed, with the error message.
+    Err(String),
+    /// The server had no room to queue the request, so it was not applied.
+    Busy,
+}
+
+/// Writes a message as a 4-byte big-endian length followed by its JSON body, then flushes
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    write_frame(writer, message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a message framed as `write_message` does, leaving it to the caller to flush
+pub(crate) fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let body = serde_json::to_vec(message)?;
+    let length = u32::try_from(body.len())
+        .ok()
+        .filter(|&length| length <= MAX_MESSAGE_LEN)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "message too large"))?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+/// Reads a message written by `write_message`
+///
+/// The whole frame is read before deserializing, so messages split across
+/// several socket reads are reassembled. A length over `MAX_MESSAGE_LEN` is an
+/// `InvalidData` error, and the body buffer only grows as bytes arrive, so a
+/// peer cannot make the reader allocate more than it actually sends.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T> {
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length);
+    if length > MAX_MESSAGE_LEN {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "message too large").into());
+    }
+    let mut body = Vec::new();
+    reader.take(u64::from(length)).read_to_end(&mut body)?;
+    if body.len() != length as usize {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
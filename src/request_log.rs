@@ -0,0 +1,121 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::protocol::{read_message, write_frame, Request, Response};
+use crate::server::{apply, SeenTokens, DEFAULT_TOKEN_WINDOW};
+use crate::{KvsEngine, KvsError, Result};
+
+/// How long a recorded request may sit in the buffer before it is written out.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An append-only trail of the requests a server received, for replaying against another store.
+///
+/// Each request is framed the same way as on the wire, wrapped in a `LoggedRequest` with the
+/// time it arrived. Recording only appends to a buffer, which a background thread flushes
+/// every 100ms and which is flushed once more when the last clone is dropped. Requests still
+/// buffered when the process dies are lost, and a frame cut off part way is ignored by `replay`.
+#[derive(Clone)]
+pub struct RequestLog {
+    inner: Arc<LogInner>,
+}
+
+struct LogInner {
+    writer: Mutex<BufWriter<File>>,
+    /// Dropped with the last clone, which wakes the flusher so it can exit.
+    _stop: Sender<()>,
+}
+
+/// One request read back from a `RequestLog`.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LoggedRequest {
+    /// When the server received the request, in milliseconds since the unix epoch.
+    pub at: u64,
+    pub request: Request,
+}
+
+/// What `replay` applied, with every request that failed.
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub replayed: usize,
+    pub failures: Vec<ReplayFailure>,
+}
+
+/// A replayed request whose response was an error.
+#[derive(Debug)]
+pub struct ReplayFailure {
+    /// Position of the request in the log, counting from 0.
+    pub index: usize,
+    pub logged: LoggedRequest,
+    pub error: String,
+}
+
+impl RequestLog {
+    /// Opens a request log for appending, creating it if it does not exist
+    pub fn open(path: impl AsRef<Path>) -> Result<RequestLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (stop, stopped) = mpsc::channel::<()>();
+        let inner = Arc::new(LogInner { writer: Mutex::new(BufWriter::new(file)), _stop: stop });
+        let flushed: Weak<LogInner> = Arc::downgrade(&inner);
+        thread::Builder::new().name("kvs-request-log".to_owned()).spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(FLUSH_INTERVAL) {
+                match flushed.upgrade() {
+                    Some(inner) => {
+                        if let Err(err) = inner.writer.lock().unwrap().flush() {
+                            eprintln!("Error flushing request log: {}", err);
+                        }
+                    }
+                    None => return,
+                }
+            }
+        })?;
+        Ok(RequestLog { inner })
+    }
+
+    /// Appends a request stamped with the current time, without waiting for it to reach the file
+    pub fn record(&self, request: &Request) -> Result<()> {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+        write_frame(&mut *self.inner.writer.lock().unwrap(), &LoggedEntry { at, request })
+    }
+
+    /// Writes out every buffered request
+    pub fn flush(&self) -> Result<()> {
+        self.inner.writer.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+/// The borrowed form of `LoggedRequest` that is written.
+#[derive(Serialize)]
+struct LoggedEntry<'a> {
+    at: u64,
+    request: &'a Request,
+}
+
+/// Applies every request in a log to an engine in order, reporting those that fail
+///
+/// Failed requests do not stop the replay. It ends at the end of the log, or at a frame
+/// that was cut off part way, and only returns an error if the log cannot be read.
+/// Resent `Request::Once` requests are applied once, remembering as many tokens as a
+/// server does by default.
+pub fn replay<E: KvsEngine>(engine: &mut E, log: impl Read) -> Result<ReplayReport> {
+    let mut reader = BufReader::new(log);
+    let mut report = ReplayReport::default();
+    let seen = SeenTokens::new(DEFAULT_TOKEN_WINDOW);
+    loop {
+        let logged: LoggedRequest = match read_message(&mut reader) {
+            Ok(logged) => logged,
+            Err(KvsError::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => return Ok(report),
+            Err(err) => return Err(err),
+        };
+        let index = report.replayed;
+        report.replayed += 1;
+        if let Response::Err(error) = apply(engine, &seen, logged.request.clone()) {
+            report.failures.push(ReplayFailure { index, logged, error });
+        }
+    }
+}
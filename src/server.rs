@@ -0,0 +1,289 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+use crate::protocol::{read_message, write_frame, write_message, Handshake, Request, Response, PROTOCOL_VERSION};
+use crate::{KvsEngine, KvsError, RequestLog, Result, ThreadPool};
+
+/// How long a shut down server waits for requests in flight unless `KvsServer::drain_timeout` says otherwise.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a connection turned away as busy is given to send its request before the reply.
+const BUSY_READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How many `Request::Once` tokens a server remembers unless `KvsServer::token_window` says otherwise.
+pub(crate) const DEFAULT_TOKEN_WINDOW: usize = 1024;
+
+/// Serves `Request`s over TCP against a `KvsEngine`, one request per connection.
+///
+/// Connections are accepted on the calling thread and handled on the pool,
+/// each against its own clone of the engine. A connection the pool has no room
+/// to queue is answered with `Response::Busy` straight away, so the queue bound
+/// set by the pool, see `SharedQueueThreadPool::with_queue_len`, caps how much
+/// work can pile up.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+    request_log: Option<RequestLog>,
+    shutdown: ShutdownHandle,
+    drain_timeout: Duration,
+    seen: Arc<SeenTokens>,
+}
+
+/// Stops a `KvsServer` from another thread, such as a signal handler.
+///
+/// Clones all stop the same server.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    /// Where the server is accepting connections, once it has started.
+    addr: Mutex<Option<SocketAddr>>,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to stop accepting connections, returning without waiting for it
+    pub fn shutdown(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        // The accept loop only looks at the flag between connections, so it is woken with one
+        if let Some(addr) = *self.inner.addr.lock().unwrap() {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    /// Returns whether `shutdown` has been called
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Records the address to wake the accept loop on, reachable even if it listens on every interface
+    fn listening_on(&self, mut addr: SocketAddr) {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        *self.inner.addr.lock().unwrap() = Some(addr);
+    }
+}
+
+impl<E: KvsEngine + Clone + Send + 'static, P: ThreadPool> KvsServer<E, P> {
+    /// Creates a server backed by the given engine and thread pool
+    pub fn new(engine: E, pool: P) -> KvsServer<E, P> {
+        KvsServer {
+            engine,
+            pool,
+            request_log: None,
+            shutdown: ShutdownHandle::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            seen: Arc::new(SeenTokens::new(DEFAULT_TOKEN_WINDOW)),
+        }
+    }
+
+    /// Sets how long a shut down server waits for requests in flight, 30 seconds by default
+    pub fn drain_timeout(mut self, timeout: Duration) -> KvsServer<E, P> {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Sets how many of the latest `Request::Once` tokens are remembered, 1024 by default
+    ///
+    /// A request resent with a token that has since been forgotten is applied again.
+    pub fn token_window(mut self, tokens: usize) -> KvsServer<E, P> {
+        self.seen = Arc::new(SeenTokens::new(tokens));
+        self
+    }
+
+    /// Returns a handle that makes `run` or `serve` stop accepting connections and return
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Records every request received to the given log before it is applied
+    pub fn request_log(mut self, request_log: RequestLog) -> KvsServer<E, P> {
+        self.request_log = Some(request_log);
+        self
+    }
+
+    /// Binds to the given address and serves connections until shut down
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves connections accepted by an already bound listener until shut down
+    ///
+    /// Once `ShutdownHandle::shutdown` is called no more connections are accepted. Requests
+    /// already accepted are served, the request log and engine are flushed, and then this
+    /// returns. Returns `KvsError::ShutdownTimedOut` if requests are still being served once
+    /// the drain timeout runs out, leaving them running.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        let KvsServer { mut engine, pool, request_log, shutdown, drain_timeout, seen } = self;
+        shutdown.listening_on(listener.local_addr()?);
+        for stream in listener.incoming() {
+            if shutdown.is_requested() {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let stream = Arc::new(stream);
+                    let engine = engine.clone();
+                    let request_log = request_log.clone();
+                    let seen = Arc::clone(&seen);
+                    let served = Arc::clone(&stream);
+                    let queued = pool.try_spawn(move || {
+                        if let Err(err) = handle(engine, request_log, &seen, &served) {
+                            eprintln!("Error serving client: {}", err);
+                        }
+                    });
+                    if !queued {
+                        if let Err(err) = reject_busy(&stream) {
+                            eprintln!("Error turning away client: {}", err);
+                        }
+                    }
+                }
+                Err(err) => eprintln!("Connection failed: {}", err),
+            }
+        }
+        drop(listener);
+
+        let drained = pool.shutdown(drain_timeout);
+        if let Some(request_log) = &request_log {
+            request_log.flush()?;
+        }
+        engine.flush()?;
+        match drained {
+            true => Ok(()),
+            false => Err(KvsError::ShutdownTimedOut),
+        }
+    }
+}
+
+/// Reads a single request from the connection, applies it and writes back the response
+///
+/// Returns `KvsError::ProtocolVersionMismatch` once the server's handshake is sent if the
+/// client speaks another version, leaving its request unapplied.
+fn handle<E: KvsEngine>(mut engine: E, request_log: Option<RequestLog>, seen: &SeenTokens, stream: &TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut writer = BufWriter::new(stream);
+    let handshake: Handshake = read_message(&mut reader)?;
+    // Read whatever the version, as closing with it unread could reset the connection under the reply
+    let request: serde_json::Value = read_message(&mut reader)?;
+    write_frame(&mut writer, &Handshake { version: PROTOCOL_VERSION })?;
+    if handshake.version != PROTOCOL_VERSION {
+        writer.flush()?;
+        return Err(KvsError::ProtocolVersionMismatch { client: handshake.version, server: PROTOCOL_VERSION });
+    }
+    let request: Request = serde_json::from_value(request)?;
+    if let Some(request_log) = request_log {
+        // Losing a trail entry is no reason to fail the request itself
+        if let Err(err) = request_log.record(&request) {
+            eprintln!("Error recording request: {}", err);
+        }
+    }
+
+    write_message(&mut writer, &apply(&mut engine, seen, request))
+}
+
+/// Answers a connection the pool had no room for with `Response::Busy`, without applying its request
+///
+/// The handshake and request are still read, for up to `BUSY_READ_TIMEOUT`, as closing a
+/// socket with unread data resets the connection and the client could lose the reply.
+fn reject_busy(stream: &TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(BUSY_READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let _: Result<Handshake> = read_message(&mut reader);
+    let _: Result<serde_json::Value> = read_message(&mut reader);
+    let mut writer = BufWriter::new(stream);
+    write_frame(&mut writer, &Handshake { version: PROTOCOL_VERSION })?;
+    write_message(&mut writer, &Response::Busy)
+}
+
+/// Applies a request to an engine, turning any error into an error response
+pub(crate) fn apply<E: KvsEngine>(engine: &mut E, seen: &SeenTokens, request: Request) -> Response {
+    let response = match request {
+        Request::Once { token, mut request } => {
+            while let Request::Once { request: inner, .. } = *request {
+                request = inner;
+            }
+            return seen.once(token, || apply(engine, seen, *request));
+        }
+        Request::Get { key } => engine.get(key).map(Response::Value),
+        Request::Set { key, value } => engine.set(key, value).map(|_| Response::Ok),
+        Request::Remove { key } => engine.remove(key).map(|_| Response::Ok),
+        Request::SetAll { pairs } => engine.set_all(pairs).map(|_| Response::Ok),
+    };
+    response.unwrap_or_else(|err| Response::Err(err.to_string()))
+}
+
+/// The response to one `Request::Once` token, `None` until an attempt succeeds.
+///
+/// Locked for as long as an attempt runs, so a resend waits for it instead of applying the request again.
+type TokenSlot = Arc<Mutex<Option<Response>>>;
+
+/// The responses given to the latest `Request::Once` tokens, forgetting the oldest first.
+pub(crate) struct SeenTokens {
+    window: usize,
+    seen: Mutex<(HashMap<Uuid, TokenSlot>, VecDeque<Uuid>)>,
+}
+
+impl SeenTokens {
+    pub(crate) fn new(window: usize) -> SeenTokens {
+        SeenTokens { window, seen: Mutex::new((HashMap::new(), VecDeque::new())) }
+    }
+
+    /// Returns the response remembered for a token, or runs `apply` and remembers its response
+    ///
+    /// Only the token's own slot stays locked while `apply` runs, so a resend that arrives
+    /// before the first attempt has finished waits for its response, while requests with other
+    /// tokens go ahead. Error responses are not remembered, so a resend after a failure is
+    /// applied again.
+    fn once(&self, token: Uuid, apply: impl FnOnce() -> Response) -> Response {
+        if self.window == 0 {
+            return apply();
+        }
+        let slot = {
+            let mut seen = self.seen.lock().unwrap();
+            let (slots, order) = &mut *seen;
+            match slots.get(&token) {
+                Some(slot) => Arc::clone(slot),
+                None => {
+                    if order.len() == self.window {
+                        if let Some(oldest) = order.pop_front() {
+                            slots.remove(&oldest);
+                        }
+                    }
+                    order.push_back(token);
+                    Arc::clone(slots.entry(token).or_default())
+                }
+            }
+        };
+        let mut response = slot.lock().unwrap();
+        if let Some(response) = &*response {
+            return response.clone();
+        }
+        match apply() {
+            Response::Err(err) => {
+                // Forgotten unless a resend waiting on the slot has taken it over
+                let mut seen = self.seen.lock().unwrap();
+                let (slots, order) = &mut *seen;
+                if Arc::strong_count(&slot) == 2 && slots.get(&token).map_or(false, |held| Arc::ptr_eq(held, &slot)) {
+                    slots.remove(&token);
+                    order.retain(|&held| held != token);
+                }
+                Response::Err(err)
+            }
+            applied => {
+                *response = Some(applied.clone());
+                applied
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+use crate::Result;
+
+pub mod shared_queue;
+
+/// A pool of threads that runs submitted jobs.
+pub trait ThreadPool {
+    /// Creates a pool with the given number of threads, failing if any cannot be spawned
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs the job on one of the pool's threads
+    ///
+    /// A panicking job does not reduce the number of threads in the pool.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+
+    /// Runs the job on one of the pool's threads if there is room to queue it, returning whether there was
+    ///
+    /// A pool that never runs out of room can rely on the default, which always spawns it.
+    fn try_spawn<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        true
+    }
+
+    /// Stops taking jobs and waits up to `timeout` for the queued and running ones to finish
+    ///
+    /// Returns whether they all finished in time. Jobs still running after that are left to run.
+    fn shutdown(self, timeout: Duration) -> bool
+    where
+        Self: Sized;
+}
@@ -0,0 +1,124 @@
+use std::io;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::thread_pool::ThreadPool;
+use crate::Result;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A `ThreadPool` whose workers take jobs from one shared, bounded queue.
+///
+/// The queue holds as many jobs as there are threads unless `with_queue_len` says otherwise.
+/// Submitting with `spawn` blocks while the queue is full, and `try_spawn` turns the job away.
+/// Dropping the pool lets the workers finish the queued jobs and exit, `shutdown` also waits for them.
+pub struct SharedQueueThreadPool {
+    sender: SyncSender<Job>,
+    running: Arc<Running>,
+}
+
+/// Counts the worker threads still alive, so `shutdown` can wait for them to exit.
+#[derive(Default)]
+struct Running {
+    workers: Mutex<usize>,
+    exited: Condvar,
+}
+
+impl SharedQueueThreadPool {
+    /// Creates a pool with the given number of threads whose queue holds up to `queue_len` jobs waiting for one
+    ///
+    /// With a `queue_len` of 0 a job is only taken while a thread is idle.
+    pub fn with_queue_len(threads: u32, queue_len: usize) -> Result<Self> {
+        if threads == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "thread pool needs at least one thread").into());
+        }
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_len);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let running = Arc::new(Running::default());
+        for _ in 0..threads {
+            spawn_worker(Worker { receiver: Arc::clone(&receiver), running: Arc::clone(&running) })?;
+        }
+        Ok(SharedQueueThreadPool { sender, running })
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        SharedQueueThreadPool::with_queue_len(threads, threads as usize)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Workers only go away once the pool itself is dropped
+        self.sender
+            .send(Box::new(job))
+            .expect("thread pool has no workers");
+    }
+
+    fn try_spawn<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender.try_send(Box::new(job)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Disconnected(_)) => panic!("thread pool has no workers"),
+        }
+    }
+
+    fn shutdown(self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        // Workers exit once the queue is empty and the sender is gone
+        drop(self.sender);
+        let mut workers = self.running.workers.lock().unwrap();
+        while *workers > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            workers = self.running.exited.wait_timeout(workers, left).unwrap().0;
+        }
+        true
+    }
+}
+
+/// Runs jobs from the shared queue, replacing itself if a job panics.
+struct Worker {
+    receiver: Arc<Mutex<Receiver<Job>>>,
+    running: Arc<Running>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let worker = Worker { receiver: Arc::clone(&self.receiver), running: Arc::clone(&self.running) };
+            if let Err(err) = spawn_worker(worker) {
+                eprintln!("Failed to replace a panicked worker: {}", err);
+            }
+        }
+        // Any replacement is counted first, so the count never touches 0 while jobs remain
+        *self.running.workers.lock().unwrap() -= 1;
+        self.running.exited.notify_all();
+    }
+}
+
+fn spawn_worker(worker: Worker) -> Result<()> {
+    // Dropping the worker uncounts it again, including when its thread never starts
+    *worker.running.workers.lock().unwrap() += 1;
+    thread::Builder::new().spawn(move || run_jobs(worker))?;
+    Ok(())
+}
+
+fn run_jobs(worker: Worker) {
+    loop {
+        // The lock guard is a temporary, so it is released before the job runs
+        let job = worker.receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
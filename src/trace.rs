@@ -0,0 +1,66 @@
+//! Events emitted through `tracing` when the `tracing` feature is enabled.
+//!
+//! Without the feature every macro expands to nothing, so its arguments are never evaluated.
+
+#[cfg(feature = "tracing")]
+use crate::KvsError;
+use crate::Result;
+
+#[cfg(feature = "tracing")]
+macro_rules! debug {
+    ($($arg:tt)*) => { tracing::debug!($($arg)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! info {
+    ($($arg:tt)*) => { tracing::info!($($arg)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! info {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! warn {
+    ($($arg:tt)*) => { tracing::warn!($($arg)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {};
+}
+
+/// Passes a result through, warning about any failure other than a missing key
+#[cfg(feature = "tracing")]
+pub(crate) fn warn_if_failed<T>(op: &'static str, result: Result<T>) -> Result<T> {
+    match &result {
+        Err(KvsError::KeyNotFound { .. }) | Ok(_) => {}
+        Err(err) => tracing::warn!(op, error = %err, "operation failed"),
+    }
+    result
+}
+
+/// Passes a result through, reporting any failure as an error
+#[cfg(feature = "tracing")]
+pub(crate) fn error_if_failed<T>(op: &'static str, result: Result<T>) -> Result<T> {
+    if let Err(err) = &result {
+        tracing::error!(op, error = %err, "operation failed");
+    }
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn warn_if_failed<T>(_op: &'static str, result: Result<T>) -> Result<T> {
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn error_if_failed<T>(_op: &'static str, result: Result<T>) -> Result<T> {
+    result
+}
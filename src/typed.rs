@@ -0,0 +1,74 @@
+use std::marker::PhantomData;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::{KvStore, Result};
+
+/// A view of a `KvStore` that stores any serde types as keys and values.
+///
+/// Keys and values are encoded as JSON before they reach the store, so the
+/// log format, compaction and value hooks are unchanged. A typed view shares
+/// its store with every other view and with plain `String` access, but a
+/// typed `String` key is stored quoted and never matches the plain key.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// let scores = store.typed::<u32, Vec<u8>>();
+/// scores.set(&7, &vec![1, 2, 3])?;
+/// assert_eq!(scores.get(&7)?, Some(vec![1, 2, 3]));
+/// # Ok(())
+/// # }
+/// ```
+pub struct TypedKvStore<K, V> {
+    store: KvStore,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Clone for TypedKvStore<K, V> {
+    fn clone(&self) -> Self {
+        TypedKvStore { store: self.store.clone(), types: PhantomData }
+    }
+}
+
+impl<K, V> TypedKvStore<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    pub(crate) fn new(store: KvStore) -> Self {
+        TypedKvStore { store, types: PhantomData }
+    }
+
+    /// Sets the value of a key, replacing any previous value
+    pub fn set(&self, key: &K, value: &V) -> Result<()> {
+        self.store.set(serde_json::to_string(key)?, serde_json::to_string(value)?)
+    }
+
+    /// Gets the value of a key, or `None` if it does not exist
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.store.get(serde_json::to_string(key)?)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes a key, returning `KvsError::KeyNotFound` if it does not exist
+    pub fn remove(&self, key: &K) -> Result<()> {
+        self.store.remove(serde_json::to_string(key)?)
+    }
+
+    /// Returns every key that decodes as `K`, ordered by its encoded form
+    ///
+    /// Keys written through other views or as plain strings are skipped when they do not decode.
+    pub fn keys(&self) -> Result<Vec<K>> {
+        Ok(self.store
+            .keys()?
+            .iter()
+            .filter_map(|key| serde_json::from_str(key).ok())
+            .collect())
+    }
+}
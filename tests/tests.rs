@@ -1,8 +1,18 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::protocol::{read_message, write_message, Handshake, Request, Response, MAX_MESSAGE_LEN, PROTOCOL_VERSION};
+use kvs::{
+    ChangeEvent, EngineKind, KvStore, KvsEngine, KvsError, KvsServer, RequestLog, Result, SharedQueueThreadPool, SledKvsEngine,
+    SyncMode, ThreadPool,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +27,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +38,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +51,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +64,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -65,14 +75,14 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +90,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -94,13 +104,13 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +118,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +131,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +146,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +167,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,16 +182,121 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
 
+// `kvs compact` should drop overwritten records and report how much it reclaimed.
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let before = store.stats()?.disk_bytes;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("({} -> ", before)));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.stats()?.disk_bytes < before);
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// `--format json` should print one JSON document per command, including for misses.
+#[test]
+fn cli_json_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).args(["--format", "json"]).current_dir(&temp_dir);
+        cmd.assert()
+    };
+
+    kvs(&["set", "key1", "value1"]).success().stdout(eq(r#"{"set":true}"#).trim());
+    kvs(&["get", "key1"]).success().stdout(eq(r#"{"key":"key1","value":"value1"}"#).trim());
+    kvs(&["get", "key2"]).success().stdout(eq(r#"{"key":"key2","value":null}"#).trim());
+    kvs(&["keys"]).success().stdout(eq(r#"["key1"]"#).trim());
+    kvs(&["scan", "key"]).success().stdout(eq(r#"[{"key":"key1","value":"value1"}]"#).trim());
+    kvs(&["len"]).success().stdout(eq(r#"{"len":1}"#).trim());
+    kvs(&["exists", "key1"]).success().stdout(eq(r#"{"key":"key1","exists":true}"#).trim());
+    kvs(&["rm", "key1"]).success().stdout(eq(r#"{"removed":true}"#).trim());
+    kvs(&["rm", "key1"]).failure().stdout(eq(r#"{"removed":false}"#).trim());
+    kvs(&["stats"]).success().stdout(contains(r#""live_keys":0"#));
+    Ok(())
+}
+
+// `--path` should pick the store directory, ahead of `KVS_PATH` and then the current directory.
+#[test]
+fn cli_path_precedence() -> Result<()> {
+    let flag_dir = TempDir::new().expect("unable to create temporary working directory");
+    let env_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cwd_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(flag_dir.path())?.set("key".to_owned(), "flag".to_owned())?;
+    KvStore::open(env_dir.path())?.set("key".to_owned(), "env".to_owned())?;
+    KvStore::open(cwd_dir.path())?.set("key".to_owned(), "cwd".to_owned())?;
+
+    let get = |path: Option<&std::path::Path>, env: Option<&std::path::Path>| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(["get", "key"]).current_dir(&cwd_dir).env_remove("KVS_PATH");
+        if let Some(path) = path {
+            cmd.arg("--path").arg(path);
+        }
+        if let Some(env) = env {
+            cmd.env("KVS_PATH", env);
+        }
+        cmd.assert().success()
+    };
+    get(Some(flag_dir.path()), Some(env_dir.path())).stdout(eq("flag").trim());
+    get(None, Some(env_dir.path())).stdout(eq("env").trim());
+    get(None, None).stdout(eq("cwd").trim());
+    Ok(())
+}
+
+/// Splits a log into its record bodies, asserting that the records cover the whole file
+///
+/// Logs are an 8-byte magic and version header followed by records of length, checksum, body and a newline.
+fn log_record_bodies(path: &std::path::Path) -> Vec<Vec<u8>> {
+    let contents = std::fs::read(path).unwrap();
+    let mut bodies = Vec::new();
+    let mut rest = contents.get(8..).unwrap_or_default();
+    while !rest.is_empty() {
+        let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let (body, trailer) = rest[8..].split_at(length);
+        assert_eq!(trailer[0], b'\n');
+        bodies.push(body.to_vec());
+        rest = &trailer[1..];
+    }
+    bodies
+}
+
+/// Frames a record body the way the store writes it
+fn log_frame(body: &[u8]) -> Vec<u8> {
+    let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&crc32fast::hash(body).to_be_bytes());
+    frame.extend_from_slice(body);
+    frame.push(b'\n');
+    frame
+}
+
+fn bytes_contain(haystack: &[u8], needle: &str) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle.as_bytes())
+}
+
 // Should get previously stored value.
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -191,7 +306,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -202,7 +317,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn get_stored_value_memory() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -217,7 +332,7 @@ fn get_stored_value_memory() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     // let temp_dir_path = current_dir()?;
     // let mut store = KvStore::open(&temp_dir_path)?;
 
@@ -228,7 +343,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     // let mut store = KvStore::open(temp_dir_path)?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
@@ -241,14 +356,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -257,7 +372,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -265,7 +380,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -277,7 +392,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -307,7 +422,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -317,3 +432,3769 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Opening a store where the directory can't be created should report `CreateDir`.
+#[test]
+fn open_create_dir_failure() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_path = temp_dir.path().join("not-a-dir");
+    std::fs::write(&file_path, b"").expect("unable to create file");
+
+    match KvStore::open(file_path.join("store")) {
+        Err(KvsError::CreateDir { path, .. }) => assert_eq!(path, file_path.join("store")),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("open should fail when the directory can't be created"),
+    }
+}
+
+// Reopening a store with an unparseable record before the end of a log should report `Corrupt`.
+#[test]
+fn open_corrupt_log_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_len = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    std::io::Write::write_all(&mut log, &log_frame(b"not a command"))?;
+    std::io::Write::write_all(&mut log, &log_frame(&bincode::serialize(&kvs::Command::Remove { key: "key1".to_owned(), seq: 0 }).unwrap()))?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corrupt { gen, offset }) => {
+            assert_eq!(gen, 1);
+            assert_eq!(offset, log_len);
+        }
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("open should fail on a corrupt log"),
+    }
+    Ok(())
+}
+
+// The large value hook should fire above the threshold and be able to veto the write.
+#[test]
+fn large_value_hook_vetoes_write() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let fired = Arc::new(AtomicUsize::new(0));
+    let hook_fired = Arc::clone(&fired);
+    store.on_large_value(8, move |key, size| {
+        hook_fired.fetch_add(1, Ordering::SeqCst);
+        key != "rejected" || size < 8
+    });
+
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(fired.load(Ordering::SeqCst), 0);
+
+    store.set("accepted".to_owned(), "a large value".to_owned())?;
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+    assert_eq!(store.get("accepted".to_owned())?, Some("a large value".to_owned()));
+
+    match store.set("rejected".to_owned(), "a large value".to_owned()) {
+        Err(KvsError::LargeValueRejected { key, size }) => {
+            assert_eq!(key, "rejected");
+            assert_eq!(size, 13);
+        }
+        other => panic!("expected the write to be rejected, got {:?}", other),
+    }
+    assert_eq!(fired.load(Ordering::SeqCst), 2);
+    assert_eq!(store.get("rejected".to_owned())?, None);
+    Ok(())
+}
+
+// Opening with a report should skip a corrupt record, keep the rest and describe the corruption.
+#[test]
+fn open_with_report_captures_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let offset = std::fs::metadata(&log_path)?.len();
+    let mut log = std::fs::OpenOptions::new().append(true).open(&log_path)?;
+    // A well framed record whose checksum does not match, followed by a valid record
+    std::io::Write::write_all(&mut log, b"\0\0\0\x03\0\0\0\0\xff\xff\xff\n")?;
+    let valid = bincode::serialize(&kvs::Command::Set {
+        key: "key3".to_owned(),
+        value: "value3".to_owned(),
+        expires_at: None,
+        seq: 0,
+    })
+    .unwrap();
+    std::io::Write::write_all(&mut log, &log_frame(&valid))?;
+    drop(log);
+
+    let (store, report) = KvStore::open_with_report(temp_dir.path())?;
+    assert!(!report.is_healthy());
+    assert_eq!(report.generations, 1);
+    assert_eq!(report.records, 2);
+    assert_eq!(
+        report.corruptions,
+        vec![kvs::Corruption { gen: 1, offset, length: 12 }]
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    drop(store);
+    let (_, report) = KvStore::open_with_report(temp_dir.path())?;
+    assert_eq!(report.generations, 1);
+    assert_eq!(report.corruptions.len(), 1);
+    Ok(())
+}
+
+// Repair should cut each log back to its last good record and count everything after it as dropped.
+#[test]
+fn repair_trims_logs_after_the_first_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let good_len = std::fs::metadata(&log_path)?.len();
+    let mut log = std::fs::OpenOptions::new().append(true).open(&log_path)?;
+    // A record with a bad checksum, a valid record behind it and a torn tail
+    std::io::Write::write_all(&mut log, b"\0\0\0\x03\0\0\0\0\xff\xff\xff\n")?;
+    let valid = bincode::serialize(&kvs::Command::Set {
+        key: "key2".to_owned(),
+        value: "value2".to_owned(),
+        expires_at: None,
+        seq: 0,
+    })
+    .unwrap();
+    std::io::Write::write_all(&mut log, &log_frame(&valid))?;
+    std::io::Write::write_all(&mut log, b"garbage")?;
+    drop(log);
+    let bad_len = std::fs::metadata(&log_path)?.len();
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert!(!report.is_clean());
+    assert_eq!(
+        report.generations,
+        vec![kvs::GenerationRepair { gen: 1, kept: 1, dropped: 3, trimmed_bytes: bad_len - good_len }]
+    );
+    assert_eq!(std::fs::metadata(&log_path)?.len(), good_len);
+
+    let (store, recovery) = KvStore::open_with_report(temp_dir.path())?;
+    assert!(recovery.is_healthy());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+    assert!(KvStore::repair(temp_dir.path())?.is_clean());
+    Ok(())
+}
+
+// `kvs repair` should work on a store whose logs would stop it opening.
+#[test]
+fn cli_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = std::fs::OpenOptions::new().append(true).open(&log_path)?;
+    std::io::Write::write_all(&mut log, b"\0\0\0\x03\0\0\0\0\xff\xff\xff\n")?;
+    drop(log);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("generation 1: kept 1, dropped 1, trimmed 12 bytes").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"[{"gen":1,"kept":1,"dropped":0,"trimmed_bytes":0}]"#).trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Ok(())
+}
+
+// Defragmenting should leave only densely packed live records on disk.
+#[test]
+fn defragment_removes_gaps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let saved = store.defragment()?;
+    assert!(saved > 0);
+
+    let mut live_records = Vec::new();
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() != Some("log".as_ref()) {
+            continue;
+        }
+        // Every byte of the log belongs to a record, with nothing in between
+        live_records.extend(log_record_bodies(&path));
+    }
+    assert_eq!(live_records.len(), 9);
+
+    for key_id in 1..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value9".to_owned()));
+    }
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 1..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value9".to_owned()));
+    }
+    Ok(())
+}
+
+// The history of a key should list every set and remove in order, across reopening.
+#[test]
+fn key_history_is_chronological() -> Result<()> {
+    use kvs::Command as LogCommand;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+
+    let history = store.key_history("key1")?;
+    let commands: Vec<(u64, &LogCommand)> = history.iter().map(|entry| (entry.gen, &entry.command)).collect();
+    assert_eq!(
+        commands,
+        vec![
+            (1, &LogCommand::Set { key: "key1".to_owned(), value: "value1".to_owned(), expires_at: None, seq: 1 }),
+            (1, &LogCommand::Set { key: "key1".to_owned(), value: "value2".to_owned(), expires_at: None, seq: 3 }),
+            (1, &LogCommand::Remove { key: "key1".to_owned(), seq: 4 }),
+        ]
+    );
+    // Offsets start after the 8-byte format header
+    assert_eq!(history[0].offset, 8);
+    assert!(history[1].offset > history[0].offset);
+    assert!(history[2].offset > history[1].offset);
+    assert!(store.key_history("missing")?.is_empty());
+    Ok(())
+}
+
+// The store id should be generated once and survive reopening.
+#[test]
+fn store_id_is_stable_across_reopens() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let id = store.store_id();
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.store_id(), id);
+    drop(store);
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path())?;
+    assert_ne!(other.store_id(), id);
+    Ok(())
+}
+
+// Value hooks should transform what is written to disk but not what is read back.
+#[test]
+fn value_hooks_transform_stored_bytes() -> Result<()> {
+    let xor = |bytes: &[u8]| bytes.iter().map(|byte| byte ^ 0x5a).collect::<Vec<u8>>();
+    let open = |path: &std::path::Path| {
+        KvStore::builder()
+            .value_hooks(xor, move |bytes| Ok(xor(bytes)))
+            .open(path)
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), "secret value".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret value".to_owned()));
+    drop(store);
+
+    let on_disk = std::fs::read(temp_dir.path().join("1.log"))?;
+    assert!(bytes_contain(&on_disk, "key1"));
+    assert!(!bytes_contain(&on_disk, "secret value"));
+
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret value".to_owned()));
+    store.set("key2".to_owned(), "other value".to_owned())?;
+    store.defragment()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret value".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("other value".to_owned()));
+    Ok(())
+}
+
+// Concurrent async writes should all land and be readable through the same handle.
+#[cfg(feature = "async")]
+#[tokio::test(flavor = "multi_thread")]
+async fn async_store_handles_concurrent_calls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = kvs::AsyncKvStore::new(KvStore::open(temp_dir.path())?);
+
+    let tasks: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                store.set(format!("key{}", i), format!("value{}", i)).await?;
+                store.get(format!("key{}", i)).await
+            })
+        })
+        .collect();
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await.unwrap()?, Some(format!("value{}", i)));
+    }
+
+    store.remove("key0".to_owned()).await?;
+    assert_eq!(store.get("key0".to_owned()).await?, None);
+    assert!(matches!(store.remove("key0".to_owned()).await, Err(KvsError::KeyNotFound { .. })));
+    assert_eq!(store.store().len(), 99);
+    Ok(())
+}
+
+// Encrypted values should round-trip with the right key and fail to decrypt with any other.
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_requires_the_right_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().encryption_key([7; 32]).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "secret value".to_owned())?;
+    drop(store);
+
+    let on_disk = std::fs::read(temp_dir.path().join("1.log"))?;
+    assert!(!bytes_contain(&on_disk, "secret value"));
+
+    let store = KvStore::builder().encryption_key([9; 32]).open(temp_dir.path())?;
+    match store.get("key1".to_owned()) {
+        Err(KvsError::DecryptionFailed) => {}
+        other => panic!("expected decryption to fail, got {:?}", other),
+    }
+    drop(store);
+
+    let store = KvStore::builder().encryption_key([7; 32]).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret value".to_owned()));
+    Ok(())
+}
+
+// Record encryption should hide keys as well as values, survive compaction and refuse the wrong key.
+#[cfg(feature = "encryption")]
+#[test]
+fn record_encryption_hides_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |key: [u8; 32]| {
+        KvStore::builder().record_encryption_key(key).dedup_values(64).open(temp_dir.path())
+    };
+    let shared = "shared secret ".repeat(8);
+    let store = open([7; 32])?;
+    store.set("secret-key1".to_owned(), "secret value".to_owned())?;
+    store.set("secret-key2".to_owned(), shared.clone())?;
+    store.set("secret-key3".to_owned(), "temporary".to_owned())?;
+    store.remove("secret-key3".to_owned())?;
+    drop(store);
+
+    let mut on_disk = Vec::new();
+    for entry in WalkDir::new(temp_dir.path()).into_iter().filter_map(|entry| entry.ok()) {
+        if entry.file_type().is_file() {
+            on_disk.extend(std::fs::read(entry.path())?);
+        }
+    }
+    assert!(!bytes_contain(&on_disk, "secret-key"));
+    assert!(!bytes_contain(&on_disk, "secret value"));
+    assert!(!bytes_contain(&on_disk, "shared secret"));
+
+    match open([9; 32]) {
+        Err(KvsError::DecryptionFailed) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("open should fail with the wrong key"),
+    }
+
+    let store = open([7; 32])?;
+    assert_eq!(store.get("secret-key1".to_owned())?, Some("secret value".to_owned()));
+    assert_eq!(store.get("secret-key2".to_owned())?, Some(shared.clone()));
+    assert_eq!(store.get("secret-key3".to_owned())?, None);
+    store.compact()?;
+    drop(store);
+    let store = open([7; 32])?;
+    assert_eq!(store.keys()?, vec!["secret-key1".to_owned(), "secret-key2".to_owned()]);
+    assert_eq!(store.get("secret-key2".to_owned())?, Some(shared));
+    Ok(())
+}
+
+// Compacting into another directory should copy exactly the live keys and leave the source alone.
+#[test]
+fn compact_into_copies_live_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..5 {
+        for key_id in 0..5 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let source_log = std::fs::read(temp_dir.path().join("1.log"))?;
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_path = dest_dir.path().join("compacted");
+    let stats = store.compact_into(&dest_path)?;
+    assert_eq!(stats.keys, 4);
+    assert_eq!(stats.bytes, std::fs::metadata(dest_path.join("1.log"))?.len());
+    assert_eq!(std::fs::read(temp_dir.path().join("1.log"))?, source_log);
+
+    let compacted = KvStore::open(&dest_path)?;
+    assert_eq!(compacted.store_id(), store.store_id());
+    assert_eq!(compacted.get("key0".to_owned())?, None);
+    for key_id in 1..5 {
+        assert_eq!(compacted.get(format!("key{}", key_id))?, Some("value4".to_owned()));
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value4".to_owned()));
+    }
+
+    match store.compact_into(&dest_path) {
+        Err(KvsError::DestinationNotEmpty { .. }) => {}
+        other => panic!("expected a non-empty destination error, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Swapping should exchange two values, including across a reopen.
+#[test]
+fn swap_exchanges_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.swap("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    store.swap("key1".to_owned(), "key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Swapping with a missing key should fail without changing anything.
+#[test]
+fn swap_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match store.swap("key1".to_owned(), "missing".to_owned()) {
+        Err(KvsError::KeyNotFound { key }) => assert_eq!(key, "missing"),
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("missing".to_owned())?, None);
+    Ok(())
+}
+
+// Keys holding identical values should share a single blob, which is collected once unreferenced.
+#[test]
+fn dedup_values_share_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blob_count = || std::fs::read_dir(temp_dir.path().join("blobs")).map_or(0, |dir| dir.count());
+    let large_value = "x".repeat(1024);
+
+    let store = KvStore::builder().dedup_values(64).open(temp_dir.path())?;
+    store.set("key1".to_owned(), large_value.clone())?;
+    store.set("key2".to_owned(), large_value.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(blob_count(), 1);
+    assert!(std::fs::metadata(temp_dir.path().join("1.log"))?.len() < 1024);
+    assert_eq!(store.get("key1".to_owned())?, Some(large_value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(large_value.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    drop(store);
+    let store = KvStore::builder().dedup_values(64).open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some(large_value.clone()));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.defragment()?;
+    assert_eq!(blob_count(), 1);
+    assert_eq!(store.get("key2".to_owned())?, Some(large_value));
+
+    store.remove("key2".to_owned())?;
+    store.defragment()?;
+    assert_eq!(blob_count(), 0);
+    Ok(())
+}
+
+// Resetting the generation counter should renumber a high generation store back to 1.
+#[test]
+fn reset_generation_counter_renumbers_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_files = || {
+        let mut names: Vec<String> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".log"))
+            .collect();
+        names.sort();
+        names
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..5 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+        store.set(format!("key{}", iter + 2), "value".to_owned())?;
+        store.defragment()?;
+    }
+    assert_eq!(log_files(), vec!["10.log", "11.log"]);
+
+    store.reset_generation_counter()?;
+    assert_eq!(log_files(), vec!["1.log", "2.log"]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    store.set("key1".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get("key6".to_owned())?, Some("value".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+    for key_id in 2..7 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// Values under a prefix should come back in key order without the keys.
+#[test]
+fn values_with_prefix_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user:3".to_owned(), "carol".to_owned())?;
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    store.remove("user:3".to_owned())?;
+
+    assert_eq!(store.values_with_prefix("user:")?, vec!["alice".to_owned(), "bob".to_owned()]);
+    assert_eq!(store.values_with_prefix("group:")?, vec!["admins".to_owned()]);
+    assert!(store.values_with_prefix("missing:")?.is_empty());
+    assert_eq!(store.values_with_prefix("")?.len(), 3);
+    Ok(())
+}
+
+// Records should be written and replayed with a custom separator.
+#[test]
+fn custom_record_separator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().record_separator(*b"\x1e\r\n").open(temp_dir.path());
+
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let on_disk = std::fs::read(temp_dir.path().join("1.log"))?;
+    assert!(on_disk.ends_with(b"\x1e\r\n"));
+    assert_eq!(on_disk.windows(3).filter(|window| window == b"\x1e\r\n").count(), 4);
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.key_history("key1")?.len(), 2);
+    Ok(())
+}
+
+// Closing the store should persist everything written.
+#[test]
+fn close_persists_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Dropping every clone without closing should still leave the data on disk.
+#[test]
+fn drop_persists_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    clone.set("key2".to_owned(), "value2".to_owned())?;
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Closing the store should surface a failing final flush instead of swallowing it.
+#[cfg(target_os = "linux")]
+#[test]
+fn close_reports_flush_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Writes to /dev/full always fail with ENOSPC, and the symlink is not counted as an existing generation
+    std::os::unix::fs::symlink("/dev/full", temp_dir.path().join("1.log"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.set("key1".to_owned(), "value1".to_owned()).is_err());
+    assert!(matches!(store.close(), Err(KvsError::Io(_))));
+
+    // Dropping instead has nowhere to report the failure, but must not panic
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.set("key1".to_owned(), "value1".to_owned()).is_err());
+    drop(store);
+    Ok(())
+}
+
+// Older versions of a key should stay readable until their generation is compacted away.
+#[test]
+fn get_version_reads_history() -> Result<()> {
+    // A generation is only sealed on reopen once it passes the 1 MiB compaction threshold
+    let filler = "x".repeat(1024 * 1024);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("filler1".to_owned(), filler.clone())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.set("filler2".to_owned(), filler)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+
+    assert_eq!(store.get_version("key1", 1)?, Some("value1".to_owned()));
+    assert_eq!(store.get_version("key1", 2)?, Some("value2".to_owned()));
+    assert_eq!(store.get_version("key2", 1)?, None);
+    assert_eq!(store.get_version("key2", 2)?, Some("value".to_owned()));
+    // The generation still being written to has no fixed answer yet
+    assert!(matches!(store.get_version("key1", 3), Err(KvsError::VersionNotFound { version: 3 })));
+
+    store.defragment()?;
+    assert!(matches!(store.get_version("key1", 1), Err(KvsError::VersionNotFound { version: 1 })));
+    assert_eq!(store.get_version("key2", 4)?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Compacting should keep every gettable key while dropping removed keys and stale logs.
+#[test]
+fn compact_keeps_live_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+    store.compact()?;
+
+    let mut logs: Vec<String> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    logs.sort();
+    assert_eq!(logs, vec!["2.log", "3.log"]);
+    let compacted = log_record_bodies(&temp_dir.path().join("2.log"));
+    assert_eq!(compacted.len(), 2);
+    assert!(!compacted.iter().any(|body| bytes_contain(body, "removed")));
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("removed".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("removed".to_owned())?, None);
+    Ok(())
+}
+
+// Overwriting the same key should trigger compaction and keep the logs well below the naive append size.
+#[test]
+fn automatic_compaction_bounds_disk_usage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(100);
+
+    let mut appended = 0;
+    for iter in 0..30_000 {
+        let record = format!("{}{}", value, iter);
+        appended += record.len() as u64;
+        store.set("key".to_owned(), record)?;
+    }
+
+    let on_disk: u64 = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(on_disk < appended / 2, "{} bytes on disk after appending {}", on_disk, appended);
+    assert_eq!(store.get("key".to_owned())?, Some(format!("{}{}", value, 29_999)));
+    Ok(())
+}
+
+// The log-structured store should be usable through the engine trait object.
+#[test]
+fn kv_store_as_engine_trait_object() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let engine: &mut dyn KvsEngine = &mut store;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    engine.remove("key1".to_owned())?;
+    assert!(matches!(engine.remove("key1".to_owned()), Err(KvsError::KeyNotFound { .. })));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// The sled engine should follow the same contract and persist across reopening.
+#[test]
+fn sled_engine_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    assert_eq!(engine.get("missing".to_owned())?, None);
+    assert!(matches!(engine.remove("missing".to_owned()), Err(KvsError::KeyNotFound { .. })));
+    drop(engine);
+
+    // sled releases its directory lock from a background thread, so the reopen may briefly race it
+    let mut engine = (0..50)
+        .find_map(|_| match SledKvsEngine::open(temp_dir.path()) {
+            Err(KvsError::Sled(_)) => {
+                thread::sleep(Duration::from_millis(10));
+                None
+            }
+            opened => Some(opened),
+        })
+        .expect("sled kept its lock after being dropped")?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+fn send(addr: std::net::SocketAddr, request: &Request) -> Result<Response> {
+    let mut stream = TcpStream::connect(addr)?;
+    write_message(&mut stream, &Handshake { version: PROTOCOL_VERSION })?;
+    write_message(&mut stream, request)?;
+    assert_eq!(read_message::<_, Handshake>(&mut stream)?, Handshake { version: PROTOCOL_VERSION });
+    read_message(&mut stream)
+}
+
+// The server should apply each request and report errors back instead of failing.
+#[test]
+fn server_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.serve(listener));
+
+    let set = Request::Set { key: "key1".to_owned(), value: "value1".to_owned() };
+    assert_eq!(send(addr, &set)?, Response::Ok);
+    let get = Request::Get { key: "key1".to_owned() };
+    assert_eq!(send(addr, &get)?, Response::Value(Some("value1".to_owned())));
+    let remove = Request::Remove { key: "key2".to_owned() };
+    assert_eq!(send(addr, &remove)?, Response::Err("Key not found: key2".to_owned()));
+    Ok(())
+}
+
+// `kvs-server` should refuse a directory created by a different engine.
+#[test]
+fn server_cli_wrong_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("sled"));
+}
+
+// A write that fails part way, here by hitting the file size limit, should be cut back
+// out of the log so later writes and a reopen still see every earlier key.
+#[cfg(target_os = "linux")]
+#[test]
+fn server_recovers_from_a_failed_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    // SIGXFSZ is ignored so that writes past the limit fail with EFBIG instead of killing the server
+    let mut server = Command::new("sh")
+        .args(["-c", "trap '' XFSZ; ulimit -f 64; exec \"$0\" --addr \"$1\""])
+        .arg(assert_cmd::cargo::cargo_bin("kvs-server"))
+        .arg(addr.to_string())
+        .current_dir(&temp_dir)
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+    let client = kvs::KvsClient::new(addr);
+    let mut attempts = 0;
+    while client.get("key0".to_owned()).is_err() && attempts < 50 {
+        thread::sleep(Duration::from_millis(100));
+        attempts += 1;
+    }
+
+    let big = client.set("big".to_owned(), "x".repeat(200_000));
+    let after = client.set("after".to_owned(), "value".to_owned());
+    let values = (client.get("after".to_owned()), client.get("big".to_owned()));
+    server.kill()?;
+    server.wait()?;
+    assert!(big.is_err());
+    after?;
+    assert_eq!(values.0?, Some("value".to_owned()));
+    assert_eq!(values.1?, None);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("after".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("big".to_owned())?, None);
+    Ok(())
+}
+
+// `kvs-client` should round-trip through a server and report missing keys like the local CLI.
+#[test]
+fn client_cli_against_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.serve(listener));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key2", "--addr", &addr])
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+    Ok(())
+}
+
+/// Hands out at most one byte per read, like a slow socket.
+struct Trickle<R>(R);
+
+impl<R: Read> Read for Trickle<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(1);
+        self.0.read(&mut buf[..len])
+    }
+}
+
+// A frame split across many short reads should be reassembled before decoding.
+#[test]
+fn protocol_reassembles_chunked_frames() -> Result<()> {
+    let request = Request::Set { key: "key1".to_owned(), value: "value1".to_owned() };
+    let mut frame = Vec::new();
+    write_message(&mut frame, &request)?;
+    write_message(&mut frame, &Response::Ok)?;
+    let body_len = serde_json::to_vec(&request)?.len() as u32;
+    assert_eq!(frame[..4], body_len.to_be_bytes());
+
+    let mut reader = Trickle(&frame[..]);
+    assert_eq!(read_message::<_, Request>(&mut reader)?, request);
+    assert_eq!(read_message::<_, Response>(&mut reader)?, Response::Ok);
+    assert!(read_message::<_, Response>(&mut reader).is_err());
+    Ok(())
+}
+
+// Clones share one store, so writes from several threads are all visible afterwards.
+#[test]
+fn concurrent_clones_share_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    store.set(format!("key{}-{}", thread_id, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    for thread_id in 0..8 {
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}-{}", thread_id, i))?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// Every job should run even when there are more jobs than threads and some of them panic.
+#[test]
+fn shared_queue_thread_pool_runs_every_job() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for i in 0..32 {
+        let sender = sender.clone();
+        pool.spawn(move || {
+            sender.send(i).unwrap();
+            if i % 4 == 0 {
+                panic!("job {} panicked on purpose", i);
+            }
+        });
+    }
+    drop(sender);
+
+    let mut ran: Vec<i32> = receiver.iter().collect();
+    ran.sort_unstable();
+    assert_eq!(ran, (0..32).collect::<Vec<_>>());
+    Ok(())
+}
+
+// Keys set with a TTL should read as absent once it passes, even after reopening.
+#[test]
+fn set_with_ttl_expires_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("short".to_owned(), "value1".to_owned(), Duration::from_millis(50))?;
+    store.set_with_ttl("long".to_owned(), "value2".to_owned(), Duration::from_secs(3600))?;
+    store.set("forever".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("short".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(matches!(store.swap("short".to_owned(), "long".to_owned()), Err(KvsError::KeyNotFound { .. })));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("forever".to_owned())?, Some("value3".to_owned()));
+    assert!(matches!(store.remove("short".to_owned()), Err(KvsError::KeyNotFound { .. })));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.compact_into(temp_dir.path().join("copy"))?.keys, 2);
+    Ok(())
+}
+
+// `kvs keys` should list live keys one per line, leaving out removed and expired ones.
+#[test]
+fn cli_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set_with_ttl("key4".to_owned(), "value4".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key2".to_owned()]);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("key1\nkey2\n"));
+    Ok(())
+}
+
+// Range scans should honour inclusive and exclusive bounds and return pairs in key order.
+#[test]
+fn range_scans_sorted_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in [3, 1, 4, 2, 5] {
+        store.set(format!("user:{}", i), format!("value{}", i))?;
+    }
+    store.remove("user:4".to_owned())?;
+
+    let keys = |pairs: Vec<(String, String)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+    let included = store.range(Included("user:2".to_owned()), Included("user:5".to_owned()))?;
+    assert_eq!(included[0], ("user:2".to_owned(), "value2".to_owned()));
+    assert_eq!(keys(included), vec!["user:2", "user:3", "user:5"]);
+    assert_eq!(
+        keys(store.range(Excluded("user:2".to_owned()), Excluded("user:5".to_owned()))?),
+        vec!["user:3"]
+    );
+    assert_eq!(keys(store.range(Unbounded, Excluded("user:3".to_owned()))?), vec!["user:1", "user:2"]);
+    assert!(store.range(Included("user:5".to_owned()), Excluded("user:1".to_owned()))?.is_empty());
+    assert!(store.range(Excluded("user:3".to_owned()), Excluded("user:3".to_owned()))?.is_empty());
+    Ok(())
+}
+
+// Prefix scans should only return keys under the prefix, and `kvs scan` should print them as key=value.
+#[test]
+fn scan_prefix_matches_hierarchical_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user".to_owned(), "root".to_owned())?;
+    store.set("user:2".to_owned(), "value2".to_owned())?;
+    store.set("user:1".to_owned(), "value1".to_owned())?;
+    store.set("user;".to_owned(), "next".to_owned())?;
+    store.set("user:\u{10FFFF}".to_owned(), "max".to_owned())?;
+
+    let scanned = store.scan_prefix("user:")?;
+    assert_eq!(
+        scanned,
+        vec![
+            ("user:1".to_owned(), "value1".to_owned()),
+            ("user:2".to_owned(), "value2".to_owned()),
+            ("user:\u{10FFFF}".to_owned(), "max".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan_prefix("user:\u{10FFFF}")?.len(), 1);
+    assert_eq!(store.scan_prefix("")?.len(), 5);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("user:1=value1\nuser:2=value2\nuser:\u{10FFFF}=max\n"));
+    Ok(())
+}
+
+// Logs written in the old JSON format should be rejected rather than misread.
+#[test]
+fn open_rejects_json_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("1.log"),
+        b"{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n",
+    )?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnrecognizedLog { gen: 1 }) => Ok(()),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("open should reject a JSON log"),
+    }
+}
+
+// A log header naming another format version should be refused, while logs from before
+// the header carried a magic are still read and get the new header once compacted.
+#[test]
+fn open_checks_the_log_format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut future = b"KVSL".to_vec();
+    future.extend_from_slice(&99u32.to_be_bytes());
+    std::fs::write(temp_dir.path().join("1.log"), future)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat { gen: 1, found: 99, expected: 6 }) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("open should reject an unknown format version"),
+    }
+
+    let command = kvs::Command::Set { key: "key1".to_owned(), value: "value1".to_owned(), expires_at: None, seq: 0 };
+    let mut legacy = vec![2u8];
+    legacy.extend_from_slice(&log_frame(&bincode::serialize(&command).unwrap()));
+    std::fs::write(temp_dir.path().join("1.log"), legacy)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.compact()?;
+    drop(store);
+    let generations = kvs::sorted_log_generations(temp_dir.path())?;
+    let compacted = std::fs::read(temp_dir.path().join(format!("{}.log", generations[0])))?;
+    assert_eq!(&compacted[..8], b"KVSL\0\0\0\x06");
+    assert_eq!(KvStore::open(temp_dir.path())?.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A flipped bit inside a record should fail its checksum when the store is opened.
+#[test]
+fn open_detects_checksum_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&log_path)?;
+    // Flip a bit in the first record, since a bad last record is treated as a torn write
+    let value_at = contents.windows(6).position(|window| window == b"value1").unwrap();
+    let record_offset = 8;
+    contents[value_at] ^= 0x01;
+    std::fs::write(&log_path, contents)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptRecord { gen, offset }) => {
+            assert_eq!(gen, 1);
+            assert_eq!(offset, record_offset);
+        }
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("open should fail on a checksum mismatch"),
+    }
+    Ok(())
+}
+
+// A record torn by a crash at the end of the log should be cut off on open, keeping everything before it.
+#[test]
+fn open_truncates_torn_final_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let log_len = std::fs::metadata(&log_path)?.len();
+    let command = kvs::Command::Set { key: "key3".to_owned(), value: "value3".to_owned(), expires_at: None, seq: 0 };
+    let frame = log_frame(&bincode::serialize(&command).unwrap());
+    let mut log = std::fs::OpenOptions::new().append(true).open(&log_path)?;
+    std::io::Write::write_all(&mut log, &frame[..frame.len() / 2])?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log_path)?.len(), log_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Every sync mode should persist writes across reopening.
+#[test]
+fn sync_modes_persist_writes() -> Result<()> {
+    for sync_mode in [SyncMode::EveryWrite, SyncMode::Interval(Duration::from_millis(5)), SyncMode::Never] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder().sync_mode(sync_mode).open(temp_dir.path())?;
+        for i in 0..20 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key19".to_owned())?, Some("value19".to_owned()));
+    }
+    Ok(())
+}
+
+// Sections recorded by `set` should match the ones rebuilt when the log is replayed, framing included.
+#[test]
+fn log_sections_match_after_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "a longer value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let written: Vec<_> = ["key1", "key2"].iter().map(|key| store.log_section(key).unwrap().unwrap()).collect();
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let replayed: Vec<_> = ["key1", "key2"].iter().map(|key| store.log_section(key).unwrap().unwrap()).collect();
+    assert_eq!(written, replayed);
+
+    let bodies = log_record_bodies(&temp_dir.path().join("1.log"));
+    assert_eq!(written[1].start(), 8 + bodies[0].len() as u64 + 9);
+    assert_eq!(written[1].length(), bodies[1].len() as u64 + 9);
+    assert_eq!(written[0].start() + written[0].length(), std::fs::metadata(temp_dir.path().join("1.log"))?.len());
+    assert_eq!(store.log_section("missing")?, None);
+    Ok(())
+}
+
+// Reopening to write a key at a time should keep appending to one log rather than adding a log per open.
+#[test]
+fn reopening_reuses_the_latest_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("7.log"), b"")?;
+    for i in 0..100 {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let logs: Vec<String> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    assert_eq!(logs, vec!["1.log"]);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?.len(), 100);
+    Ok(())
+}
+
+// Compare-and-swap should only write when the current value matches, and never lose a race.
+#[test]
+fn compare_and_swap_checks_current_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.compare_and_swap("lock".to_owned(), None, "owner1".to_owned())?);
+    assert!(!store.compare_and_swap("lock".to_owned(), None, "owner2".to_owned())?);
+    assert!(!store.compare_and_swap("lock".to_owned(), Some("owner2".to_owned()), "owner3".to_owned())?);
+    assert_eq!(store.get("lock".to_owned())?, Some("owner1".to_owned()));
+    assert!(store.compare_and_swap("lock".to_owned(), Some("owner1".to_owned()), "owner2".to_owned())?);
+    assert_eq!(store.get("lock".to_owned())?, Some("owner2".to_owned()));
+
+    // Concurrent increments only succeed against the value they read
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut done = 0;
+                while done < 25 {
+                    let current = store.get("counter".to_owned())?.unwrap();
+                    let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                    if store.compare_and_swap("counter".to_owned(), Some(current), next)? {
+                        done += 1;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("100".to_owned()));
+    Ok(())
+}
+
+// Concurrent increments of one key should all be applied
+#[test]
+fn increment_adds_atomically() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.increment("hits".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("hits".to_owned(), -2)?, 3);
+    assert_eq!(store.get("hits".to_owned())?, Some("3".to_owned()));
+
+    store.set("name".to_owned(), "luke".to_owned())?;
+    assert!(matches!(
+        store.increment("name".to_owned(), 1),
+        Err(KvsError::NotAnInteger { .. })
+    ));
+    assert_eq!(store.get("name".to_owned())?, Some("luke".to_owned()));
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    store.increment("counter".to_owned(), 2)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("counter".to_owned(), 0)?, 800);
+    Ok(())
+}
+
+#[test]
+fn subscribers_see_every_write_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("before".to_owned(), "unseen".to_owned())?;
+
+    let first = store.subscribe();
+    let second = store.clone().subscribe();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_batch(vec![("key2".to_owned(), "value2".to_owned())])?;
+    store.swap("key1".to_owned(), "key2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    let set = |key: &str, value: &str| ChangeEvent::Set { key: key.to_owned(), value: value.to_owned() };
+    let expected = vec![
+        set("key1", "value1"),
+        set("key2", "value2"),
+        set("key1", "value2"),
+        set("key2", "value1"),
+        ChangeEvent::Remove { key: "key1".to_owned() },
+    ];
+    assert_eq!(first.try_iter().collect::<Vec<_>>(), expected);
+    assert_eq!(second.try_iter().collect::<Vec<_>>(), expected);
+
+    drop(first);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(second.try_iter().collect::<Vec<_>>(), vec![set("key3", "value3")]);
+    Ok(())
+}
+
+#[test]
+fn merge_updates_or_removes_atomically() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let push = |item: &'static str| {
+        move |current: Option<String>| match current {
+            Some(list) => Some(format!("{},{}", list, item)),
+            None => Some(item.to_owned()),
+        }
+    };
+    store.merge("list".to_owned(), push("a"))?;
+    store.merge("list".to_owned(), push("b"))?;
+    assert_eq!(store.get("list".to_owned())?, Some("a,b".to_owned()));
+
+    let pop = |current: Option<String>| {
+        let list = current?;
+        let rest = list.rsplit_once(',').map(|(rest, _)| rest.to_owned());
+        rest.filter(|rest| !rest.is_empty())
+    };
+    store.merge("list".to_owned(), pop)?;
+    assert_eq!(store.get("list".to_owned())?, Some("a".to_owned()));
+    store.merge("list".to_owned(), pop)?;
+    assert_eq!(store.get("list".to_owned())?, None);
+    store.merge("list".to_owned(), pop)?;
+    assert_eq!(store.get("list".to_owned())?, None);
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    store.merge("log".to_owned(), push("x"))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let log = store.get("log".to_owned())?.unwrap();
+    assert_eq!(log.split(',').count(), 400);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("list".to_owned())?, None);
+    assert_eq!(store.get("log".to_owned())?.map(|log| log.len()), Some(799));
+    Ok(())
+}
+
+// A batch should be readable immediately and after reopening
+#[test]
+fn set_batch_writes_every_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+
+    let mut entries: Vec<_> = (0..100).map(|i| (format!("key{}", i), format!("value{}", i))).collect();
+    entries.push(("key2".to_owned(), "last".to_owned()));
+    store.set_batch(entries)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("last".to_owned()));
+
+    // A rejected entry stops the whole batch before anything is written
+    store.on_large_value(10, |_, _| false);
+    let result = store.set_batch(vec![
+        ("key3".to_owned(), "small".to_owned()),
+        ("key4".to_owned(), "x".repeat(100)),
+    ]);
+    assert!(matches!(result, Err(KvsError::LargeValueRejected { .. })));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("last".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Multi-get should answer in the order the keys were asked for
+#[test]
+fn get_many_preserves_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "newer1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set_with_ttl("key4".to_owned(), "gone".to_owned(), Duration::from_millis(0))?;
+
+    let keys: Vec<String> = ["key1", "missing", "key3", "key2", "key1", "key4"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    let expected = vec![
+        Some("newer1".to_owned()),
+        None,
+        Some("value3".to_owned()),
+        None,
+        Some("newer1".to_owned()),
+        None,
+    ];
+    assert_eq!(store.get_many(&keys)?, expected);
+    assert_eq!(store.log_section("key4")?, None);
+    assert!(store.get_many(&[])?.is_empty());
+    Ok(())
+}
+
+// The live key count should follow removals and expiry but not compaction
+#[test]
+fn len_counts_live_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+
+    for i in 0..10 {
+        store.set(format!("key{}", i), "first".to_owned())?;
+        store.set(format!("key{}", i), "second".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set_with_ttl("key1".to_owned(), "short".to_owned(), Duration::from_millis(0))?;
+    assert_eq!(store.len(), 8);
+    assert!(!store.is_empty());
+
+    store.compact()?;
+    assert_eq!(store.len(), 8);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["len"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("8\n"));
+    Ok(())
+}
+
+// Existence checks should only see live keys
+#[test]
+fn contains_key_reflects_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::from_millis(0))?;
+
+    assert!(store.contains_key("key1")?);
+    assert!(!store.contains_key("key2")?);
+    assert!(!store.contains_key("key3")?);
+    assert!(!store.contains_key("missing")?);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["exists", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(0)
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["exists", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout(is_empty());
+    Ok(())
+}
+
+// Clearing should leave a single empty generation that keeps working after a reopen
+#[test]
+fn clear_removes_every_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().dedup_values(8).open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("a longer value {}", i))?;
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "short".to_owned())?;
+
+    store.clear()?;
+    assert!(store.is_empty());
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(kvs::sorted_log_generations(temp_dir.path())?, vec![1]);
+    assert!(!temp_dir.path().join("blobs").exists());
+
+    store.set("key9".to_owned(), "value9".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    Ok(())
+}
+
+// Exporting and importing into a fresh directory should reproduce the live keys
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "line\nbreak \"quoted\"".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let mut dump = Vec::new();
+    store.export(&mut dump)?;
+    assert_eq!(String::from_utf8(dump.clone())?.lines().count(), 2);
+
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    let copy = KvStore::open(copy_dir.path())?;
+    copy.import(&dump[..])?;
+    assert_eq!(copy.keys()?, vec!["key1".to_owned(), "key2".to_owned()]);
+    for key in ["key1", "key2", "key3"] {
+        assert_eq!(copy.get(key.to_owned())?, store.get(key.to_owned())?);
+    }
+    drop(store);
+    drop(copy);
+
+    let cli_dir = TempDir::new().expect("unable to create temporary working directory");
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(output.stdout, dump);
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["load"])
+        .current_dir(&cli_dir)
+        .write_stdin(output.stdout)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&cli_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Ok(())
+}
+
+// A read-only store should serve reads alongside a writer and refuse every write
+#[test]
+fn read_only_store_rejects_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::open_read_only(temp_dir.path().join("missing")).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let store_id = store.store_id();
+    assert!(matches!(KvStore::open_read_only(temp_dir.path()), Err(KvsError::Locked { .. })));
+    drop(store);
+    let files_before = std::fs::read_dir(temp_dir.path())?.count();
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.store_id(), store_id);
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(reader.set("key3".to_owned(), "value3".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.remove("key1".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.compact(), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.clear(), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.increment("count".to_owned(), 1), Err(KvsError::ReadOnly)));
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), files_before);
+
+    // Readers share the lock with each other but keep writers out
+    let second = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(second.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked { .. })));
+    reader.close()?;
+    drop(second);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    Ok(())
+}
+
+// Typed views should round-trip serde types through the same log
+#[test]
+fn typed_view_stores_serde_types() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let blobs = store.typed::<u64, Vec<u8>>();
+    blobs.set(&1, &vec![0, 159, 146, 150])?;
+    blobs.set(&2, &Vec::new())?;
+    blobs.remove(&2)?;
+    assert!(matches!(blobs.remove(&3), Err(KvsError::KeyNotFound { .. })));
+    store.set("plain".to_owned(), "value".to_owned())?;
+
+    let named = store.typed::<String, i64>();
+    named.set(&"plain".to_owned(), &-5)?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    drop(blobs);
+    drop(named);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let blobs = store.typed::<u64, Vec<u8>>();
+    assert_eq!(blobs.get(&1)?, Some(vec![0, 159, 146, 150]));
+    assert_eq!(blobs.get(&2)?, None);
+    assert_eq!(blobs.keys()?, vec![1]);
+    assert_eq!(store.typed::<String, i64>().get(&"plain".to_owned())?, Some(-5));
+    Ok(())
+}
+
+// Namespaces should share one log without seeing each other's keys
+#[test]
+fn namespaces_isolate_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.namespace(""), Err(KvsError::InvalidNamespace { .. })));
+    assert!(matches!(store.namespace("a\0b"), Err(KvsError::InvalidNamespace { .. })));
+
+    let sessions = store.namespace("sessions")?;
+    let cache = store.namespace("cache")?;
+    let nested = store.namespace("sessionsx")?;
+    sessions.set("user1".to_owned(), "token1".to_owned())?;
+    sessions.set("user2".to_owned(), "token2".to_owned())?;
+    cache.set("user1".to_owned(), "cached".to_owned())?;
+    nested.set("user3".to_owned(), "other".to_owned())?;
+    store.set("user1".to_owned(), "plain".to_owned())?;
+    sessions.remove("user2".to_owned())?;
+    match cache.remove("user2".to_owned()) {
+        Err(KvsError::KeyNotFound { key }) => assert_eq!(key, "user2"),
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+
+    assert_eq!(sessions.name(), "sessions");
+    assert_eq!(sessions.get("user1".to_owned())?, Some("token1".to_owned()));
+    assert_eq!(cache.get("user1".to_owned())?, Some("cached".to_owned()));
+    assert_eq!(store.get("user1".to_owned())?, Some("plain".to_owned()));
+    assert_eq!(sessions.keys()?, vec!["user1".to_owned()]);
+    assert_eq!(nested.keys()?, vec!["user3".to_owned()]);
+    drop((store, sessions, cache, nested));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.namespace("cache")?.keys()?, vec!["user1".to_owned()]);
+    assert_eq!(store.namespace("sessions")?.get("user2".to_owned())?, None);
+    Ok(())
+}
+
+// Stats should track dead bytes until compaction reclaims them
+#[test]
+fn stats_report_fragmentation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let empty = store.stats()?;
+    assert_eq!((empty.live_keys, empty.dead_bytes, empty.fragmentation), (0, 0, 0.0));
+
+    for iter in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value".to_owned())?;
+    let before = store.stats()?;
+    assert_eq!(before.live_keys, 2);
+    assert!(before.dead_bytes > 0);
+    assert!(before.dead_bytes < before.disk_bytes);
+    assert!(before.fragmentation > 0.5 && before.fragmentation < 1.0);
+
+    store.compact()?;
+    let after = store.stats()?;
+    assert_eq!(after.live_keys, 2);
+    assert_eq!(after.dead_bytes, 0);
+    assert_eq!(after.fragmentation, 0.0);
+    assert!(after.disk_bytes < before.disk_bytes);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live_keys: 2\n"))
+        .stdout(contains("dead_bytes: 0\n"))
+        .stdout(contains("fragmentation: 0.000\n"));
+    Ok(())
+}
+
+// A hashed index should tell colliding keys apart by reading them back
+#[test]
+fn hashed_index_handles_collisions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Every key hashes the same, so all but the first are collisions
+    let open = || KvStore::builder().hashed_index_with(|_| 0).open(temp_dir.path());
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "newer1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::KeyNotFound { .. })));
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert_eq!(store.keys()?, vec!["key2".to_owned(), "key3".to_owned(), "key4".to_owned()]);
+    assert_eq!(store.scan_prefix("key3")?, vec![("key3".to_owned(), "value3".to_owned())]);
+    assert_eq!(store.len(), 3);
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    store.compact()?;
+    assert_eq!(store.keys()?, vec!["key2".to_owned(), "key3".to_owned(), "key4".to_owned()]);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // The logs themselves are unchanged, so any hasher or a plain index can read them
+    let store = KvStore::builder().hashed_index().open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 3);
+    Ok(())
+}
+
+// Compressed values should read back exactly, and incompressible ones stay as they are
+#[test]
+fn compressed_values_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let json: String = (0..50)
+        .map(|i| format!(r#"{{"id":{},"name":"user{}","active":true,"roles":["reader","writer"]}}"#, i, i))
+        .collect::<Vec<_>>()
+        .join(",");
+    // A short pseudo-random string that deflate cannot shrink
+    let mut state: u32 = 12345;
+    let noise: String = (0..64)
+        .map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            char::from(b'!' + ((state >> 16) % 90) as u8)
+        })
+        .collect();
+
+    let store = KvStore::builder().compress_values(32).open(temp_dir.path())?;
+    store.set("json".to_owned(), json.clone())?;
+    store.set("noise".to_owned(), noise.clone())?;
+    store.set("small".to_owned(), "tiny".to_owned())?;
+    let json_section = store.log_section("json")?.unwrap();
+    assert!(json_section.length() < json.len() as u64 / 4);
+    assert!(store.log_section("noise")?.unwrap().length() > noise.len() as u64);
+    assert_eq!(store.get("json".to_owned())?, Some(json.clone()));
+    assert_eq!(store.get("noise".to_owned())?, Some(noise.clone()));
+    drop(store);
+
+    // Compressed records stay readable without the option, including through history
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("json".to_owned())?, Some(json.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("tiny".to_owned()));
+    store.compact()?;
+    let sealed_gen = store.log_section("json")?.unwrap().gen();
+    assert_eq!(store.get_version("json", sealed_gen)?, Some(json.clone()));
+    assert_eq!(store.get("json".to_owned())?, Some(json));
+    assert_eq!(store.get("noise".to_owned())?, Some(noise));
+    Ok(())
+}
+
+// Writing past the size limit should roll over into new generations that all stay readable
+#[test]
+fn max_log_size_rotates_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().max_log_size(256).open(temp_dir.path());
+    let store = open()?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    let generations = kvs::sorted_log_generations(temp_dir.path())?;
+    assert!(generations.len() > 5);
+    for gen in &generations {
+        assert!(std::fs::metadata(temp_dir.path().join(format!("{}.log", gen)))?.len() < 512);
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key49".to_owned())?, Some("value49".to_owned()));
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(kvs::sorted_log_generations(temp_dir.path())?, generations);
+    for i in 1..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.compact()?;
+    assert_eq!(store.len(), 49);
+    assert_eq!(store.get("key25".to_owned())?, Some("value25".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+// Clones should share one handle per sealed generation instead of opening their own.
+#[cfg(target_os = "linux")]
+#[test]
+fn clones_share_sealed_generation_handles() -> Result<()> {
+    let open_fds = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().max_log_size(256).open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let generations = kvs::sorted_log_generations(temp_dir.path())?.len();
+    assert!(generations > 15);
+
+    let before = open_fds();
+    let handles: Vec<_> = (0..16)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<KvStore> {
+                for i in 0..100 {
+                    assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+                }
+                Ok(store)
+            })
+        })
+        .collect();
+    let clones = handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Result<Vec<_>>>()?;
+    // Other tests open files too, so only a count near clones * generations is a failure
+    let opened = open_fds().saturating_sub(before);
+    assert!(opened < generations + clones.len() + 64, "{} descriptors for {} generations", opened, generations);
+    Ok(())
+}
+
+#[test]
+fn mmap_reads_follow_rotation_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().mmap_reads().max_log_size(256).open(temp_dir.path());
+    let store = open()?;
+    let reader = store.clone();
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(reader.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    for i in 0..50 {
+        assert_eq!(reader.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    store.set("key0".to_owned(), "updated".to_owned())?;
+    store.compact()?;
+    assert_eq!(reader.get("key0".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(reader.get("key49".to_owned())?, Some("value49".to_owned()));
+    store.reset_generation_counter()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.clear()?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("again{}", i))?;
+    }
+    drop((store, reader));
+    let store = open()?;
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("again{}", i)));
+    }
+    Ok(())
+}
+
+// Streamed values should survive reopening and compaction, and a short source should set nothing.
+#[test]
+fn streamed_values_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|i| b'a' + (i % 26) as u8).collect();
+    store.set_reader("big".to_owned(), value.len() as u64, value.as_slice())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+
+    let mut streamed = Vec::new();
+    assert!(store.get_writer("big", &mut streamed)?);
+    assert!(streamed == value);
+    assert!(!store.get_writer("missing", &mut streamed)?);
+    let mut small = Vec::new();
+    assert!(store.get_writer("small", &mut small)?);
+    assert_eq!(small, b"value");
+
+    // A source that runs dry leaves the old value and the log as they were
+    let before = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert!(store.set_reader("big".to_owned(), 1024, &b"short"[..]).is_err());
+    assert_eq!(std::fs::metadata(temp_dir.path().join("1.log"))?.len(), before);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("big".to_owned())?.map(String::into_bytes), Some(value.clone()));
+    store.compact()?;
+    let mut streamed = Vec::new();
+    assert!(store.get_writer("big", &mut streamed)?);
+    assert!(streamed == value);
+    assert_eq!(store.key_history("big")?.len(), 1);
+    drop(store);
+    assert!(KvStore::repair(temp_dir.path())?.is_clean());
+
+    let hooked_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hooked = KvStore::builder()
+        .value_hooks(|bytes| bytes.to_vec(), |bytes| Ok(bytes.to_vec()))
+        .open(hooked_dir.path())?;
+    assert!(matches!(hooked.set_reader("big".to_owned(), 1, &b"x"[..]), Err(KvsError::StreamingUnsupported)));
+    Ok(())
+}
+
+// Replay decodes records without their values, so every kind of record should still index correctly.
+#[test]
+fn reopen_indexes_every_record_kind() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().dedup_values(64).compress_values(32).hashed_index().open(temp_dir.path());
+    let store = open()?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    store.set("deduped".to_owned(), "d".repeat(100))?;
+    store.set("compressed".to_owned(), "c".repeat(40))?;
+    store.set_reader("streamed".to_owned(), 5, &b"bytes"[..])?;
+    store.set_with_ttl("expired".to_owned(), "gone".to_owned(), Duration::from_millis(1))?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+    drop(store);
+    thread::sleep(Duration::from_millis(5));
+
+    let store = open()?;
+    assert_eq!(store.keys()?, vec!["compressed", "deduped", "plain", "streamed"]);
+    assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("deduped".to_owned())?, Some("d".repeat(100)));
+    assert_eq!(store.get("compressed".to_owned())?, Some("c".repeat(40)));
+    assert_eq!(store.get("streamed".to_owned())?, Some("bytes".to_owned()));
+    Ok(())
+}
+
+// A snapshot should keep reading the values it was taken with through later writes, compaction and clear.
+#[test]
+fn snapshot_sees_values_as_of_when_it_was_taken() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().dedup_values(16).open(temp_dir.path())?;
+    store.set("changed".to_owned(), "old".to_owned())?;
+    store.set("removed".to_owned(), "old".to_owned())?;
+    store.set("deduped".to_owned(), "d".repeat(32))?;
+    let snapshot = store.snapshot()?;
+
+    store.set("changed".to_owned(), "new".to_owned())?;
+    store.remove("removed".to_owned())?;
+    store.remove("deduped".to_owned())?;
+    store.set("added".to_owned(), "new".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("changed".to_owned())?, Some("new".to_owned()));
+    assert_eq!(snapshot.get("changed".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("removed".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("deduped".to_owned())?, Some("d".repeat(32)));
+    assert_eq!(snapshot.get("added".to_owned())?, None);
+    assert_eq!(snapshot.keys()?, vec!["changed", "deduped", "removed"]);
+
+    store.clear()?;
+    assert_eq!(snapshot.get("changed".to_owned())?, Some("old".to_owned()));
+    drop(snapshot);
+    store.set("fresh".to_owned(), "value".to_owned())?;
+    assert_eq!(store.keys()?, vec!["fresh"]);
+    Ok(())
+}
+
+// Removing a key should count both its value and the remove record as dead, just as replay does.
+#[test]
+fn remove_counts_value_and_record_as_dead() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("big".to_owned(), "v".repeat(10_000))?;
+    assert_eq!(store.stats()?.dead_bytes, 0);
+    store.remove("big".to_owned())?;
+    let stats = store.stats()?;
+    // Only the log header is left live
+    assert_eq!(stats.dead_bytes, stats.disk_bytes - 8);
+    assert!(matches!(store.remove("big".to_owned()), Err(KvsError::KeyNotFound { .. })));
+    assert_eq!(store.stats()?.dead_bytes, stats.dead_bytes);
+    drop(store);
+
+    assert_eq!(KvStore::open(temp_dir.path())?.stats()?.dead_bytes, stats.dead_bytes);
+    Ok(())
+}
+
+// `get_or` should fall back to the default, and only one racing `get_or_insert` should write it.
+#[test]
+fn get_or_and_get_or_insert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("present".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_or("present".to_owned(), "default".to_owned())?, "value");
+    assert_eq!(store.get_or("absent".to_owned(), "default".to_owned())?, "default");
+    assert_eq!(store.get("absent".to_owned())?, None);
+    assert_eq!(store.get_or_insert("present".to_owned(), "default".to_owned())?, "value");
+    assert_eq!(store.get_or_insert("absent".to_owned(), "default".to_owned())?, "default");
+    assert_eq!(store.get("absent".to_owned())?, Some("default".to_owned()));
+
+    let events = store.subscribe();
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || store.get_or_insert("raced".to_owned(), format!("thread{}", i)))
+        })
+        .collect();
+    let values = handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Result<Vec<_>>>()?;
+    assert!(values.iter().all(|value| *value == values[0]));
+    assert_eq!(store.get("raced".to_owned())?, Some(values[0].clone()));
+    assert_eq!(events.try_iter().count(), 1);
+    Ok(())
+}
+
+// Log positions should come out the same whatever the buffer size, down to no buffering at all.
+#[test]
+fn buffer_capacity_does_not_change_the_logs() -> Result<()> {
+    let mut logs = Vec::new();
+    for capacity in [0, 1, 7, 64 * 1024] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || KvStore::builder().buffer_capacity(capacity).max_log_size(4096).open(temp_dir.path());
+        let store = open()?;
+        store.set_batch((0..200).map(|i| (format!("key{}", i), format!("value{}", i))).collect())?;
+        store.set("key0".to_owned(), "updated".to_owned())?;
+        store.remove("key1".to_owned())?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(store.get("key0".to_owned())?, Some("updated".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.key_history("key0")?.len(), 2);
+        store.compact()?;
+        assert_eq!(store.get("key199".to_owned())?, Some("value199".to_owned()));
+        drop(store);
+        let store = open()?;
+        assert_eq!(store.len(), 199);
+        logs.push((store.log_section("key150")?, store.stats()?.disk_bytes));
+    }
+    assert!(logs.iter().all(|log| *log == logs[0]));
+    Ok(())
+}
+
+// A second writer, in this process or another, should be locked out until the first closes
+#[test]
+fn store_directory_is_locked_while_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked { .. })));
+    assert!(matches!(KvStore::repair(temp_dir.path()), Err(KvsError::Locked { .. })));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(70)
+        .stderr(contains("is locked by another open"));
+
+    // Clones share the lock, which is released once the last one is dropped
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked { .. })));
+    drop(clone);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// The sweeper should drop expired keys without them being read, and stop with the store
+#[test]
+fn sweeper_drops_expired_keys_in_the_background() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .sweep_expired(Duration::from_millis(10))
+        .open(temp_dir.path())?;
+    store.set_with_ttl("short".to_owned(), "value".to_owned(), Duration::from_millis(20))?;
+    store.set("kept".to_owned(), "value".to_owned())?;
+    let section = store.log_section("short")?.expect("short is indexed");
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while store.log_section("short")?.is_some() {
+        assert!(std::time::Instant::now() < deadline, "expired key was never swept");
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(store.stats()?.dead_bytes, section.length());
+    assert!(store.log_section("kept")?.is_some());
+
+    // Dropping the last clone stops the thread, which would otherwise keep the lock held
+    let clone = store.clone();
+    drop(store);
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["kept".to_owned()]);
+    Ok(())
+}
+
+// Iterating should read each value only when it is reached, skipping keys removed meanwhile
+#[test]
+fn iter_reads_values_one_at_a_time() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let reads = Arc::new(AtomicUsize::new(0));
+    let hook_reads = Arc::clone(&reads);
+    let store = KvStore::builder()
+        .value_hooks(|bytes| bytes.to_vec(), move |bytes| {
+            hook_reads.fetch_add(1, Ordering::SeqCst);
+            Ok(bytes.to_vec())
+        })
+        .open(temp_dir.path())?;
+    let big = "x".repeat(1024 * 1024);
+    for key in ["a", "b", "c", "d"] {
+        store.set(key.to_owned(), format!("{}{}", key, big))?;
+    }
+    store.remove("c".to_owned())?;
+
+    let before = reads.load(Ordering::SeqCst);
+    let mut entries = store.iter();
+    assert_eq!(reads.load(Ordering::SeqCst), before);
+    let (key, value) = entries.next().expect("a is live")?;
+    assert_eq!((key.as_str(), value.len()), ("a", big.len() + 1));
+    assert_eq!(reads.load(Ordering::SeqCst), before + 1);
+    store.remove("b".to_owned())?;
+    let before = reads.load(Ordering::SeqCst);
+    let (key, _) = entries.next().expect("d is live")?;
+    assert_eq!(key, "d");
+    assert!(entries.next().is_none());
+    assert_eq!(reads.load(Ordering::SeqCst), before + 1);
+    Ok(())
+}
+
+// Keys and values should be held to the configured limits, and empty keys refused
+#[test]
+fn key_and_value_sizes_are_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(store.set(String::new(), "value".to_owned()), Err(KvsError::EmptyKey)));
+    store.set("k".repeat(4096), "value".to_owned())?;
+    assert!(matches!(
+        store.set("k".repeat(4097), "value".to_owned()),
+        Err(KvsError::KeyTooLong { len: 4097, max: 4096 })
+    ));
+    drop(store);
+
+    let store = KvStore::builder().max_key_size(8).max_value_size(16).open(temp_dir.path())?;
+    store.set("12345678".to_owned(), "v".repeat(16))?;
+    assert!(matches!(store.set("123456789".to_owned(), "v".to_owned()), Err(KvsError::KeyTooLong { len: 9, max: 8 })));
+    assert!(matches!(
+        store.set("key".to_owned(), "v".repeat(17)),
+        Err(KvsError::ValueTooLong { len: 17, max: 16, .. })
+    ));
+    assert!(matches!(
+        store.set_batch(vec![("a".to_owned(), "v".to_owned()), ("b".to_owned(), "v".repeat(17))]),
+        Err(KvsError::ValueTooLong { len: 17, .. })
+    ));
+    assert!(matches!(store.set_reader("key".to_owned(), 17, &[0; 17][..]), Err(KvsError::ValueTooLong { .. })));
+    store.set_reader("key".to_owned(), 16, &[b'v'; 16][..])?;
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(store.get("12345678".to_owned())?, Some("v".repeat(16)));
+    Ok(())
+}
+
+// Opening a directory of small logs should merge them into one sealed generation
+#[test]
+fn open_consolidates_small_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().max_log_size(128).open(temp_dir.path())?;
+    for round in 0..3 {
+        store.set(format!("key{}", round), format!("value{}", round))?;
+        store.set("shared".to_owned(), format!("value{}", round))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+    assert!(kvs::sorted_log_generations(temp_dir.path())?.len() > 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let generations = kvs::sorted_log_generations(temp_dir.path())?;
+    assert_eq!(generations.len(), 2);
+    assert_eq!(store.log_section("shared")?.map(|section| section.gen()), Some(generations[0]));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("shared".to_owned())?, Some("value2".to_owned()));
+    store.set("new".to_owned(), "value".to_owned())?;
+    assert_eq!(store.log_section("new")?.map(|section| section.gen()), Some(generations[1]));
+    Ok(())
+}
+
+// With the tracing feature, compaction should report its sizes through the installed subscriber
+#[cfg(feature = "tracing")]
+#[test]
+fn compaction_emits_a_tracing_event() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let _guard = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_max_level(tracing::Level::DEBUG)
+        .finish()
+        .set_default();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    store.set("key".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    assert!(output.contains("opened store"));
+    assert!(output.contains("set key=key"));
+    let compacted = output.lines().find(|line| line.contains("compacted")).expect("compaction event");
+    assert!(compacted.contains("before=") && compacted.contains("after="));
+    Ok(())
+}
+
+// A batched remove should report which keys existed, and a conditional one should only remove a match
+#[test]
+fn remove_batch_and_remove_if() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..4 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let keys = ["key0", "missing", "key2", "key0", "key3"].map(str::to_owned).to_vec();
+    assert_eq!(store.remove_batch(keys)?, vec![true, false, true, false, true]);
+    assert_eq!(store.remove_batch(vec!["key0".to_owned()])?, vec![false]);
+    assert_eq!(store.keys()?, vec!["key1".to_owned()]);
+
+    assert!(!store.remove_if("key1".to_owned(), "other".to_owned())?);
+    assert!(!store.remove_if("missing".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.remove_if("key1".to_owned(), "value1".to_owned())?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+
+    // Only one of the racing threads sees its expected value and removes the key
+    store.set("lease".to_owned(), "held".to_owned())?;
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || store.remove_if("lease".to_owned(), "held".to_owned()))
+        })
+        .collect();
+    let mut removed = 0;
+    for handle in handles {
+        if handle.join().unwrap()? {
+            removed += 1;
+        }
+    }
+    assert_eq!(removed, 1);
+    Ok(())
+}
+
+// A lower compaction threshold should compact as soon as that many bytes are dead
+#[test]
+fn compaction_threshold_is_configurable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().compaction_threshold(1024).open(temp_dir.path())?;
+    let mut compactions = 0;
+    for i in 0..200 {
+        let before = store.stats()?.dead_bytes;
+        store.set("key".to_owned(), format!("value{}", i))?;
+        if store.stats()?.dead_bytes < before {
+            compactions += 1;
+        }
+        assert!(store.stats()?.dead_bytes <= 1024);
+    }
+    assert!(compactions > 1);
+    assert_eq!(store.get("key".to_owned())?, Some("value199".to_owned()));
+    drop(store);
+
+    // The default threshold is far above what these writes leave behind
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.stats()?.dead_bytes > 1024);
+    Ok(())
+}
+
+// A server's request log should replay the same traffic against another store
+#[test]
+fn request_log_replays_server_traffic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("requests");
+    let request_log = RequestLog::open(&log_path)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(KvStore::open(temp_dir.path().join("live"))?, SharedQueueThreadPool::new(2)?)
+        .request_log(request_log.clone());
+    thread::spawn(move || server.serve(listener));
+
+    let set = Request::Set { key: "key1".to_owned(), value: "value1".to_owned() };
+    assert_eq!(send(addr, &set)?, Response::Ok);
+    let get = Request::Get { key: "key1".to_owned() };
+    assert_eq!(send(addr, &get)?, Response::Value(Some("value1".to_owned())));
+    let remove = Request::Remove { key: "key2".to_owned() };
+    assert!(matches!(send(addr, &remove)?, Response::Err(_)));
+    request_log.flush()?;
+    // A frame cut off by a crash ends the replay without failing it
+    let mut log = std::fs::OpenOptions::new().append(true).open(&log_path)?;
+    std::io::Write::write_all(&mut log, b"\0\0\0\x40{\"at\"")?;
+    drop(log);
+
+    let mut target = KvStore::open(temp_dir.path().join("replayed"))?;
+    let report = kvs::replay(&mut target, std::fs::File::open(&log_path)?)?;
+    assert_eq!(report.replayed, 3);
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!((report.failures[0].index, &report.failures[0].logged.request), (2, &remove));
+    assert_eq!(report.failures[0].error, "Key not found: key2");
+    assert_eq!(target.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(target);
+
+    let cli_dir = temp_dir.path().join("cli");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["replay", log_path.to_str().unwrap(), "--path", cli_dir.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("request 2 (Remove { key: \"key2\" }): Key not found: key2\nreplayed 3 requests, 1 failed"));
+    assert_eq!(KvStore::open(cli_dir)?.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The index should only ever point at set records, whatever mix of writes and reopens came before
+#[test]
+fn index_never_points_at_a_remove_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keys: Vec<String> = (0..6).map(|i| format!("key{}", i)).collect();
+    let check = |store: &KvStore| -> Result<()> {
+        for key in &keys {
+            if let Some(section) = store.log_section(key)? {
+                let log = std::fs::read(temp_dir.path().join(format!("{}.log", section.gen())))?;
+                let start = section.start() as usize;
+                let len = u32::from_be_bytes(log[start..start + 4].try_into().unwrap()) as usize;
+                let command: kvs::Command = bincode::deserialize(&log[start + 8..start + 8 + len]).unwrap();
+                assert!(!matches!(command, kvs::Command::Remove { .. }), "{} points at {:?}", key, command);
+            }
+        }
+        Ok(())
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &keys {
+        store.set(key.clone(), "first".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "second".to_owned())?;
+    store.remove_batch(vec!["key2".to_owned(), "missing".to_owned()])?;
+    store.remove_if("key3".to_owned(), "first".to_owned())?;
+    store.set_batch(vec![("key2".to_owned(), "second".to_owned()), ("key4".to_owned(), "second".to_owned())])?;
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    store.remove("key4".to_owned())?;
+    store.compact()?;
+    check(&store)?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key2".to_owned(), "key5".to_owned()]);
+    assert_eq!(store.scan_prefix("key")?.len(), 3);
+    assert_eq!(store.iter().count(), 3);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+// Warming up should read through every generation without changing the store
+#[test]
+fn warmup_leaves_the_store_unchanged() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().max_log_size(4096).open(temp_dir.path())?;
+    store.warmup()?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set_reader("streamed".to_owned(), 100_000, &vec![b's'; 100_000][..])?;
+    store.remove("key7".to_owned())?;
+    store.set("key8".to_owned(), "updated".to_owned())?;
+    assert!(kvs::sorted_log_generations(temp_dir.path())?.len() > 2);
+
+    let logs = |dir: &std::path::Path| -> Result<Vec<Vec<u8>>> {
+        kvs::sorted_log_generations(dir)?
+            .into_iter()
+            .map(|gen| Ok(std::fs::read(dir.join(format!("{}.log", gen)))?))
+            .collect()
+    };
+    let before = (logs(temp_dir.path())?, store.stats()?.dead_bytes, store.len());
+    store.warmup()?;
+    assert_eq!((logs(temp_dir.path())?, store.stats()?.dead_bytes, store.len()), before);
+    assert_eq!(store.get("key8".to_owned())?, Some("updated".to_owned()));
+    drop(store);
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    reader.warmup()?;
+    assert_eq!(reader.get("key199".to_owned())?, Some("value199".to_owned()));
+    Ok(())
+}
+
+// The CLI should exit with the documented status for success, misses, usage, I/O and other errors
+#[test]
+fn cli_exit_codes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |dir: &std::path::Path, args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(dir);
+        cmd.assert()
+    };
+
+    kvs(temp_dir.path(), &["set", "key1", "value1"]).code(0).stdout(is_empty());
+    kvs(temp_dir.path(), &["get", "key1"]).code(0).stdout(eq("value1").trim());
+    kvs(temp_dir.path(), &["get", "key2"]).code(0).stdout(eq("Key not found").trim());
+    kvs(temp_dir.path(), &["rm", "key2"]).code(1).stdout(eq("Key not found").trim());
+    kvs(temp_dir.path(), &["exists", "key2"]).code(1).stdout(is_empty());
+    kvs(temp_dir.path(), &["rm", "key1"]).code(0).stdout(is_empty());
+    kvs(temp_dir.path(), &["frobnicate"]).code(2).stdout(is_empty());
+    kvs(temp_dir.path(), &["set", "key1"]).code(2).stdout(is_empty());
+    kvs(temp_dir.path(), &["set", &"k".repeat(5000), "value"])
+        .code(70)
+        .stdout(is_empty())
+        .stderr(contains("Key of 5000 bytes is over the limit"));
+
+    let corrupt_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(corrupt_dir.path().join("1.log"), b"KVSL\0\0\0\x63")?;
+    kvs(corrupt_dir.path(), &["get", "key1"])
+        .code(74)
+        .stdout(is_empty())
+        .stderr(contains("format version 99"));
+    Ok(())
+}
+
+// A lookup of an absent key should be ruled out by the sealed generation's bloom filter without reading it
+#[test]
+fn bloom_filter_skips_reads_for_absent_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Every key hashes the same, so without a filter each lookup has to read the first key back
+    let open = || KvStore::builder().hashed_index_with(|_| 0).max_log_size(256).open(temp_dir.path());
+
+    let store = open()?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(store.log_section("key0")?.unwrap().gen() < store.log_section("key19")?.unwrap().gen());
+
+    let reads = store.stats()?.record_reads;
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert!(!store.contains_key("absent")?);
+    assert_eq!(store.stats()?.record_reads, reads);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert!(store.stats()?.record_reads > reads);
+    let sealed_gen = store.log_section("key0")?.unwrap().gen();
+    drop(store);
+
+    // Each sealed generation's filter is saved next to its log and loaded on open
+    let filter_file = temp_dir.path().join(format!("{}.filter", sealed_gen));
+    let saved = std::fs::read(&filter_file)?;
+    let store = open()?;
+    let reads = store.stats()?.record_reads;
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert_eq!(store.stats()?.record_reads, reads);
+    assert_eq!(std::fs::read(&filter_file)?, saved);
+    drop(store);
+
+    // A damaged filter is ignored and built again from the replayed keys
+    std::fs::write(&filter_file, b"damaged")?;
+    let store = open()?;
+    let reads = store.stats()?.record_reads;
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert_eq!(store.stats()?.record_reads, reads);
+    assert_eq!(std::fs::read(&filter_file)?, saved);
+
+    store.compact()?;
+    assert!(!filter_file.exists());
+    let reads = store.stats()?.record_reads;
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert_eq!(store.stats()?.record_reads, reads);
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A backup should open to the state at the moment it was taken, whatever is written afterwards
+#[test]
+fn backup_opens_to_pre_write_state() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().max_log_size(256).dedup_values(64).open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("large".to_owned(), "x".repeat(100))?;
+    store.remove("key3".to_owned())?;
+    store.backup(backup_dir.path())?;
+
+    // Compaction deleting the copied logs and the blob must not reach the backup
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    store.set("new".to_owned(), "value".to_owned())?;
+    store.remove("large".to_owned())?;
+    store.compact()?;
+    assert!(matches!(store.backup(backup_dir.path()), Err(KvsError::DestinationNotEmpty { .. })));
+
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.store_id(), store.store_id());
+    assert_eq!(backup.len(), 20);
+    assert_eq!(backup.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(backup.get("key3".to_owned())?, None);
+    assert_eq!(backup.get("new".to_owned())?, None);
+    assert_eq!(backup.get("large".to_owned())?, Some("x".repeat(100)));
+    drop(backup);
+    drop(store);
+
+    let cli_backup = backup_dir.path().join("cli");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", cli_backup.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_eq!(KvStore::open(&cli_backup)?.get("key0".to_owned())?, Some("changed".to_owned()));
+    Ok(())
+}
+
+// Appends should concatenate from several threads at once, with each superseded record counted as dead
+#[test]
+fn append_concatenates_and_compacts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().compaction_threshold(4096).open(temp_dir.path())?;
+    store.append("log".to_owned(), "a".to_owned())?;
+    assert_eq!(store.get("log".to_owned())?, Some("a".to_owned()));
+
+    let before = store.stats()?.dead_bytes;
+    store.append("log".to_owned(), "b".to_owned())?;
+    assert_eq!(store.get("log".to_owned())?, Some("ab".to_owned()));
+    assert!(store.stats()?.dead_bytes > before);
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    store.append("log".to_owned(), thread.to_string()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let value = store.get("log".to_owned())?.unwrap();
+    assert_eq!(value.len(), 202);
+    for thread in 0..4 {
+        assert_eq!(value.matches(&thread.to_string()).count(), 50);
+    }
+    // Far more than the threshold was written, but compaction kept reclaiming it
+    let stats = store.stats()?;
+    assert!(stats.dead_bytes <= 4096);
+    assert!(stats.disk_bytes < 8192);
+    Ok(())
+}
+
+// Records should decode from the coordinates the index reports, and bad coordinates should fail cleanly
+#[test]
+fn read_record_decodes_by_coordinates() -> Result<()> {
+    use kvs::Command as LogCommand;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().compress_values(64).open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    store.set_with_ttl("expiring".to_owned(), "value".to_owned(), Duration::from_secs(3600))?;
+    store.set("compressed".to_owned(), "x".repeat(200))?;
+    store.set_reader("streamed".to_owned(), 11, "hello world".as_bytes())?;
+
+    let read = |key: &str| -> Result<LogCommand> {
+        let section = store.log_section(key)?.unwrap();
+        store.read_record(section.gen(), section.start(), section.length())
+    };
+    assert!(matches!(read("plain")?, LogCommand::Set { key, value, expires_at: None, .. } if key == "plain" && value == "value"));
+    assert!(matches!(read("expiring")?, LogCommand::Set { expires_at: Some(_), .. }));
+    assert!(matches!(read("compressed")?, LogCommand::SetCompressed { value, .. } if value.len() < 200));
+    assert!(matches!(read("streamed")?, LogCommand::SetStream { key, len: 11, .. } if key == "streamed"));
+
+    let section = store.log_section("plain")?.unwrap();
+    let (gen, start, length) = (section.gen(), section.start(), section.length());
+    let corrupt = |result: Result<LogCommand>| matches!(result, Err(KvsError::Corrupt { .. }));
+    assert!(corrupt(store.read_record(gen, start + 1, length)));
+    assert!(corrupt(store.read_record(gen, start, length - 1)));
+    assert!(corrupt(store.read_record(gen, start, length + 1)));
+    assert!(corrupt(store.read_record(gen, start, 3)));
+    assert!(corrupt(store.read_record(gen, u64::MAX, length)));
+    assert!(matches!(store.read_record(gen + 100, start, length), Err(KvsError::ReaderNotFound { gen: missing }) if missing == gen + 100));
+
+    // The streamed value has to be covered exactly too
+    let streamed = store.log_section("streamed")?.unwrap();
+    assert!(corrupt(store.read_record(streamed.gen(), streamed.start(), streamed.length() - 4)));
+    Ok(())
+}
+
+// A store keeping 3 versions should return the 3 latest values through reopens and compaction
+#[test]
+fn keep_versions_returns_recent_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().keep_versions(3).open(temp_dir.path());
+    let recent = |store: &KvStore, n| store.get_versions("key", n);
+    let expected: Vec<String> = ["value5", "value4", "value3"].iter().map(|value| value.to_string()).collect();
+
+    let store = open()?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    store.set("key".to_owned(), "value2".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats()?.dead_bytes, 0);
+    for i in 3..=5 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    assert_eq!(recent(&store, 3)?, expected);
+    assert_eq!(recent(&store, 10)?, expected);
+    assert_eq!(recent(&store, 1)?, vec!["value5".to_owned()]);
+    assert_eq!(store.get_versions("other", 3)?, vec!["value".to_owned()]);
+    // Only the two values pushed out of the ring are dead
+    let dead = store.stats()?.dead_bytes;
+    assert!(dead > 0);
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(recent(&store, 3)?, expected);
+    assert_eq!(store.stats()?.dead_bytes, dead);
+    store.compact()?;
+    assert_eq!(recent(&store, 3)?, expected);
+    assert_eq!(store.stats()?.dead_bytes, 0);
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(recent(&store, 3)?, expected);
+    assert_eq!(store.get("key".to_owned())?, Some("value5".to_owned()));
+    store.remove("key".to_owned())?;
+    assert!(recent(&store, 3)?.is_empty());
+    store.set("key".to_owned(), "fresh".to_owned())?;
+    assert_eq!(recent(&store, 3)?, vec!["fresh".to_owned()]);
+    drop(store);
+
+    // Without the option only the latest value is kept
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "newer".to_owned())?;
+    assert_eq!(recent(&store, 3)?, vec!["newer".to_owned()]);
+    Ok(())
+}
+
+// Shutting a server down should let a request already being served finish, then refuse new connections
+#[test]
+fn server_shutdown_drains_in_flight_requests() -> Result<()> {
+    // Gets take long enough for the shutdown to arrive while one is being served
+    #[derive(Clone)]
+    struct SlowEngine(KvStore);
+
+    impl KvsEngine for SlowEngine {
+        fn set(&mut self, key: String, value: String) -> Result<()> {
+            self.0.set(key, value)
+        }
+        fn get(&mut self, key: String) -> Result<Option<String>> {
+            thread::sleep(Duration::from_millis(300));
+            self.0.get(key)
+        }
+        fn remove(&mut self, key: String) -> Result<()> {
+            self.0.remove(key)
+        }
+        fn keys(&mut self) -> Result<Vec<String>> {
+            self.0.keys()
+        }
+        fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+            self.0.scan_prefix(prefix)
+        }
+        fn len(&mut self) -> Result<usize> {
+            Ok(self.0.len())
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(SlowEngine(store.clone()), SharedQueueThreadPool::new(2)?);
+    let shutdown = server.shutdown_handle();
+    let serving = thread::spawn(move || server.serve(listener));
+
+    let slow = thread::spawn(move || send(addr, &Request::Get { key: "key1".to_owned() }));
+    thread::sleep(Duration::from_millis(100));
+    shutdown.shutdown();
+    serving.join().unwrap()?;
+    assert_eq!(slow.join().unwrap()?, Response::Value(Some("value1".to_owned())));
+    assert!(TcpStream::connect(addr).is_err());
+
+    // A client that never sends its request holds the drain up until the timeout
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(2)?).drain_timeout(Duration::from_millis(200));
+    let shutdown = server.shutdown_handle();
+    let serving = thread::spawn(move || server.serve(listener));
+    let _silent = TcpStream::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+    shutdown.shutdown();
+    assert!(matches!(serving.join().unwrap(), Err(KvsError::ShutdownTimedOut)));
+    Ok(())
+}
+
+// `kvs-server` should exit cleanly on SIGTERM, keeping every write it acknowledged
+#[cfg(unix)]
+#[test]
+fn server_cli_exits_cleanly_on_sigterm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+    let client = kvs::KvsClient::new(addr);
+    let mut attempts = 0;
+    while client.set("key1".to_owned(), "value1".to_owned()).is_err() && attempts < 50 {
+        thread::sleep(Duration::from_millis(100));
+        attempts += 1;
+    }
+
+    assert_eq!(unsafe { libc::kill(server.id() as libc::pid_t, libc::SIGTERM) }, 0);
+    assert!(server.wait()?.success());
+    assert_eq!(KvStore::open(temp_dir.path())?.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A reader that buffered part of a failed write should not serve it back once later records
+// are written over the cut, even though reads of neighbouring records no longer seek.
+#[test]
+fn reads_after_a_cut_see_the_records_written_over_it() -> Result<()> {
+    struct Failing<'a> {
+        store: &'a KvStore,
+        supplied: usize,
+    }
+
+    impl Read for Failing<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.supplied >= 64 * 1024 {
+                // The first chunk has reached the file, so this read buffers it past "a"
+                assert_eq!(self.store.get("a".to_owned()).unwrap(), Some("value".to_owned()));
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            buf.fill(b'x');
+            self.supplied += buf.len();
+            Ok(buf.len())
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "value".to_owned())?;
+    assert!(store.set_reader("big".to_owned(), 200_000, Failing { store: &store, supplied: 0 }).is_err());
+
+    store.set("b".to_owned(), "other".to_owned())?;
+    assert_eq!(store.get("a".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("other".to_owned()));
+    assert_eq!(store.get("big".to_owned())?, None);
+    Ok(())
+}
+
+// Validation should report every corrupt record and a torn tail without touching any file.
+#[test]
+fn validate_reports_without_changing_the_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(KvStore::validate(temp_dir.path()), Err(KvsError::Locked { .. })));
+    drop(store);
+    let report = KvStore::validate(temp_dir.path())?;
+    assert!(report.is_valid() && report.warnings.is_empty());
+    assert_eq!((report.generations, report.records), (1, 1));
+
+    let log_path = temp_dir.path().join("1.log");
+    let good_len = std::fs::metadata(&log_path)?.len();
+    let mut log = std::fs::OpenOptions::new().append(true).open(&log_path)?;
+    // A record with a bad checksum, a valid record behind it and a torn tail
+    std::io::Write::write_all(&mut log, b"\0\0\0\x03\0\0\0\0\xff\xff\xff\n")?;
+    let valid = bincode::serialize(&kvs::Command::Set {
+        key: "key2".to_owned(),
+        value: "value2".to_owned(),
+        expires_at: None,
+        seq: 0,
+    })
+    .unwrap();
+    std::io::Write::write_all(&mut log, &log_frame(&valid))?;
+    std::io::Write::write_all(&mut log, b"garbage")?;
+    drop(log);
+    std::fs::File::create(temp_dir.path().join("2.log"))?;
+    let bad_len = std::fs::metadata(&log_path)?.len();
+
+    let report = KvStore::validate(temp_dir.path())?;
+    assert!(!report.is_valid());
+    assert_eq!((report.generations, report.records), (2, 2));
+    assert_eq!(report.corruptions, vec![kvs::Corruption { gen: 1, offset: good_len, length: 12 }]);
+    assert_eq!(report.warnings.len(), 2);
+    assert!(report.warnings[0].contains("torn record"));
+    assert!(report.warnings[1].contains("generation 2 is empty"));
+    assert_eq!(std::fs::metadata(&log_path)?.len(), bad_len);
+    assert!(temp_dir.path().join("2.log").exists());
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// `kvs validate` should print the report and exit with 74 only when a record is corrupt.
+#[test]
+fn cli_validate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["validate", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"generations":1,"records":1,"corruptions":[],"warnings":[]}"#).trim());
+
+    let log_path = temp_dir.path().join("1.log");
+    let good_len = std::fs::metadata(&log_path)?.len();
+    let mut log = std::fs::OpenOptions::new().append(true).open(&log_path)?;
+    std::io::Write::write_all(&mut log, b"\0\0\0\x03\0\0\0\0\xff\xff\xff\n")?;
+    std::io::Write::write_all(&mut log, b"\0\0\0\x03\0\0\0\0\xff\xff\xff\n")?;
+    drop(log);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["validate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(74)
+        .stdout(contains(format!("records: 1\ncorrupt: generation 1 offset {} (12 bytes)\nwarning: ", good_len)));
+    assert_eq!(std::fs::metadata(&log_path)?.len(), good_len + 24);
+    Ok(())
+}
+
+// Sequence numbers should keep increasing across reopens, even once compaction has dropped
+// the record holding the newest one, and records from before them should read as 0.
+#[test]
+fn sequence_numbers_increase_across_reopens() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let (_, first) = store.get_with_seq("key1".to_owned())?.unwrap();
+    let (_, second) = store.get_with_seq("key2".to_owned())?.unwrap();
+    assert!(first < second);
+    assert_eq!(store.get_with_seq("missing".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_with_seq("key2".to_owned())?, Some(("value2".to_owned(), second)));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let (value, third) = store.get_with_seq("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value3");
+    assert!(third > second);
+    // Only the remove holds the newest number, and compaction drops it
+    store.set("key3".to_owned(), "value".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    let (_, fourth) = store.get_with_seq("key4".to_owned())?.unwrap();
+    assert!(fourth > third + 2);
+
+    #[derive(serde::Serialize)]
+    enum LegacyCommand {
+        Set { key: String, value: String, expires_at: Option<u64> },
+    }
+    let legacy_dir = TempDir::new().expect("unable to create temporary working directory");
+    let command = LegacyCommand::Set { key: "old".to_owned(), value: "value".to_owned(), expires_at: None };
+    let mut log = b"KVSL\0\0\0\x04".to_vec();
+    log.extend_from_slice(&log_frame(&bincode::serialize(&command).unwrap()));
+    std::fs::write(legacy_dir.path().join("1.log"), log)?;
+    let store = KvStore::open(legacy_dir.path())?;
+    assert_eq!(store.get_with_seq("old".to_owned())?, Some(("value".to_owned(), 0)));
+    store.set("new".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_with_seq("new".to_owned())?, Some(("value".to_owned(), 1)));
+    Ok(())
+}
+
+/// Checks the `KvsEngine` contract every engine has to keep, starting from an empty engine
+fn check_engine_contract<E: KvsEngine>(engine: &mut E) -> Result<()> {
+    assert!(engine.is_empty()?);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(!engine.contains_key("key1")?);
+    assert!(matches!(engine.remove("key1".to_owned()), Err(KvsError::KeyNotFound { key }) if key == "key1"));
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("other".to_owned(), "value3".to_owned())?;
+    engine.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert!(engine.contains_key("key2")?);
+    assert_eq!(engine.len()?, 3);
+    assert_eq!(engine.keys()?, vec!["key1".to_owned(), "key2".to_owned(), "other".to_owned()]);
+    assert_eq!(
+        engine.scan_prefix("key")?,
+        vec![("key1".to_owned(), "value4".to_owned()), ("key2".to_owned(), "value2".to_owned())]
+    );
+    assert!(engine.scan_prefix("missing")?.is_empty());
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(engine.remove("key1".to_owned()), Err(KvsError::KeyNotFound { .. })));
+    assert_eq!(engine.keys()?, vec!["key2".to_owned(), "other".to_owned()]);
+    assert!(!engine.is_empty()?);
+
+    let pair = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+    engine.set_all(vec![pair("key3", "value5"), pair("key2", "value6"), pair("key3", "value7")])?;
+    assert_eq!(engine.get("key2".to_owned())?, Some("value6".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value7".to_owned()));
+    assert_eq!(engine.len()?, 3);
+    engine.flush()?;
+    Ok(())
+}
+
+// The log-backed store should keep the engine contract.
+#[test]
+fn kvs_store_keeps_the_engine_contract() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_engine_contract(&mut KvStore::open(temp_dir.path())?)
+}
+
+// The sled engine should keep the engine contract.
+#[test]
+fn sled_engine_keeps_the_engine_contract() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_engine_contract(&mut SledKvsEngine::open(temp_dir.path())?)
+}
+
+// The in-memory engine should keep the engine contract, with clones sharing its keys.
+#[test]
+fn in_memory_engine_keeps_the_engine_contract() -> Result<()> {
+    let mut engine = kvs::InMemoryKvsEngine::new();
+    check_engine_contract(&mut engine)?;
+    let mut clone = engine.clone();
+    clone.set("key3".to_owned(), "value5".to_owned())?;
+    assert_eq!(engine.get("key3".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// Size-tiered compaction should merge neighbouring logs as writes go, keeping every value,
+// older version, removal and expiry intact across merges and a reopen.
+#[test]
+fn size_tiered_compaction_merges_neighbouring_logs() -> Result<()> {
+    use kvs::CompactionStrategy;
+    let builders: [(fn() -> kvs::KvStoreBuilder, usize); 3] = [
+        (KvStore::builder, 1),
+        (|| KvStore::builder().hashed_index(), 1),
+        (|| KvStore::builder().keep_versions(3), 3),
+    ];
+    for (builder, versions) in builders {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || builder().compaction_strategy(CompactionStrategy::SizeTiered).max_log_size(256).open(temp_dir.path());
+        let store = open()?;
+        store.set_with_ttl("expiring".to_owned(), "value".to_owned(), Duration::from_millis(1))?;
+        thread::sleep(Duration::from_millis(5));
+
+        let mut model: std::collections::BTreeMap<String, Vec<String>> = std::collections::BTreeMap::new();
+        for i in 0..600 {
+            let key = format!("key{}", i % 25);
+            if i % 7 == 0 && model.contains_key(&key) {
+                store.remove(key.clone())?;
+                model.remove(&key);
+            } else {
+                let value = format!("value{}", i);
+                store.set(key.clone(), value.clone())?;
+                model.entry(key).or_default().push(value);
+            }
+        }
+        let rotations = kvs::sorted_log_generations(temp_dir.path())?.last().copied().unwrap_or(0);
+        assert!(kvs::sorted_log_generations(temp_dir.path())?.len() < rotations as usize / 2);
+
+        let check = |store: &KvStore| -> Result<()> {
+            assert_eq!(store.get("expiring".to_owned())?, None);
+            for i in 0..25 {
+                let key = format!("key{}", i);
+                let values = model.get(&key);
+                assert_eq!(store.get(key.clone())?, values.and_then(|values| values.last().cloned()));
+                let expected: Vec<String> = values.into_iter().flatten().rev().take(versions).cloned().collect();
+                assert_eq!(store.get_versions(&key, 3)?, expected);
+            }
+            assert_eq!(store.len(), model.len());
+            Ok(())
+        };
+        check(&store)?;
+        drop(store);
+        check(&open()?)?;
+    }
+    Ok(())
+}
+
+// Opening from a checkpoint should replay only the records written after it, and end up
+// with the same keys, versions, sequence numbers and dead bytes as replaying every log.
+#[test]
+fn checkpoint_lets_open_skip_replaying_old_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().keep_versions(2).max_log_size(64 * 1024).open(temp_dir.path());
+    let store = open()?;
+    for i in 0..20_000 {
+        store.set(format!("key{}", i % 5000), format!("value{}", i))?;
+    }
+    for i in 0..500 {
+        store.remove(format!("key{}", i * 7))?;
+    }
+    store.checkpoint()?;
+    store.set("key1".to_owned(), "after".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    // Set aside, the checkpoint leaves the open to replay every log
+    let checkpoint = temp_dir.path().join("CHECKPOINT");
+    let set_aside = temp_dir.path().join("CHECKPOINT.aside");
+    std::fs::rename(&checkpoint, &set_aside)?;
+    let replayed = open()?;
+    let expected = replayed.stats()?;
+    let keys = replayed.keys()?;
+    let entries: Vec<_> = keys
+        .iter()
+        .map(|key| Ok((replayed.get_with_seq(key.clone())?, replayed.get_versions(key, 2)?)))
+        .collect::<Result<_>>()?;
+    drop(replayed);
+    std::fs::rename(&set_aside, &checkpoint)?;
+
+    let store = open()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.replayed_records, 2);
+    assert!(stats.replayed_records * 1000 < expected.replayed_records);
+    assert_eq!((stats.live_keys, stats.dead_bytes), (expected.live_keys, expected.dead_bytes));
+    assert_eq!(store.keys()?, keys);
+    for (key, entry) in keys.iter().zip(&entries) {
+        assert_eq!(&(store.get_with_seq(key.clone())?, store.get_versions(key, 2)?), entry);
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let highest_seq = entries.iter().filter_map(|(entry, _)| entry.as_ref().map(|(_, seq)| *seq)).max();
+    store.set("key3".to_owned(), "next".to_owned())?;
+    assert!(store.get_with_seq("key3".to_owned())?.map(|(_, seq)| seq) > highest_seq);
+    Ok(())
+}
+
+// A checkpoint that is damaged, or that no longer matches the logs, should be ignored.
+#[test]
+fn stale_or_damaged_checkpoints_fall_back_to_full_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().max_log_size(1024).open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i % 50), format!("value{}", i))?;
+    }
+    store.checkpoint()?;
+    drop(store);
+    let checkpoint = temp_dir.path().join("CHECKPOINT");
+    let mut bytes = std::fs::read(&checkpoint)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&checkpoint, bytes)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.replayed_records, 200);
+    assert_eq!(store.get("key49".to_owned())?, Some("value199".to_owned()));
+
+    // Compaction removes the checkpoint along with the logs it covers
+    store.checkpoint()?;
+    store.compact()?;
+    assert!(!checkpoint.exists());
+    store.checkpoint()?;
+    drop(store);
+
+    // The logs it covers changing underneath it makes it stale
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.replayed_records, 0);
+    drop(store);
+    let compacted = kvs::sorted_log_generations(temp_dir.path())?[0];
+    let log = kvs::log_file_path(temp_dir.path(), compacted);
+    let len = std::fs::metadata(&log)?.len();
+    std::fs::OpenOptions::new().write(true).open(&log)?.set_len(len - 1)?;
+    let store = KvStore::open(temp_dir.path())?;
+    // Every record but the torn one is replayed
+    assert_eq!(store.stats()?.replayed_records, 49);
+    assert_eq!(store.len(), 49);
+    Ok(())
+}
+
+// `checkpoint_every` should keep a checkpoint up to date in the background, hashed index included.
+#[test]
+fn checkpoint_every_writes_checkpoints_in_the_background() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().hashed_index().checkpoint_every(Duration::from_millis(10)).open(temp_dir.path());
+    let store = open()?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    drop(store);
+    assert!(temp_dir.path().join("CHECKPOINT").is_file());
+    let store = open()?;
+    assert_eq!(store.stats()?.replayed_records, 0);
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(store.get("missing".to_owned())?, None);
+    Ok(())
+}
+
+// Opening a store that another open already holds should report `Locked` for its directory.
+#[test]
+fn open_locked_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked { path }) => assert_eq!(path, temp_dir.path()),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("open should fail while the store is locked"),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Removing a key should only consult its index entry, never read its value back
+#[test]
+fn remove_does_not_read_the_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "x".repeat(64 * 1024))?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), std::time::Duration::from_millis(1))?;
+    std::thread::sleep(std::time::Duration::from_millis(5));
+
+    let reads = store.stats()?.record_reads;
+    store.remove("key1".to_owned())?;
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::KeyNotFound { .. })));
+    assert!(matches!(store.remove("key2".to_owned()), Err(KvsError::KeyNotFound { .. })));
+    assert_eq!(store.stats()?.record_reads, reads);
+    Ok(())
+}
+
+// A value streamed in with set_reader should be announced by its length once it is committed
+#[test]
+fn subscribers_see_streamed_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = store.subscribe();
+
+    let value = vec![b'x'; 100_000];
+    store.set_reader("big".to_owned(), value.len() as u64, value.as_slice())?;
+    assert!(store.set_reader("short".to_owned(), 10, &b"abc"[..]).is_err());
+    assert_eq!(
+        events.try_iter().collect::<Vec<_>>(),
+        vec![ChangeEvent::SetStream { key: "big".to_owned(), len: 100_000 }]
+    );
+    Ok(())
+}
+
+// Closing the last clone should release the store lock, while any other clone keeps it held
+#[test]
+fn close_releases_the_lock_with_the_last_clone() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().sweep_expired(std::time::Duration::from_millis(1)).open(temp_dir.path())?;
+    let clone = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked { .. })));
+    assert_eq!(clone.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    clone.close()?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A panic while the store is open, even one inside a hook that holds the writer, should
+// still release the lock as the store is dropped during the unwind
+#[test]
+fn panic_while_open_releases_the_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().to_owned();
+    let unwound = std::panic::catch_unwind(move || {
+        let store = KvStore::open(&path).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.on_large_value(4, |_, _| panic!("hook failed"));
+        let _ = store.set("key2".to_owned(), "a large value".to_owned());
+    });
+    assert!(unwound.is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Compaction counters should advance with every compaction or merge triggered by overwrites
+#[test]
+fn metrics_count_compactions() -> Result<()> {
+    use kvs::{CompactionStrategy, Metrics};
+    for strategy in [CompactionStrategy::Full, CompactionStrategy::SizeTiered] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder()
+            .compaction_strategy(strategy)
+            .compaction_threshold(4 * 1024)
+            .max_log_size(1024)
+            .open(temp_dir.path())?;
+        assert_eq!(store.metrics(), Metrics::default());
+
+        for i in 0..2000 {
+            store.set(format!("key{}", i % 10), format!("value{}", i))?;
+        }
+        let metrics = store.metrics();
+        assert!(metrics.compactions > 0);
+        assert!(metrics.reclaimed_bytes > 0);
+        assert!(metrics.compaction_time > Duration::ZERO);
+
+        store.compact()?;
+        let after = store.clone().metrics();
+        assert_eq!(after.compactions, metrics.compactions + 1);
+        assert!(after.reclaimed_bytes > metrics.reclaimed_bytes);
+        assert!(after.compaction_time > metrics.compaction_time);
+    }
+    Ok(())
+}
+
+// A batch sent with the client should be applied as a whole, or not at all if any pair is refused
+#[test]
+fn client_set_all_applies_the_whole_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.serve(listener));
+    let client = kvs::KvsClient::new(addr);
+
+    let pairs: Vec<(String, String)> = (0..10).map(|i| (format!("key{}", i), format!("value{}", i))).collect();
+    client.set_all(pairs)?;
+    for i in 0..10 {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    let refused = vec![("key0".to_owned(), "new".to_owned()), (String::new(), "value".to_owned())];
+    assert!(matches!(client.set_all(refused), Err(KvsError::Server { .. })));
+    assert_eq!(client.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+// A request resent with the same token should only be applied the first time, live and on replay
+#[test]
+fn once_requests_are_applied_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("requests");
+    let request_log = RequestLog::open(&log_path)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(KvStore::open(temp_dir.path().join("live"))?, SharedQueueThreadPool::new(2)?)
+        .request_log(request_log.clone());
+    thread::spawn(move || server.serve(listener));
+    let client = kvs::KvsClient::new(addr);
+
+    let token = kvs::Uuid::new_v4();
+    let set = Request::Set { key: "key1".to_owned(), value: "first".to_owned() };
+    assert_eq!(client.send_once(token, set.clone())?, Response::Ok);
+    client.set("key1".to_owned(), "second".to_owned())?;
+    assert_eq!(client.send_once(token, set)?, Response::Ok);
+    assert_eq!(client.get("key1".to_owned())?, Some("second".to_owned()));
+
+    // A remove resent after it succeeded gets the first response rather than a miss
+    let remove = Request::Remove { key: "key2".to_owned() };
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    let remove_token = kvs::Uuid::new_v4();
+    assert_eq!(client.send_once(remove_token, remove.clone())?, Response::Ok);
+    assert_eq!(client.send_once(remove_token, remove)?, Response::Ok);
+    client.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    request_log.flush()?;
+    let mut target = KvStore::open(temp_dir.path().join("replayed"))?;
+    let report = kvs::replay(&mut target, std::fs::File::open(&log_path)?)?;
+    assert_eq!(report.replayed, 9);
+    assert!(report.failures.is_empty());
+    assert_eq!(target.get("key1".to_owned())?, Some("second".to_owned()));
+    assert_eq!(target.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A token that has dropped out of the server's window should be applied again when resent
+#[test]
+fn once_tokens_are_forgotten_outside_the_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?).token_window(2);
+    thread::spawn(move || server.serve(listener));
+    let client = kvs::KvsClient::new(addr);
+
+    let token = kvs::Uuid::new_v4();
+    let set = Request::Set { key: "key1".to_owned(), value: "first".to_owned() };
+    client.send_once(token, set.clone())?;
+    client.set("key1".to_owned(), "second".to_owned())?;
+    client.send_once(kvs::Uuid::new_v4(), Request::Get { key: "key1".to_owned() })?;
+    client.send_once(token, set.clone())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("second".to_owned()));
+
+    client.send_once(kvs::Uuid::new_v4(), Request::Get { key: "key1".to_owned() })?;
+    client.send_once(token, set)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("first".to_owned()));
+    Ok(())
+}
+
+// Gets of "gated" hold their thread until the test lets them go
+#[derive(Clone)]
+struct GatedEngine {
+    inner: kvs::InMemoryKvsEngine,
+    entered: Arc<Mutex<std::sync::mpsc::Sender<()>>>,
+    release: Arc<Mutex<std::sync::mpsc::Receiver<()>>>,
+}
+
+impl KvsEngine for GatedEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.inner.set(key, value)
+    }
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        if key == "gated" {
+            self.entered.lock().unwrap().send(()).unwrap();
+            self.release.lock().unwrap().recv().unwrap();
+        }
+        self.inner.get(key)
+    }
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.inner.remove(key)
+    }
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.inner.keys()
+    }
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.inner.scan_prefix(prefix)
+    }
+    fn len(&mut self) -> Result<usize> {
+        self.inner.len()
+    }
+}
+
+impl GatedEngine {
+    /// Returns the engine along with the receiver told when a gated get starts and the sender that lets it finish
+    fn new() -> (GatedEngine, std::sync::mpsc::Receiver<()>, std::sync::mpsc::Sender<()>) {
+        let (entered, entered_rx) = std::sync::mpsc::channel();
+        let (release_tx, release) = std::sync::mpsc::channel();
+        let engine = GatedEngine {
+            inner: kvs::InMemoryKvsEngine::new(),
+            entered: Arc::new(Mutex::new(entered)),
+            release: Arc::new(Mutex::new(release)),
+        };
+        (engine, entered_rx, release_tx)
+    }
+}
+
+// A connection that finds every thread busy and the queue full should be turned away as busy
+#[test]
+fn server_turns_connections_away_when_the_queue_is_full() -> Result<()> {
+    let (engine, entered_rx, release_tx) = GatedEngine::new();
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::with_queue_len(1, 0)?);
+    thread::spawn(move || server.serve(listener));
+    let client = kvs::KvsClient::new(addr);
+    // With no queue a request is only taken while the thread is idle, so wait for it to start
+    let retry = |key: &str| loop {
+        match client.get(key.to_owned()) {
+            Err(KvsError::ServerBusy) => thread::sleep(Duration::from_millis(10)),
+            got => return got,
+        }
+    };
+    assert_eq!(retry("key1")?, None);
+
+    let gated = thread::spawn(move || kvs::KvsClient::new(addr).get("gated".to_owned()));
+    entered_rx.recv().unwrap();
+    assert!(matches!(client.set("key1".to_owned(), "value1".to_owned()), Err(KvsError::ServerBusy)));
+    assert!(matches!(send(addr, &Request::Get { key: "key1".to_owned() })?, Response::Busy));
+
+    release_tx.send(()).unwrap();
+    assert_eq!(gated.join().unwrap()?, None);
+    assert_eq!(retry("key1")?, None);
+    Ok(())
+}
+
+// A client and server speaking different protocol versions should fail cleanly on either side
+#[test]
+fn protocol_version_mismatch_is_reported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.serve(listener));
+
+    // A newer client is told the server's version and its request is left unapplied
+    let mut stream = TcpStream::connect(addr)?;
+    write_message(&mut stream, &Handshake { version: PROTOCOL_VERSION + 1 })?;
+    write_message(&mut stream, &Request::Set { key: "key1".to_owned(), value: "value1".to_owned() })?;
+    assert_eq!(read_message::<_, Handshake>(&mut stream)?, Handshake { version: PROTOCOL_VERSION });
+    assert!(read_message::<_, Response>(&mut stream).is_err());
+    assert_eq!(kvs::KvsClient::new(addr).get("key1".to_owned())?, None);
+
+    // A server speaking another version makes the client fail without reading a response
+    let old_server = TcpListener::bind("127.0.0.1:0")?;
+    let old_addr = old_server.local_addr()?;
+    let answering = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = old_server.accept()?;
+        read_message::<_, Handshake>(&mut stream)?;
+        read_message::<_, Request>(&mut stream)?;
+        write_message(&mut stream, &Handshake { version: 0 })
+    });
+    match kvs::KvsClient::new(old_addr).get("key1".to_owned()) {
+        Err(KvsError::ProtocolVersionMismatch { client, server }) => assert_eq!((client, server), (PROTOCOL_VERSION, 0)),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    answering.join().unwrap()?;
+    Ok(())
+}
+
+// The builder can carry its path and pick the engine it opens
+#[test]
+fn builder_path_and_engine_selection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().path(temp_dir.path()).sync_mode(SyncMode::EveryWrite).open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+    let store = KvStore::builder().open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.close()?;
+
+    let mut engine = KvStore::builder().path(temp_dir.path()).open_engine()?;
+    assert_eq!(engine.kind(), EngineKind::Kvs);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(engine);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = KvStore::builder().engine(EngineKind::Sled).path(sled_dir.path()).open_engine()?;
+    assert_eq!(engine.kind(), EngineKind::Sled);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A compaction that fails part way must leave the index on the old logs and no partial log behind
+#[test]
+fn failed_compaction_leaves_the_index_on_the_old_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().max_log_size(256).open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.flush()?;
+    let logs = kvs::sorted_log_generations(temp_dir.path())?;
+
+    // Damage the last record compaction copies, after every other key has been copied
+    let section = store.log_section("key9")?.unwrap();
+    let log_file = kvs::log_file_path(temp_dir.path(), section.gen());
+    let mut bytes = std::fs::read(&log_file)?;
+    bytes[(section.start() + section.length()) as usize - 2] ^= 0xff;
+    std::fs::write(&log_file, bytes)?;
+    assert!(store.compact().is_err());
+    assert_eq!(kvs::sorted_log_generations(temp_dir.path())?, logs);
+
+    // Writes go on into new logs without disturbing the keys copied before the failure
+    for i in 10..30 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..9).chain(10..30) {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A frame claiming more than the message limit is refused before any of its body is read
+#[test]
+fn protocol_rejects_oversized_frames() -> Result<()> {
+    let mut frame: &[u8] = &(MAX_MESSAGE_LEN + 1).to_be_bytes();
+    match read_message::<_, Request>(&mut frame) {
+        Err(KvsError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidData),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // A length under the limit is only trusted as far as the bytes that actually follow it
+    let mut frame = MAX_MESSAGE_LEN.to_be_bytes().to_vec();
+    frame.extend(b"{}");
+    match read_message::<_, Request>(&mut frame.as_slice()) {
+        Err(KvsError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
+// A failed once request should be retried on resend, and a slow one should not hold up other tokens
+#[test]
+fn once_requests_retry_failures_and_run_side_by_side() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let (engine, entered_rx, release_tx) = GatedEngine::new();
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.serve(listener));
+    let client = kvs::KvsClient::new(addr);
+
+    let token = kvs::Uuid::new_v4();
+    let remove = Request::Remove { key: "key1".to_owned() };
+    assert!(matches!(client.send_once(token, remove.clone()), Err(KvsError::Server { .. })));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.send_once(token, remove)?, Response::Ok);
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    let gated = thread::spawn(move || kvs::KvsClient::new(addr).send_once(kvs::Uuid::new_v4(), Request::Get { key: "gated".to_owned() }));
+    entered_rx.recv().unwrap();
+    let (done, finished) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let set = Request::Set { key: "key2".to_owned(), value: "value2".to_owned() };
+        done.send(kvs::KvsClient::new(addr).send_once(kvs::Uuid::new_v4(), set)).unwrap();
+    });
+    assert_eq!(finished.recv_timeout(Duration::from_secs(5)).unwrap()?, Response::Ok);
+
+    release_tx.send(()).unwrap();
+    assert_eq!(gated.join().unwrap()?, Response::Value(None));
+    Ok(())
+}
+
+// Opening a directory with a different engine than the one that created it should fail
+#[test]
+fn open_engine_refuses_a_different_engine() -> Result<()> {
+    let wrong_engine = |found: &str, requested: &str, result: Result<kvs::AnyEngine>| match result {
+        Err(KvsError::WrongEngine { found: f, requested: r }) => assert_eq!((f.as_str(), r.as_str()), (found, requested)),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(engine) => panic!("opened the {} engine", engine.kind()),
+    };
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(kvs_dir.path())?.close()?;
+    wrong_engine("kvs", "sled", KvStore::builder().engine(EngineKind::Sled).open_engine(kvs_dir.path()));
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::builder().engine(EngineKind::Sled).open_engine(sled_dir.path())?);
+    wrong_engine("sled", "kvs", KvStore::builder().engine(EngineKind::Kvs).open_engine(sled_dir.path()));
+    assert_eq!(KvStore::builder().open_engine(sled_dir.path())?.kind(), EngineKind::Sled);
+
+    // A directory sled created on its own is recognised without the marker
+    let bare_sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledKvsEngine::open(bare_sled_dir.path())?);
+    wrong_engine("sled", "kvs", KvStore::builder().engine(EngineKind::Kvs).open_engine(bare_sled_dir.path()));
+    Ok(())
+}
+
+// Hooked values should be stored as the bytes the hooks return, while hex encoded values written before still read
+#[test]
+fn hooked_values_are_stored_as_bytes() -> Result<()> {
+    let xor = |bytes: &[u8]| bytes.iter().map(|byte| byte ^ 0x5a).collect::<Vec<u8>>();
+    let open = |path: &std::path::Path| KvStore::builder().value_hooks(xor, move |bytes| Ok(xor(bytes))).open(path);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let value = "v".repeat(1000);
+    store.set("key1".to_owned(), value.clone())?;
+    let section = store.log_section("key1")?.unwrap();
+    assert!(section.length() < 1100);
+    match store.read_record(section.gen(), section.start(), section.length())? {
+        kvs::Command::SetBytes { value: stored, .. } => assert_eq!(stored, xor(value.as_bytes())),
+        command => panic!("unexpected record: {:?}", command),
+    }
+    drop(store);
+
+    // A format version 5 log holds hooked values as hex in a plain set
+    let hex: String = xor(b"old value").iter().map(|byte| format!("{:02x}", byte)).collect();
+    let command = kvs::Command::Set { key: "key2".to_owned(), value: hex, expires_at: None, seq: 1 };
+    let mut log = b"KVSL\0\0\0\x05".to_vec();
+    log.extend_from_slice(&log_frame(&bincode::serialize(&command).unwrap()));
+    let old_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(old_dir.path().join("1.log"), log)?;
+    let store = open(old_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("old value".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("old value".to_owned()));
+    Ok(())
+}
+
+// A key's history should only decode that key's values, so another key's unreadable value does not get in the way
+#[test]
+fn key_history_only_decodes_the_key_asked_for() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let decoded = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&decoded);
+    let store = KvStore::builder()
+        .value_hooks(
+            |bytes| bytes.to_vec(),
+            move |stored| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                match stored {
+                    b"poison" => Err(KvsError::DecryptionFailed),
+                    stored => Ok(stored.to_vec()),
+                }
+            },
+        )
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "first".to_owned())?;
+    store.set("key2".to_owned(), "poison".to_owned())?;
+    store.set("key1".to_owned(), "second".to_owned())?;
+
+    let values: Vec<_> = store
+        .key_history("key1")?
+        .into_iter()
+        .map(|entry| match entry.command {
+            kvs::Command::Set { value, .. } => value,
+            command => panic!("unexpected record: {:?}", command),
+        })
+        .collect();
+    assert_eq!(values, ["first", "second"]);
+    assert_eq!(decoded.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+// An empty separator should leave nothing between records and still round-trip
+#[test]
+fn empty_record_separator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().record_separator(Vec::new()).open(temp_dir.path());
+
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2 ".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let section = store.log_section("key2")?.unwrap();
+    drop(store);
+
+    let on_disk = std::fs::read(temp_dir.path().join("1.log"))?;
+    let record = &on_disk[section.start() as usize..(section.start() + section.length()) as usize];
+    let body_len = u32::from_be_bytes(record[..4].try_into().unwrap()) as u64;
+    assert_eq!(section.length(), 8 + body_len);
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2 ".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2 ".to_owned()));
+    drop(store);
+    assert!(KvStore::builder().record_separator(Vec::new()).validate(temp_dir.path())?.corruptions.is_empty());
+    Ok(())
+}
+
+// Prefix values should skip expired keys and agree between a full and a hashed index.
+#[test]
+fn values_with_prefix_skips_expired_keys() -> Result<()> {
+    for hashed in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let builder = KvStore::builder();
+        let store = if hashed { builder.hashed_index() } else { builder }.open(temp_dir.path())?;
+        store.set("user:1".to_owned(), "alice".to_owned())?;
+        store.set_with_ttl("user:2".to_owned(), "bob".to_owned(), Duration::from_millis(1))?;
+        store.set("user:3".to_owned(), "carol".to_owned())?;
+        store.set("users".to_owned(), "everyone".to_owned())?;
+        thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(store.values_with_prefix("user:")?, vec!["alice".to_owned(), "carol".to_owned()]);
+        assert_eq!(store.values_with_prefix("user")?.len(), 3);
+    }
+    Ok(())
+}
+
+// Rolling back to a savepoint should drop the sets staged after it and keep the earlier ones.
+#[test]
+fn transaction_rolls_back_to_savepoints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "before".to_owned())?;
+
+    let mut tx = store.transaction();
+    tx.set("key1".to_owned(), "first".to_owned());
+    tx.set("key2".to_owned(), "second".to_owned());
+    let inner = tx.savepoint();
+    tx.set("key3".to_owned(), "third".to_owned());
+    tx.rollback_to(inner);
+    tx.set("key4".to_owned(), "fourth".to_owned());
+    assert_eq!(store.get("key1".to_owned())?, Some("before".to_owned()));
+    tx.commit()?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("first".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("second".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("fourth".to_owned()));
+
+    let mut tx = store.transaction();
+    tx.set("key5".to_owned(), "fifth".to_owned());
+    drop(tx);
+    assert_eq!(store.get("key5".to_owned())?, None);
+    Ok(())
+}